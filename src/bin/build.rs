@@ -0,0 +1,82 @@
+// builds as `build-tfs`: makes an image out of a host directory tree
+use std::path::Path;
+use std::{env, fs, process};
+use disk::prelude::*;
+use disk::FileSystem;
+
+const USAGE: &str = "[-N inodes] [-i bytes-per-inode] [-L label] [-r reserved-blocks] [-U uuid] [-s size] [-m manifest] [-f] <directory> <image>";
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} {}", program, USAGE);
+    eprintln!("    the image is just large enough for the tree, unless -s makes it larger");
+    eprintln!("    the manifest of inode numbers goes to stdout, unless -m names a file");
+    process::exit(1);
+}
+
+fn fail(what: &str, e: Error) -> ! {
+    eprintln!("{}: {}", what, e);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let mut options = FormatOptions::new();
+    let mut uuid = None;
+    let mut size = None;
+    let mut manifest_path = None;
+    let mut force = false;
+    let mut positional = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().cloned().unwrap_or_else(|| usage(program));
+        match arg.as_str() {
+            "-N" => options = options.inodes(value().parse().unwrap_or_else(|_| usage(program))),
+            "-i" => options = options.bytes_per_inode(value().parse().unwrap_or_else(|_| usage(program))),
+            "-L" => options = options.label(&value()),
+            "-r" => options = options.reserved_blocks(value().parse().unwrap_or_else(|_| usage(program))),
+            "-U" => uuid = Some(parse_uuid(&value()).unwrap_or_else(|e| fail("-U", e))),
+            "-s" => size = Some(parse_size(&value()).unwrap_or_else(|| usage(program))),
+            "-m" => manifest_path = Some(value()),
+            "-f" => force = true,
+            _ if arg.starts_with('-') => usage(program),
+            _ => positional.push(arg.clone())
+        }
+    }
+    if positional.len() != 2 {
+        usage(program);
+    }
+    let (source, path) = (&positional[0], &positional[1]);
+    options = options.uuid(uuid.unwrap_or_else(random_uuid));
+
+    let needed = blocks_needed(source, &options).unwrap_or_else(|e| fail(source, e));
+    let blocks = size.unwrap_or(needed);
+    if blocks < needed {
+        eprintln!("{} needs an image of at least {} blocks", source, needed);
+        process::exit(1);
+    }
+    if Path::new(path).exists() && !force {
+        eprintln!("{} already exists: use -f to overwrite it", path);
+        process::exit(1);
+    }
+
+    let mut disk = Disk::create(path, blocks).unwrap_or_else(|e| fail(&format!("Unable to open disk {}", path), e));
+    FileSystem::format_with(&mut disk, &options).unwrap_or_else(|e| fail("format failed", e));
+    let mut fs = FileSystem::new(disk);
+    fs.mount().unwrap_or_else(|e| fail("mount failed", e));
+    let manifest = fs.copy_tree(source, "/");
+    let unmounted = fs.unmount();
+    let manifest = manifest.unwrap_or_else(|e| fail("copy failed", e));
+    unmounted.unwrap_or_else(|e| fail("unmount failed", e));
+
+    match manifest_path {
+        Some(manifest_path) => {
+            if let Err(e) = fs::write(&manifest_path, manifest.to_string()) {
+                fail(&manifest_path, Error::Io(e));
+            }
+            eprintln!("{}: {} blocks, {} files and directories", path, blocks, manifest.entries.len());
+        }
+        None => print!("{}", manifest)
+    }
+}
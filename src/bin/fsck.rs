@@ -0,0 +1,47 @@
+// builds as `fsck-tfs`: cargo does not allow the `.` of `fsck.tfs` in binary names
+use std::{env, process};
+use disk::prelude::*;
+use disk::FileSystem;
+
+// exit codes follow fsck(8)
+const EXIT_CLEAN: i32 = 0;
+const EXIT_REPAIRED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_ERROR: i32 = 8;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let (repair, path) = match args.len() {
+        2 => (false, &args[1]),
+        3 if args[1] == "-r" || args[1] == "--repair" => (true, &args[2]),
+        _ => {
+            eprintln!("Usage: {} [-r|--repair] <diskfile>", args[0]);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let mut disk = match Disk::from_image(path) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Unable to open disk {}: {}", path, e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+
+    let report = match FileSystem::check(&mut disk, repair) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("check failed: {}", e);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    println!("{}", report);
+
+    if report.is_clean() {
+        process::exit(EXIT_CLEAN);
+    } else if report.repaired {
+        process::exit(EXIT_REPAIRED);
+    }
+    process::exit(EXIT_UNCORRECTED);
+}
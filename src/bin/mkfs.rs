@@ -0,0 +1,89 @@
+// builds as `mkfs-tfs`: cargo does not allow the `.` of `mkfs.tfs` in binary names
+use std::path::Path;
+use std::{env, process};
+use disk::prelude::*;
+use disk::FileSystem;
+
+const USAGE: &str = "[-N inodes] [-i bytes-per-inode] [-L label] [-r reserved-blocks] [-U uuid] [-f] <image> <size>";
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} {}", program, USAGE);
+    eprintln!("    <size> is in blocks, or in bytes with a K, M or G suffix");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+
+    let mut options = FormatOptions::new();
+    let mut uuid = None;
+    let mut force = false;
+    let mut positional = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().cloned().unwrap_or_else(|| usage(program));
+        match arg.as_str() {
+            "-N" => options = options.inodes(value().parse().unwrap_or_else(|_| usage(program))),
+            "-i" => options = options.bytes_per_inode(value().parse().unwrap_or_else(|_| usage(program))),
+            "-L" => options = options.label(&value()),
+            "-r" => options = options.reserved_blocks(value().parse().unwrap_or_else(|_| usage(program))),
+            "-U" => match parse_uuid(&value()) {
+                Ok(parsed) => uuid = Some(parsed),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            },
+            "-f" => force = true,
+            _ if arg.starts_with('-') => usage(program),
+            _ => positional.push(arg.clone())
+        }
+    }
+    if positional.len() != 2 {
+        usage(program);
+    }
+    let path = &positional[0];
+    let blocks = parse_size(&positional[1]).unwrap_or_else(|| usage(program));
+    let uuid = uuid.unwrap_or_else(random_uuid);
+    options = options.uuid(uuid);
+
+    if Path::new(path).exists() && !force {
+        eprintln!("{} already exists: use -f to overwrite it", path);
+        process::exit(1);
+    }
+
+    let mut disk = match Disk::create(path, blocks) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Unable to open disk {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = FileSystem::format_with(&mut disk, &options) {
+        eprintln!("format failed: {}", e);
+        process::exit(1);
+    }
+
+    let mut data = [0; Disk::BLOCK_SIZE];
+    if let Err(e) = disk.read(0, &mut data) {
+        eprintln!("Unable to read back the superblock: {}", e);
+        process::exit(1);
+    }
+    let mut block = Block::new();
+    block.set_data(data);
+    let superblock = block.superblock();
+    println!("{}: {} blocks of {} bytes", path, superblock.Blocks, Disk::BLOCK_SIZE);
+    println!("    {} inodes in {} blocks", superblock.inode_count(), superblock.InodeBlocks);
+    println!("    {} data blocks", superblock.data_end() - superblock.data_start());
+    if superblock.JournalBlocks > 0 {
+        println!("    {} journal blocks", superblock.JournalBlocks);
+    }
+    if superblock.ReservedBlocks > 0 {
+        println!("    {} reserved blocks", superblock.ReservedBlocks);
+    }
+    if superblock.Label[0] != 0 {
+        println!("    label: {}", superblock.label());
+    }
+    println!("    uuid: {}", format_uuid(&superblock.Uuid));
+}
@@ -0,0 +1,60 @@
+// builds as `resize-tfs`, next to `mkfs-tfs` and `fsck-tfs`
+use std::{env, process};
+use disk::prelude::*;
+use disk::FileSystem;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} <diskfile> <size>", args[0]);
+        eprintln!("    <size> is in blocks, or in bytes with a K, M or G suffix");
+        process::exit(1);
+    }
+    let path = &args[1];
+    let blocks = match parse_size(&args[2]) {
+        Some(blocks) => blocks,
+        None => {
+            eprintln!("Invalid size {}", args[2]);
+            process::exit(1);
+        }
+    };
+
+    // a crash part way through a resize can leave the file larger than the
+    // superblock says; resizing again is how that gets finished
+    let opened = match Disk::from_image(path) {
+        Err(Error::ImageSize { blocks: Some(blocks), bytes }) if bytes > (blocks * Disk::BLOCK_SIZE) as u64 => {
+            Disk::from_file(path, bytes as usize / Disk::BLOCK_SIZE)
+        }
+        opened => opened
+    };
+    let disk = match opened {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Unable to open disk {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let mut fs = FileSystem::new(disk);
+    if let Err(e) = fs.mount() {
+        eprintln!("mount failed: {}", e);
+        process::exit(1);
+    }
+    let old_blocks = match &fs.metaData {
+        Some(meta_data) => meta_data.superBlock.Blocks,
+        None => 0
+    };
+    if let Err(e) = fs.resize(blocks) {
+        eprintln!("resize failed: {}", e);
+        if let Err(e) = fs.unmount() {
+            eprintln!("unmount failed: {}", e);
+        }
+        process::exit(1);
+    }
+    if let Err(e) = fs.unmount() {
+        eprintln!("unmount failed: {}", e);
+        process::exit(1);
+    }
+    println!("{}: resized from {} to {} blocks", path, old_blocks, blocks);
+}
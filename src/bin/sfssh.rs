@@ -0,0 +1,288 @@
+// a drop-in replacement for SimpleFS's sfssh: same commands, same output and
+// the same disk I/O, so its shell_tests/ transcripts can be run against tfs
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{stderr, stdin, stdout};
+use std::{env, process};
+
+use disk::prelude::*;
+use disk::SimpleFs;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() != 3 {
+        eprintln!("Usage: {} <diskfile> <nblocks>", args[0]);
+        process::exit(1);
+    }
+
+    let nblocks: usize = match args[2].parse() {
+        Ok(n) => n,
+        _ => {
+            eprintln!("Invalid number of blocks {}", args[2]);
+            process::exit(1);
+        }
+    };
+    // SimpleFS's disk_open sizes the image to <nblocks> whatever it held,
+    // and the transcripts count on it; Disk itself refuses to
+    if let Err(e) = resize_image(&args[1], nblocks) {
+        eprintln!("Unable to open disk {}: {}", args[1], e);
+        process::exit(1);
+    }
+    let disk = match Disk::from_file(&args[1], nblocks) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Unable to open disk {}: {}", args[1], e);
+            process::exit(1);
+        }
+    };
+    let mut fs = SimpleFs::new(disk);
+
+    // shell loop; the prompt goes to stderr so transcripts only hold output
+    loop {
+        eprint!("sfs> ");
+        let _ = stderr().flush();
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        let command: Vec<&str> = line.split_whitespace().collect();
+        if command.is_empty() {
+            continue;
+        }
+
+        match command[0] {
+            "debug" => do_debug(&mut fs, &command),
+            "format" => do_format(&mut fs, &command),
+            "mount" => do_mount(&mut fs, &command),
+            "cat" => do_cat(&mut fs, &command),
+            "copyout" => do_copyout(&mut fs, &command),
+            "create" => do_create(&mut fs, &command),
+            "remove" => do_remove(&mut fs, &command),
+            "stat" => do_stat(&mut fs, &command),
+            "copyin" => do_copyin(&mut fs, &command),
+            "help" => do_help(),
+            "exit" | "quit" => break,
+            _ => {
+                print!("Unknown command: {}", line);
+                println!("Type 'help' for a list of commands.");
+            }
+        }
+    }
+
+    fs.unmount();
+    println!("{} disk block reads", fs.disk.reads());
+    println!("{} disk block writes", fs.disk.writes());
+}
+
+fn parse_inode(arg: &str) -> Option<usize> {
+    arg.parse().ok()
+}
+
+fn resize_image(path: &str, nblocks: usize) -> std::io::Result<()> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+    file.set_len((nblocks * Disk::BLOCK_SIZE) as u64)
+}
+
+fn do_debug(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    if args.len() != 1 {
+        println!("Usage: debug");
+        return;
+    }
+
+    if let Err(e) = SimpleFs::debug(&mut fs.disk) {
+        eprintln!("debug failed: {}", e);
+    }
+}
+
+fn do_format(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    if args.len() != 1 {
+        println!("Usage: format");
+        return;
+    }
+
+    match fs.format() {
+        Ok(()) => println!("disk formatted."),
+        Err(_) => println!("format failed!")
+    }
+}
+
+fn do_mount(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    if args.len() != 1 {
+        println!("Usage: mount");
+        return;
+    }
+
+    match fs.mount() {
+        Ok(()) => println!("disk mounted."),
+        Err(_) => println!("mount failed!")
+    }
+}
+
+fn do_cat(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    let inumber = match args {
+        [_, inumber] => parse_inode(inumber),
+        _ => None
+    };
+    match inumber {
+        Some(inumber) => copyout(fs, inumber, &mut stdout()),
+        None => println!("Usage: cat <inode>")
+    }
+}
+
+fn do_copyout(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    let inumber = match args {
+        [_, inumber, _] => parse_inode(inumber),
+        _ => None
+    };
+    let inumber = match inumber {
+        Some(inumber) => inumber,
+        None => {
+            println!("Usage: copyout <inode> <file>");
+            return;
+        }
+    };
+
+    match File::create(args[2]) {
+        Ok(mut file) => copyout(fs, inumber, &mut file),
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", args[2], e);
+            println!("copyout failed!");
+        }
+    }
+}
+
+fn do_create(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    if args.len() != 1 {
+        println!("Usage: create");
+        return;
+    }
+
+    match fs.create() {
+        Ok(inumber) => println!("created inode {}.", inumber),
+        Err(_) => println!("create failed!")
+    }
+}
+
+fn do_remove(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    let inumber = match args {
+        [_, inumber] => parse_inode(inumber),
+        _ => None
+    };
+    let inumber = match inumber {
+        Some(inumber) => inumber,
+        None => {
+            println!("Usage: remove <inode>");
+            return;
+        }
+    };
+
+    match fs.remove(inumber) {
+        Ok(()) => println!("removed inode {}.", inumber),
+        Err(_) => println!("remove failed!")
+    }
+}
+
+fn do_stat(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    let inumber = match args {
+        [_, inumber] => parse_inode(inumber),
+        _ => None
+    };
+    let inumber = match inumber {
+        Some(inumber) => inumber,
+        None => {
+            println!("Usage: stat <inode>");
+            return;
+        }
+    };
+
+    match fs.stat(inumber) {
+        Ok(bytes) => println!("inode {} has size {} bytes.", inumber, bytes),
+        Err(_) => println!("stat failed!")
+    }
+}
+
+fn do_copyin(fs: &mut SimpleFs<Disk>, args: &[&str]) {
+    let inumber = match args {
+        [_, _, inumber] => parse_inode(inumber),
+        _ => None
+    };
+    let inumber = match inumber {
+        Some(inumber) => inumber,
+        None => {
+            println!("Usage: copyin <file> <inode>");
+            return;
+        }
+    };
+
+    match File::open(args[1]) {
+        Ok(mut file) => copyin(fs, &mut file, inumber),
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", args[1], e);
+            println!("copyin failed!");
+        }
+    }
+}
+
+fn do_help() {
+    println!("Commands are:");
+    println!("    format");
+    println!("    mount");
+    println!("    debug");
+    println!("    create");
+    println!("    remove  <inode>");
+    println!("    cat     <inode>");
+    println!("    stat    <inode>");
+    println!("    copyin  <file> <inode>");
+    println!("    copyout <inode> <file>");
+    println!("    help");
+    println!("    quit");
+    println!("    exit");
+}
+
+// sfssh moves data 4 * BUFSIZ bytes at a time; the buffer size shows in the
+// I/O counters, so it is kept
+const BUFFER_SIZE: usize = 4 * 8192;
+
+fn copyin(fs: &mut SimpleFs<Disk>, file: &mut File, inumber: usize) {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    loop {
+        let result = match file.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(result) => result
+        };
+
+        let actual = match fs.write(inumber, &buffer, result, offset) {
+            Ok(actual) => actual,
+            Err(e) => {
+                eprintln!("fs.write failed: {}", e);
+                break;
+            }
+        };
+        offset += actual;
+        if actual != result {
+            eprintln!("fs.write only wrote {} bytes, not {} bytes", actual, result);
+            break;
+        }
+    }
+    println!("{} bytes copied", offset);
+}
+
+fn copyout<W: Write>(fs: &mut SimpleFs<Disk>, inumber: usize, out: &mut W) {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    while let Ok(result) = fs.read(inumber, &mut buffer, BUFFER_SIZE, offset) {
+        if result == 0 {
+            break;
+        }
+        if out.write_all(&buffer[..result]).is_err() {
+            break;
+        }
+        offset += result;
+    }
+    let _ = out.flush();
+    println!("{} bytes copied", offset);
+}
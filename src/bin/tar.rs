@@ -0,0 +1,59 @@
+// builds as `tar-tfs`: moves an image's contents to and from tar archives
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::{env, process};
+use disk::prelude::*;
+use disk::FileSystem;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} -c <diskfile> [archive]", program);
+    eprintln!("       {} -x <diskfile> [archive]", program);
+    eprintln!("    -c writes the image to a ustar archive, -x reads one into it");
+    eprintln!("    without an archive, -c writes to stdout and -x reads from stdin");
+    process::exit(1);
+}
+
+fn fail(what: &str, e: Error) -> ! {
+    eprintln!("{}: {}", what, e);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = &args[0];
+    if args.len() < 3 || args.len() > 4 {
+        usage(program);
+    }
+    let (mode, path, archive) = (&args[1], &args[2], args.get(3));
+    if mode != "-c" && mode != "-x" {
+        usage(program);
+    }
+
+    let disk = Disk::from_image(path).unwrap_or_else(|e| fail(&format!("Unable to open disk {}", path), e));
+    let mut fs = FileSystem::new(disk);
+    fs.mount().unwrap_or_else(|e| fail("mount failed", e));
+
+    let result = if mode == "-c" {
+        match archive {
+            Some(archive) => match File::create(archive) {
+                Ok(file) => fs.export_tar(BufWriter::new(file)),
+                Err(e) => Err(Error::Io(e))
+            },
+            None => fs.export_tar(BufWriter::new(io::stdout().lock()))
+        }
+    } else {
+        match archive {
+            Some(archive) => match File::open(archive) {
+                Ok(file) => fs.import_tar(BufReader::new(file)),
+                Err(e) => Err(Error::Io(e))
+            },
+            None => fs.import_tar(io::stdin().lock())
+        }
+    };
+    let unmounted = fs.unmount();
+
+    let what = archive.map(|archive| archive.as_str()).unwrap_or(if mode == "-c" { "stdout" } else { "stdin" });
+    let manifest = result.unwrap_or_else(|e| fail(what, e));
+    unmounted.unwrap_or_else(|e| fail("unmount failed", e));
+    eprintln!("{}: {} files and directories", path, manifest.entries.len());
+}
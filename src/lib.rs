@@ -1,6 +1,12 @@
+// the on-disk structures mirror SimpleFS, field names included
+#![allow(non_snake_case)]
+
 mod tfs;
 
 pub mod prelude {
     pub use super::tfs::prelude::*;
 }
-pub use tfs::FileSystem;
+pub use tfs::{
+    BlockCache, BlockDevice, Error, FileSystem, FormatOptions, Manifest, MemDisk, OpenFile, OpenMode, Problem, Report, Result,
+    SharedDevice, SharedFileSystem, SimpleFs, TfsFile
+};
@@ -1,34 +1,43 @@
 use std::{env, process};
-use std::io::{stdin, stdout, Write};
-use std::io::SeekFrom;
+use std::io::{stderr, stdin, Write};
 use std::io::prelude::*;
-use std::error::Error;
 use disk::prelude::*;
 use disk::FileSystem;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        eprintln!("Usage: {}, <diskfile> <nblocks>", args[0]);
+    if args.len() != 2 && args.len() != 3 {
+        eprintln!("Usage: {} <diskfile> [nblocks]", args[0]);
+        eprintln!("    nblocks is only needed to create a new image");
         process::exit(1);
     }
 
-    let mut fs = FileSystem::new();
-    let nblocks: usize = match args[2].as_str().parse() {
-        Ok(n) => n,
-        _ => {
-            println!("Invalid number of blocks {}", args[2]);
+    // an existing image knows its own size; a block count given for it
+    // has to agree rather than resize it
+    let disk = match args.get(2) {
+        Some(nblocks) => match nblocks.as_str().parse() {
+            Ok(n) => Disk::from_file(&args[1], n),
+            _ => {
+                println!("Invalid number of blocks {}", nblocks);
+                process::exit(1);
+            }
+        },
+        None => Disk::from_image(&args[1])
+    };
+    let disk = match disk {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("Unable to open disk {}: {}", args[1], e);
             process::exit(1);
         }
     };
-    let mut disk = Disk::from_file(&args[1], nblocks);
+    let mut fs = FileSystem::new(BlockCache::new(disk));
 
     // shell loop
-    loop {
-        let line = read_command();
+    while let Some(line) = read_command() {
         let command = parse_command(line.as_str());
-        if command.len() == 0 {
+        if command.is_empty() {
             continue;
         }
         let cmd = command[0];
@@ -36,63 +45,80 @@ fn main() {
         if cmd == "help" {
             do_help();
         } else if cmd == "format" {
-            disk = do_format(disk, command);
+            do_format(&mut fs, command);
         } else if cmd == "debug" {
-            disk = do_debug(disk, command);
+            do_debug(&mut fs, command);
         } else if cmd == "mount" {
-            let r = do_mount(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_mount(&mut fs, command);
         } else if cmd == "create" {
-            let r = do_create(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_create(&mut fs, command);
         } else if cmd == "remove" {
-            let r = do_remove(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_remove(&mut fs, command);
         } else if cmd == "stat" {
-            let r = do_stat(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_stat(&mut fs, command);
+        } else if cmd == "truncate" {
+            do_truncate(&mut fs, command);
+        } else if cmd == "resize" {
+            do_resize(&mut fs, command);
         } else if cmd == "copyin" {
-            let r = do_copyin(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_copyin(&mut fs, command);
         } else if cmd == "copyout" {
-            let r = do_copyout(disk, fs, command);
-            disk = r.0;
-            fs = r.1;
+            do_copyout(&mut fs, command);
         } else if cmd == "cat" {
-            let f = do_cat(fs, command);
-            fs = f;
+            do_cat(&mut fs, command);
+        } else if cmd == "open" {
+            do_open(&mut fs, command);
+        } else if cmd == "mkdir" {
+            do_mkdir(&mut fs, command);
+        } else if cmd == "ls" {
+            do_ls(&mut fs, command);
+        } else if cmd == "unlink" {
+            do_unlink(&mut fs, command);
+        } else if cmd == "rmdir" {
+            do_rmdir(&mut fs, command);
+        } else if cmd == "rename" {
+            do_rename(&mut fs, command);
         }
         else if cmd == "exit" || cmd == "quit" {
             break;
         } else {
             println!("Unknown command: {}", line);
-            println!("Type 'help' for a list of commands");
+            println!("Type 'help' for a list of commands.");
         }
     }
+
+    // leave the image marked clean
+    let unmounted = fs.unmount();
+    println!("{} disk block reads", fs.disk.get_ref().reads());
+    println!("{} disk block writes", fs.disk.get_ref().writes());
+    println!("{} cache hits", fs.disk.hits());
+    println!("{} cache misses", fs.disk.misses());
+    if let Err(e) = unmounted {
+        eprintln!("unmount failed: {}", e);
+        process::exit(1);
+    }
 }
 
-fn read_command() -> String {
-    println!();
-    print!("tfs> ");
-    let _ = stdout().flush();
+// the prompt goes to stderr, as in sfssh, so that piping commands in gives
+// a transcript that can be diffed against the reference shell
+fn read_command() -> Option<String> {
+    eprint!("tfs> ");
+    let _ = stderr().flush();
     let mut line = String::new();
-    let bytes_read = stdin().read_line(&mut line).unwrap();
+    match stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => return None,
+        Ok(_) => {}
+    }
     if let Some('\n') = line.chars().next_back() {
         line.pop();
     }
     if let Some('\r') = line.chars().next_back() {
         line.pop();
     }
-    println!();
-    line
+    Some(line)
 }
 
-fn parse_command <'a>(command: &'a str) -> Vec<&str> {
+fn parse_command(command: &str) -> Vec<&str> {
     let mut v = Vec::new();
     let v2: Vec<&str> = command.split_whitespace().collect();
     for c in v2.iter() {
@@ -113,225 +139,306 @@ fn do_help() {
     println!("      remove  <inode>");
     println!("      cat     <inode>");
     println!("      stat    <inode>");
-    println!("      copyin  <inode> <file>");
+    println!("      truncate <inode> <size>");
+    println!("      resize  <size>");
+    println!("      copyin  <file> <inode>");
     println!("      copyout <inode> <file>");
+    println!("      open    <path>");
+    println!("      mkdir   <path>");
+    println!("      ls      [path]");
+    println!("      unlink  <path>");
+    println!("      rmdir   <path>");
+    println!("      rename  <from> <to>");
     println!("      help");
-    println!("      quite");
+    println!("      quit");
     println!("      exit");
 }
 
-fn do_format<'a>(mut disk: Disk<'a>, args: Vec<&str>) -> Disk<'a> {
+fn do_format(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 1 {
         println!("Usage: format");
+    } else if fs.is_mounted() {
+        failed("format", Error::AlreadyMounted);
     } else {
-        if FileSystem::format(&mut disk) {
-            println!("disk formated.");
-        } else {
-            println!("format failed!");
+        match FileSystem::format(&mut fs.disk) {
+            Ok(()) => println!("disk formatted."),
+            Err(e) => failed("format", e)
         }
     }
-    disk
 }
 
-fn do_mount<'a>(mut disk: Disk<'a>, mut fs: FileSystem<'a>,  args: Vec<&str>) -> (Disk<'a>, FileSystem<'a>) {
+fn do_mount(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 1 {
         println!("Usage: mount");
     } else {
-        if fs.mount(&mut disk) {
-            println!("disk mounted.");
-        } else {
-            println!("mount failed!");
+        match fs.mount() {
+            Ok(()) => println!("disk mounted."),
+            Err(e) => failed("mount", e)
         }
     }
+}
 
-    (disk, fs)
+fn do_create(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 1 {
+        println!("Usage: create");
+    } else {
+        match fs.create() {
+            Ok(inumber) => println!("created inode {}.", inumber),
+            Err(e) => failed("create", e)
+        }
+    }
 }
 
-fn do_create<'a>(mut disk: Disk<'a>, 
-    mut fs: FileSystem<'a>,  args: Vec<&str>) 
-    -> (Disk<'a>, FileSystem<'a>) {
-        if args.len() != 1 {
-            println!("Usage: create");
-        } else {
-            let inumber = fs.create();
-            if inumber >= 0 {
-                println!("created inode {}", inumber);
-            } else {
-                println!("create failed!");
-            }
+fn do_remove(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: remove <inode>");
+    } else {
+        let inumber: usize = args[1].parse().unwrap();
+        match fs.remove(inumber) {
+            Ok(()) => println!("removed inode {}.", inumber),
+            Err(e) => failed("remove", e)
         }
-    
-        (disk, fs) 
+    }
 }
 
-fn do_remove<'a>(mut disk: Disk<'a>, 
-    mut fs: FileSystem<'a>,  args: Vec<&str>) 
-    -> (Disk<'a>, FileSystem<'a>) {
-        if args.len() != 2 {
-            println!("Usage: remove <inode>");
-        } else {
-            let inumber: usize = args[1].parse().unwrap();
-            if fs.remove(inumber) {
-                println!("removed inode {}", inumber);
-            } else {
-                println!("remove failed!");
-            }
+fn do_stat(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: stat <inode>");
+    } else {
+        let inumber: usize = args[1].parse().unwrap();
+        match fs.stat(inumber) {
+            Ok(bytes) => println!("inode {} has size {} bytes.", inumber, bytes),
+            Err(e) => failed("stat", e)
         }
-    
-        (disk, fs)
-    
+    }
 }
 
-fn do_stat<'a>(mut disk: Disk<'a>, 
-    mut fs: FileSystem<'a>,  args: Vec<&str>) 
-    -> (Disk<'a>, FileSystem<'a>) {
-        if args.len() != 2 {
-            println!("Usage: stat <inode>");
-        } else {
-            let inumber: usize = args[1].parse().unwrap();
-            let bytes = fs.stat(inumber);
-            if bytes >= 0 {
-                println!(" inode {} has size {} bytes", inumber, bytes);
-            } else {
-                println!("stat failed!");
-            }
+fn do_truncate(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 3 {
+        println!("Usage: truncate <inode> <size>");
+    } else {
+        match (args[1].parse(), args[2].parse()) {
+            (Ok(inumber), Ok(size)) => match fs.truncate(inumber, size) {
+                Ok(()) => println!("truncated inode {} to {} bytes", inumber, size),
+                Err(e) => failed("truncate", e)
+            },
+            _ => println!("Usage: truncate <inode> <size>")
         }
-    
-        (disk, fs)
-    
+    }
+}
+
+fn do_resize(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: resize <size>");
+    } else {
+        match parse_size(args[1]) {
+            Some(blocks) => match fs.resize(blocks) {
+                Ok(()) => println!("disk resized to {} blocks.", blocks),
+                Err(e) => failed("resize", e)
+            },
+            None => println!("Invalid size {}", args[1])
+        }
+    }
 }
 
-fn do_copyin<'a>(mut disk: Disk<'a>, 
-    mut fs: FileSystem<'a>,  args: Vec<&str>) 
-    -> (Disk<'a>, FileSystem<'a>) {
+fn do_copyin(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 3 {
-        println!("Usage: copyin <inode> <file>");
-        return (disk, fs)
+        println!("Usage: copyin <file> <inode>");
     } else {
-        let inumber: usize = args[1].parse().unwrap();
-        let (f, copied) = copyin(fs, args[2], inumber);
-        // let fs, copied = r;
-        if !copied {
+        let inumber: usize = args[2].parse().unwrap();
+        if !copyin(fs, args[1], inumber) {
             println!("copyin failed!");
         }
-        (disk, f)
     }
-
 }
 
-fn do_copyout<'a>(mut disk: Disk<'a>, 
-    mut fs: FileSystem<'a>,  args: Vec<&str>) 
-    -> (Disk<'a>, FileSystem<'a>) {
+fn do_copyout(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 3 {
         println!("Usage: copyout <inode> <file>");
-        return (disk, fs)
     } else {
         let inumber: usize = args[1].parse().unwrap();
-        let (f, copied) = copyout(fs, args[2], inumber);
-        if !copied {
+        if !copyout(fs, args[2], inumber) {
             println!("copyout failed!");
         }
-        (disk, f)
     }
-
 }
 
-
-
-fn do_debug<'a>(mut disk: Disk<'a>, args: Vec<&str>) -> Disk<'a> {
+fn do_debug(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 1 {
         println!("Usage: debug");
-    } else {
-        FileSystem::debug(&mut disk);
+    } else if let Err(e) = FileSystem::debug(&mut fs.disk) {
+        failed("debug", e);
     }
-    return disk;
 }
 
-fn copyin<'a>(mut fs: FileSystem<'a>, path: &str, inumber: usize) -> (FileSystem<'a>, bool) {
-    use std::fs::{File, OpenOptions};
+// the transcript only says what failed, as sfssh's does; the reason goes to
+// stderr
+fn failed(command: &str, e: Error) {
+    println!("{} failed!", command);
+    eprintln!("{}: {}", command, e);
+}
+
+// copyin/copyout move 64 blocks at a time, so bulk copies reach the
+// filesystem as large sequential requests it can read ahead and coalesce
+const BUFFER_SIZE: usize = 64 * 4096;
+
+fn copyin(fs: &mut FileSystem<BlockCache<Disk>>, path: &str, inumber: usize) -> bool {
+    use std::fs::OpenOptions;
     let file = OpenOptions::new().read(true).open(path);
     let mut file = match file {
          Ok(f) => f,
-        _ => {
-            println!("Unable to open {}", path);
-            return (fs, false);
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", path, e);
+            return false;
         }
     };
 
-    let mut buffer = [0; Disk::BLOCK_SIZE];
+    let mut buffer = vec![0; BUFFER_SIZE];
     let mut offset = 0;
-    let mut n = 0;
-    loop {
-        let result = match file.read(&mut buffer) {
-            Ok(r) => r,
-            _ => {
-                break;
-            }
-        };
-        if result <= 0 {
+    while let Ok(result) = file.read(&mut buffer) {
+        if result == 0 {
             break;
         }
 
-        let actual = fs.write(inumber, &mut buffer, result, offset);
-        if actual < 0 {
-            println!("fs.write returned invalid result {}", actual);
-            break;
-        }
-        // offset += result;
-        n += 1;
-        file.seek(SeekFrom::Start( n as u64 * Disk::BLOCK_SIZE as u64));
-        if actual as usize != result {
-            println!("fs.write only wrote {} bytes, not {} bytes", actual, result);
+        let actual = match fs.write(inumber, &buffer, result, offset) {
+            Ok(actual) => actual,
+            Err(e) => {
+                eprintln!("fs.write failed: {}", e);
+                break;
+            }
+        };
+        offset += actual;
+        if actual != result {
+            eprintln!("fs.write only wrote {} bytes, not {} bytes", actual, result);
             break;
         }
     }
     println!("{} bytes copied", offset);
-    (fs, true)
+    true
 }
 
 
-fn copyout<'a>(mut fs: FileSystem<'a>, path: &str, inumber: usize) -> (FileSystem<'a>, bool) {
-    use std::fs::{File, OpenOptions};
-    let file = OpenOptions::new().write(true).open(path);
+fn copyout(fs: &mut FileSystem<BlockCache<Disk>>, path: &str, inumber: usize) -> bool {
+    use std::fs::File;
+    let file = File::create(path);
     let mut file = match file {
          Ok(f) => f,
-        _ => {
-            println!("Unable to open {}", path);
-            return (fs, false);
+        Err(e) => {
+            eprintln!("Unable to open {}: {}", path, e);
+            return false;
         }
     };
 
-    let mut buffer = [0; Disk::BLOCK_SIZE];
+    let mut buffer = vec![0; BUFFER_SIZE];
     let mut offset = 0;
 
     loop {
-        let result = fs.read(inumber, &mut buffer, Disk::BLOCK_SIZE, offset);
-        if result <= 0 {
+        let result = match fs.read(inumber, &mut buffer, BUFFER_SIZE, offset) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("fs.read failed: {}", e);
+                break;
+            }
+        };
+        if result == 0 {
             break;
         }
 
-        if result < Disk::BLOCK_SIZE as i64 {
-            let mut buff = &buffer[0..result as usize];
-            file.write(&mut buff);            
-        } else {
-            file.write(&mut buffer);
+        if file.write_all(&buffer[0..result]).is_err() {
+            eprintln!("Unable to write {}", path);
+            return false;
         }
-        offset += result as usize;
+        offset += result;
     }
     println!("{} bytes copied", offset);
-    (fs, true)
+    true
 }
 
-fn do_cat<'a>(mut fs: FileSystem<'a>, args: Vec<&str>) -> FileSystem<'a> {
+fn do_cat(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
     if args.len() != 2 {
         println!("Usage: cat <inode>");
-        return fs;
     } else {
         let inumber = args[1].parse().unwrap();
-        let (f, copied) = copyout(fs, "/dev/stdout", inumber);
-        if !copied {
+        if !copyout(fs, "/dev/stdout", inumber) {
             println!("cat failed!");
         }
-        return f;
     }
-}
\ No newline at end of file
+}
+
+fn do_open(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: open <path>");
+    } else {
+        match fs.open(args[1]) {
+            Ok(inumber) => println!("{} is inode {}", args[1], inumber),
+            Err(e) => failed("open", e)
+        }
+    }
+}
+
+fn do_mkdir(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: mkdir <path>");
+    } else {
+        match fs.mkdir(args[1]) {
+            Ok(inumber) => println!("created directory {} in inode {}", args[1], inumber),
+            Err(e) => failed("mkdir", e)
+        }
+    }
+}
+
+fn do_ls(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() > 2 {
+        println!("Usage: ls [path]");
+        return;
+    }
+
+    let path = args.get(1).copied().unwrap_or("/");
+    let entries = match fs.readdir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            failed("ls", e);
+            return;
+        }
+    };
+    for (name, inumber) in entries {
+        match (fs.is_dir(inumber), fs.stat(inumber)) {
+            (Ok(true), _) => println!("{:>6} {:>10} {}/", inumber, "-", name),
+            (Ok(false), Ok(size)) => println!("{:>6} {:>10} {}", inumber, size, name),
+            (Err(e), _) | (_, Err(e)) => println!("{:>6} {:>10} {} ({})", inumber, "?", name, e)
+        }
+    }
+}
+
+fn do_unlink(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: unlink <path>");
+    } else {
+        match fs.unlink(args[1]) {
+            Ok(()) => println!("removed {}", args[1]),
+            Err(e) => failed("unlink", e)
+        }
+    }
+}
+
+fn do_rmdir(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 2 {
+        println!("Usage: rmdir <path>");
+    } else {
+        match fs.rmdir(args[1]) {
+            Ok(()) => println!("removed directory {}", args[1]),
+            Err(e) => failed("rmdir", e)
+        }
+    }
+}
+
+fn do_rename(fs: &mut FileSystem<BlockCache<Disk>>, args: Vec<&str>) {
+    if args.len() != 3 {
+        println!("Usage: rename <from> <to>");
+    } else {
+        match fs.rename(args[1], args[2]) {
+            Ok(()) => println!("renamed {} to {}", args[1], args[2]),
+            Err(e) => failed("rename", e)
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::options::FormatOptions;
+use super::types::*;
+use super::FileSystem;
+
+// files are copied 64 blocks at a time, as the shell's copyin does (and
+// tar archives are read and written the same way)
+pub(super) const COPY_BLOCKS: usize = 64;
+
+/// The path and inode number of everything `FileSystem::copy_tree` created
+/// or a tar archive held, each directory before what is in it.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub entries: Vec<(String, usize)>
+}
+
+impl Manifest {
+    /// The inode number `path` was copied to.
+    pub fn inumber(&self, path: &str) -> Option<usize> {
+        self.entries.iter().find(|(entry, _)| entry == path).map(|(_, inumber)| *inumber)
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (path, inumber) in self.entries.iter() {
+            writeln!(f, "{}\t{}", inumber, path)?;
+        }
+        Ok(())
+    }
+}
+
+// what a host tree takes on a filesystem
+#[derive(Debug, Default, PartialEq)]
+struct Usage {
+    inodes: usize,
+    blocks: usize  // data and pointer blocks
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Copies the host directory `source`, files and subdirectories, into
+    /// the directory `target` of the mounted filesystem. Entries are copied
+    /// in name order; anything but regular files and directories (symbolic
+    /// links, devices, ...) is skipped.
+    pub fn copy_tree<P: AsRef<Path>>(&mut self, source: P, target: &str) -> Result<Manifest> {
+        let inumber = self.lookup(target)?;
+        if !self.is_dir(inumber)? {
+            return Err(Error::NotADirectory(target.to_string()));
+        }
+
+        let mut manifest = Manifest::default();
+        self.copy_dir(source.as_ref(), target.trim_end_matches('/'), &mut manifest)?;
+        Ok(manifest)
+    }
+
+    fn copy_dir(&mut self, source: &Path, target: &str, manifest: &mut Manifest) -> Result<()> {
+        for (name, path) in host_entries(source)? {
+            let target = format!("{}/{}", target, name);
+            let file_type = fs::symlink_metadata(&path)?.file_type();
+            if file_type.is_dir() {
+                let inumber = self.mkdir(&target)?;
+                manifest.entries.push((target.clone(), inumber));
+                self.copy_dir(&path, &target, manifest)?;
+            } else if file_type.is_file() {
+                let inumber = self.open_new(&target)?;
+                self.copy_file(&path, inumber)?;
+                manifest.entries.push((target, inumber));
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file(&mut self, source: &Path, inumber: usize) -> Result<()> {
+        let mut file = File::open(source)?;
+        let mut buffer = vec![0; COPY_BLOCKS * Disk::BLOCK_SIZE];
+        let mut offset = 0;
+        loop {
+            let length = file.read(&mut buffer)?;
+            if length == 0 {
+                return Ok(());
+            }
+            if self.write(inumber, &buffer, length, offset)? < length {
+                return Err(Error::NoSpace);
+            }
+            offset += length;
+        }
+    }
+}
+
+/// The smallest image that, formatted with `options`, holds the tree at
+/// `source`.
+pub fn blocks_needed<P: AsRef<Path>>(source: P, options: &FormatOptions) -> Result<usize> {
+    // the root directory, with what it holds
+    let usage = tree_usage(source.as_ref())?;
+    let usage = Usage { inodes: usage.inodes + 1, blocks: usage.blocks };
+
+    // the layout grows with the disk, so try larger ones until it fits
+    let mut blocks = usage.blocks + 2;
+    while blocks <= u32::MAX as usize {
+        if let Ok(superblock) = options.superblock(blocks) {
+            if superblock.inode_count() >= usage.inodes
+                && superblock.data_end() - superblock.data_start() >= usage.blocks {
+                return Ok(blocks);
+            }
+        }
+        blocks += (blocks / 64).max(1);
+    }
+    Err(Error::NoSpace)
+}
+
+/// Creates the image `image` just large enough for the tree at `source`,
+/// formatted with `options`, and copies the tree into its root directory.
+pub fn build_image<P: AsRef<Path>, Q: AsRef<Path>>(source: P, image: Q, options: &FormatOptions) -> Result<Manifest> {
+    let blocks = blocks_needed(source.as_ref(), options)?;
+    let mut disk = Disk::create(image, blocks)?;
+    FileSystem::format_with(&mut disk, options)?;
+
+    let mut fs = FileSystem::new(disk);
+    fs.mount()?;
+    let manifest = fs.copy_tree(source, "/");
+    let unmounted = fs.unmount();
+    let manifest = manifest?;
+    unmounted?;
+    Ok(manifest)
+}
+
+// the entries of a host directory that `copy_tree` copies, by name
+fn host_entries(dir: &Path) -> Result<Vec<(String, std::path::PathBuf)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string()
+            .map_err(|name| Error::InvalidPath(name.to_string_lossy().into_owned()))?;
+        entries.push((name, entry.path()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+// inodes and blocks of everything below `dir`, and the blocks of `dir`
+// itself (its inode is counted by the caller)
+fn tree_usage(dir: &Path) -> Result<Usage> {
+    let mut usage = Usage::default();
+    let mut entries = 2;  // `.` and `..`
+    for (_, path) in host_entries(dir)? {
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            let below = tree_usage(&path)?;
+            usage.inodes += below.inodes + 1;
+            usage.blocks += below.blocks;
+        } else if metadata.is_file() {
+            usage.inodes += 1;
+            usage.blocks += file_blocks(metadata.len() as usize);
+        } else {
+            continue;
+        }
+        entries += 1;
+    }
+    usage.blocks += file_blocks(entries * DIR_ENTRY_SIZE);
+    Ok(usage)
+}
+
+// the data blocks of a file of `size` bytes plus the pointer blocks
+// leading to them
+fn file_blocks(size: usize) -> usize {
+    let data = size.div_ceil(Disk::BLOCK_SIZE);
+    let mut blocks = data;
+    let mut rest = data.saturating_sub(POINTERS_PER_INODE);
+    let mut span = 1;
+    for level in 1..=3 {
+        if rest == 0 {
+            break;
+        }
+        span *= POINTERS_PER_BLOCK;
+        let here = rest.min(span);
+
+        // a tree `level` deep has a pointer block per POINTERS_PER_BLOCK
+        // blocks below it at every depth
+        for depth in 1..=level {
+            blocks += here.div_ceil(POINTERS_PER_BLOCK.pow(depth));
+        }
+        rest -= here;
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memdisk::MemDisk;
+    use super::super::testutil::pattern;
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("tfs-build-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_blocks() {
+        assert_eq!(file_blocks(0), 0);
+        assert_eq!(file_blocks(1), 1);
+        assert_eq!(file_blocks(5 * Disk::BLOCK_SIZE), 5);
+        assert_eq!(file_blocks(6 * Disk::BLOCK_SIZE), 7);
+        assert_eq!(file_blocks((5 + 1024) * Disk::BLOCK_SIZE), 1030);
+        assert_eq!(file_blocks((5 + 1025) * Disk::BLOCK_SIZE), 1030 + 1 + 2);
+        assert_eq!(file_blocks((5 + 1024 + 2048) * Disk::BLOCK_SIZE), 3077 + 1 + 1 + 2);
+    }
+
+    #[test]
+    fn test_build_image() {
+        let source = scratch("tree");
+        fs::create_dir_all(source.join("docs/empty")).unwrap();
+        fs::write(source.join("small"), b"hello").unwrap();
+        fs::write(source.join("docs/large"), pattern(1100 * Disk::BLOCK_SIZE + 7, 1)).unwrap();
+        fs::write(source.join("docs/zero"), b"").unwrap();
+        for i in 0..70 {
+            fs::write(source.join(format!("docs/file{:02}", i)), pattern(100 + i, i as u8)).unwrap();
+        }
+
+        let image = source.with_extension("img");
+        let options = FormatOptions::new().label("tree");
+        let manifest = build_image(&source, &image, &options).unwrap();
+        assert_eq!(manifest.entries.len(), 75);
+        assert_eq!(manifest.entries[0].0, "/docs");
+        assert_eq!(manifest.entries.last().unwrap().0, "/small");
+        assert!(manifest.to_string().starts_with(&format!("{}\t/docs\n", manifest.entries[0].1)));
+
+        // the image is just large enough
+        let blocks = blocks_needed(&source, &options).unwrap();
+        assert_eq!(fs::metadata(&image).unwrap().len(), (blocks * Disk::BLOCK_SIZE) as u64);
+        assert!(matches!(build_image(&source, &image, &FormatOptions::new().inodes(64)), Err(Error::NoSpace)));
+
+        let mut disk = Disk::from_image(&image).unwrap();
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        for (path, expected) in [("/small", b"hello".to_vec()), ("/docs/large", pattern(1100 * Disk::BLOCK_SIZE + 7, 1)),
+                                 ("/docs/file69", pattern(169, 69)), ("/docs/zero", Vec::new())].iter() {
+            let inumber = fs.lookup(path).unwrap();
+            assert_eq!(Some(inumber), manifest.inumber(path));
+            let mut data = vec![0; expected.len()];
+            assert_eq!(fs.read(inumber, &mut data, expected.len(), 0).unwrap(), expected.len());
+            assert!(&data == expected, "{} differs", path);
+        }
+        let empty = fs.lookup("/docs/empty").unwrap();
+        assert!(fs.is_dir(empty).unwrap());
+        fs.unmount().unwrap();
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn test_copy_tree_into_subdirectory() {
+        let source = scratch("subdir");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"a").unwrap();
+
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        fs.mkdir("/import").unwrap();
+        let manifest = fs.copy_tree(&source, "/import/").unwrap();
+        assert_eq!(manifest.entries, vec![("/import/a".to_string(), fs.lookup("/import/a").unwrap())]);
+
+        // files already there are not overwritten, as directories are not
+        let inumber = fs.lookup("/import/a").unwrap();
+        fs.write(inumber, b"longer", 6, 0).unwrap();
+        assert!(matches!(fs.copy_tree(&source, "/import"), Err(Error::AlreadyExists(_))));
+        let mut data = [0; 6];
+        assert_eq!(fs.read(inumber, &mut data, 6, 0).unwrap(), 6);
+        assert_eq!(&data, b"longer");
+
+        // the target has to be a directory
+        assert!(matches!(fs.copy_tree(&source, "/import/a"), Err(Error::NotADirectory(_))));
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
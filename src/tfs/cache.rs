@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::device::BlockDevice;
+use super::error::{Error, Result};
+
+/// A write-back buffer cache in front of a `BlockDevice`.
+///
+/// Recently used blocks are kept in memory, up to `capacity` of them; the
+/// least recently used one makes room for the next. Writes only reach the
+/// device when their block is evicted or on `sync`/`flush`, which the
+/// filesystem calls at every point it needs them to be durable. Anything
+/// written after the last `sync` is lost if the cache is dropped.
+pub struct BlockCache<D: BlockDevice> {
+    disk: D,
+    Capacity: usize,                    // most blocks held at once
+    Blocks: HashMap<usize, CachedBlock>,
+    Recent: BTreeMap<u64, usize>,       // block numbers by last use, oldest first
+    Clock: u64,                         // stamp for the next use
+    Hits: usize,                        // Number of lookups found in the cache
+    Misses: usize                       // Number of lookups that were not
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,     // newer than the copy on the device
+    last_used: u64   // key in `Recent`
+}
+
+impl<D: BlockDevice> BlockCache<D> {
+    /// 256 blocks, a megabyte with 4 KB blocks
+    pub const DEFAULT_CAPACITY: usize = 256;
+
+    pub fn new(disk: D) -> BlockCache<D> {
+        Self::with_capacity(disk, Self::DEFAULT_CAPACITY)
+    }
+
+    /// A cache of `capacity` blocks; 0 passes every read and write through.
+    pub fn with_capacity(disk: D, capacity: usize) -> BlockCache<D> {
+        BlockCache {
+            disk,
+            Capacity: capacity,
+            Blocks: HashMap::new(),
+            Recent: BTreeMap::new(),
+            Clock: 0,
+            Hits: 0,
+            Misses: 0
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.Capacity
+    }
+
+    pub fn hits(&self) -> usize {
+        self.Hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.Misses
+    }
+
+    /// Number of blocks waiting to be written back.
+    pub fn dirty(&self) -> usize {
+        self.Blocks.values().filter(|block| block.dirty).count()
+    }
+
+    pub fn get_ref(&self) -> &D {
+        &self.disk
+    }
+
+    /// The device underneath. Writing to it directly bypasses the cache,
+    /// which may then hold stale copies; `sync` and `clear` first.
+    pub fn get_mut(&mut self) -> &mut D {
+        &mut self.disk
+    }
+
+    /// Writes back every dirty block, then returns the device.
+    pub fn into_inner(mut self) -> Result<D> {
+        self.sync()?;
+        Ok(self.disk)
+    }
+
+    /// Writes every dirty block back and flushes the device. Runs of
+    /// adjacent dirty blocks go out as a single write.
+    pub fn sync(&mut self) -> Result<()> {
+        let mut dirty: Vec<usize> = self.Blocks.iter()
+            .filter(|(_, block)| block.dirty)
+            .map(|(blocknum, _)| *blocknum)
+            .collect();
+        dirty.sort_unstable();
+
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len() && dirty[end] == dirty[end - 1] + 1 {
+                end += 1;
+            }
+
+            let blocks = &self.Blocks;
+            let run: Vec<&[u8]> = dirty[start..end].iter().map(|blocknum| &blocks[blocknum].data[..]).collect();
+            self.disk.write_blocks(dirty[start], &run)?;
+            for blocknum in dirty[start..end].iter() {
+                self.Blocks.get_mut(blocknum).unwrap().dirty = false;
+            }
+            start = end;
+        }
+        self.disk.flush()
+    }
+
+    /// Writes back and forgets every cached block.
+    pub fn clear(&mut self) -> Result<()> {
+        self.sync()?;
+        self.Blocks.clear();
+        self.Recent.clear();
+        Ok(())
+    }
+
+    fn check_range(&self, blocknum: usize) -> Result<()> {
+        if blocknum >= self.disk.block_count() {
+            return Err(Error::BlockOutOfRange { block: blocknum, blocks: self.disk.block_count() });
+        }
+        Ok(())
+    }
+
+    // marks `blocknum` as the most recently used block
+    fn touch(&mut self, blocknum: usize) -> &mut CachedBlock {
+        let stamp = self.Clock;
+        self.Clock += 1;
+
+        let block = self.Blocks.get_mut(&blocknum).unwrap();
+        self.Recent.remove(&block.last_used);
+        self.Recent.insert(stamp, blocknum);
+        block.last_used = stamp;
+        block
+    }
+
+    // finds `blocknum` in the cache, reading it in (if `load`) after making
+    // room for it
+    fn lookup(&mut self, blocknum: usize, load: bool) -> Result<&mut CachedBlock> {
+        if self.Blocks.contains_key(&blocknum) {
+            self.Hits += 1;
+            return Ok(self.touch(blocknum));
+        }
+        self.Misses += 1;
+
+        let mut data = vec![0; self.disk.block_size()];
+        if load {
+            self.disk.read_block(blocknum, &mut data)?;
+        }
+        self.insert(blocknum, data)?;
+        Ok(self.touch(blocknum))
+    }
+
+    fn insert(&mut self, blocknum: usize, data: Vec<u8>) -> Result<()> {
+        if self.Blocks.len() >= self.Capacity {
+            self.evict()?;
+        }
+        self.Blocks.insert(blocknum, CachedBlock { data, dirty: false, last_used: 0 });
+        self.touch(blocknum);
+        Ok(())
+    }
+
+    // reads the blocks of `blocknum..end` that are not cached yet, each run
+    // of them with a single request
+    fn load_run(&mut self, blocknum: usize, end: usize) -> Result<()> {
+        let block_size = self.disk.block_size();
+        let mut start = blocknum;
+        while start < end {
+            if self.Blocks.contains_key(&start) {
+                start += 1;
+                continue;
+            }
+            let mut stop = start + 1;
+            while stop < end && !self.Blocks.contains_key(&stop) {
+                stop += 1;
+            }
+
+            let mut data = vec![0; (stop - start) * block_size];
+            self.disk.read_blocks(start, &mut data)?;
+            for (i, block) in data.chunks(block_size).enumerate() {
+                self.insert(start + i, block.to_vec())?;
+            }
+            start = stop;
+        }
+        Ok(())
+    }
+
+    fn evict(&mut self) -> Result<()> {
+        let (stamp, blocknum) = match self.Recent.iter().next() {
+            Some((stamp, blocknum)) => (*stamp, *blocknum),
+            None => return Ok(())
+        };
+
+        let block = &self.Blocks[&blocknum];
+        if block.dirty {
+            self.disk.write_block(blocknum, &block.data)?;
+        }
+        self.Recent.remove(&stamp);
+        self.Blocks.remove(&blocknum);
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for BlockCache<D> {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.disk.block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        if self.Capacity == 0 {
+            return self.disk.read_block(blocknum, data);
+        }
+        self.check_range(blocknum)?;
+
+        let block = self.lookup(blocknum, true)?;
+        data.copy_from_slice(&block.data[..data.len()]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        if self.Capacity == 0 {
+            return self.disk.write_block(blocknum, data);
+        }
+        self.check_range(blocknum)?;
+
+        // a whole block replaces the old contents without reading them
+        let partial = data.len() < self.disk.block_size();
+        let block = self.lookup(blocknum, partial)?;
+        block.data[..data.len()].copy_from_slice(data);
+        block.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+
+    fn read_blocks(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        let block_size = self.disk.block_size();
+        let count = data.len() / block_size;
+        if self.Capacity < count {
+            // a run larger than the cache would only push everything out
+            self.sync()?;
+            return self.disk.read_blocks(blocknum, data);
+        }
+        self.check_range(blocknum + count.max(1) - 1)?;
+
+        // the cached part of the run is used first, so that loading the
+        // rest cannot evict it
+        let cached: Vec<usize> = (blocknum..blocknum + count).filter(|i| self.Blocks.contains_key(i)).collect();
+        for i in cached.iter() {
+            self.touch(*i);
+        }
+        self.Hits += cached.len();
+        self.Misses += count - cached.len();
+        self.load_run(blocknum, blocknum + count)?;
+        for (i, chunk) in data.chunks_mut(block_size).enumerate() {
+            chunk.copy_from_slice(&self.touch(blocknum + i).data);
+        }
+        Ok(())
+    }
+
+    /// Reads ahead the blocks not cached yet, up to half the cache.
+    fn prefetch(&mut self, blocknum: usize, count: usize) -> Result<()> {
+        let end = (blocknum + count.min(self.Capacity / 2)).min(self.disk.block_count());
+        self.load_run(blocknum, end)
+    }
+
+    /// Writes everything back first, and forgets the blocks that go.
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        self.sync()?;
+        let gone: Vec<usize> = self.Blocks.keys().filter(|blocknum| **blocknum >= blocks).copied().collect();
+        for blocknum in gone {
+            let block = self.Blocks.remove(&blocknum).unwrap();
+            self.Recent.remove(&block.last_used);
+        }
+        self.disk.resize(blocks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::crash::{check_crashes_with, RecordingDisk};
+    use super::super::disk::Disk;
+    use super::super::memdisk::MemDisk;
+    use super::super::types::JOURNAL_MIN_BLOCKS;
+    use super::super::FileSystem;
+
+    fn block(value: u8) -> [u8; Disk::BLOCK_SIZE] {
+        [value; Disk::BLOCK_SIZE]
+    }
+
+    #[test]
+    fn test_hits_misses_and_write_back() {
+        let mut cache = BlockCache::with_capacity(MemDisk::new(10), 4);
+        let mut data = block(0);
+
+        cache.write_block(1, &block(1)).unwrap();
+        cache.read_block(1, &mut data).unwrap();
+        cache.read_block(2, &mut data).unwrap();
+        cache.read_block(2, &mut data).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+        assert_eq!(cache.dirty(), 1);
+
+        // nothing reaches the device before the sync
+        assert_eq!((cache.get_ref().reads(), cache.get_ref().writes()), (1, 0));
+        cache.sync().unwrap();
+        assert_eq!(cache.get_ref().writes(), 1);
+        assert_eq!(cache.dirty(), 0);
+        cache.sync().unwrap();
+        assert_eq!(cache.get_ref().writes(), 1);
+
+        let disk = cache.into_inner().unwrap();
+        disk.read(1, &mut data).unwrap();
+        assert_eq!(data, block(1));
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = BlockCache::with_capacity(MemDisk::new(10), 2);
+        let mut data = block(0);
+
+        cache.write_block(1, &block(1)).unwrap();
+        cache.write_block(2, &block(2)).unwrap();
+        cache.read_block(1, &mut data).unwrap();
+
+        // block 2 is the least recently used, so it is written back to make room
+        cache.write_block(3, &block(3)).unwrap();
+        assert_eq!(cache.get_ref().writes(), 1);
+        cache.get_mut().read(2, &mut data).unwrap();
+        assert_eq!(data, block(2));
+
+        let misses = cache.misses();
+        cache.read_block(1, &mut data).unwrap();
+        assert_eq!(data, block(1));
+        assert_eq!(cache.misses(), misses);
+
+        // evicted blocks read back from the device
+        cache.read_block(2, &mut data).unwrap();
+        assert_eq!(data, block(2));
+        assert_eq!(cache.misses(), misses + 1);
+
+        assert!(matches!(
+            cache.read_block(10, &mut data),
+            Err(Error::BlockOutOfRange { block: 10, blocks: 10 })
+        ));
+    }
+
+    // remembers the runs written to it
+    struct Runs {
+        disk: MemDisk,
+        runs: Vec<(usize, usize)>
+    }
+
+    impl BlockDevice for Runs {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> usize {
+            self.disk.block_count()
+        }
+
+        fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+            self.disk.read_block(blocknum, data)
+        }
+
+        fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+            self.write_blocks(blocknum, &[data])
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, blocknum: usize, blocks: &[&[u8]]) -> Result<()> {
+            self.runs.push((blocknum, blocks.len()));
+            self.disk.write_blocks(blocknum, blocks)
+        }
+    }
+
+    #[test]
+    fn test_sync_coalesces_runs() {
+        let mut cache = BlockCache::with_capacity(Runs { disk: MemDisk::new(20), runs: Vec::new() }, 10);
+        for blocknum in [7, 3, 5, 4, 9, 8, 12].iter() {
+            cache.write_block(*blocknum, &block(*blocknum as u8)).unwrap();
+        }
+        cache.sync().unwrap();
+        assert_eq!(cache.get_ref().runs, vec![(3, 3), (7, 3), (12, 1)]);
+
+        let disk = cache.into_inner().unwrap().disk;
+        let mut data = block(0);
+        disk.read(8, &mut data).unwrap();
+        assert_eq!(data, block(8));
+    }
+
+    #[test]
+    fn test_no_capacity_passes_through() {
+        let mut cache = BlockCache::with_capacity(MemDisk::new(10), 0);
+        cache.write_block(4, &block(4)).unwrap();
+        assert_eq!(cache.get_ref().writes(), 1);
+        assert_eq!((cache.hits(), cache.misses()), (0, 0));
+    }
+
+    #[test]
+    fn test_resize_drops_blocks_past_the_end() {
+        let mut cache = BlockCache::with_capacity(MemDisk::new(10), 4);
+        cache.write_block(2, &block(2)).unwrap();
+        cache.write_block(8, &block(8)).unwrap();
+
+        cache.resize(5).unwrap();
+        assert_eq!(cache.block_count(), 5);
+        assert_eq!(cache.dirty(), 0);
+        assert!(cache.read_block(8, &mut block(0)).is_err());
+
+        // growing again brings back zeroed blocks, not the cached copy
+        cache.resize(10).unwrap();
+        let mut data = block(1);
+        cache.read_block(8, &mut data).unwrap();
+        assert_eq!(data, block(0));
+        cache.read_block(2, &mut data).unwrap();
+        assert_eq!(data, block(2));
+    }
+
+    #[test]
+    fn test_filesystem_through_the_cache() {
+        let mut disk = MemDisk::new(200);
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(BlockCache::new(disk));
+        fs.mount().unwrap();
+
+        let inumber = fs.open("/file").unwrap();
+        let data = vec![9; 100 * 1024];
+        for offset in (0..data.len()).step_by(1000) {
+            let end = (offset + 1000).min(data.len());
+            fs.write(inumber, &data[offset..end], end - offset, offset).unwrap();
+        }
+        let cache = fs.into_disk().unwrap();
+        assert!(cache.hits() > cache.misses());
+
+        // every write made it through the cache to the image
+        let mut disk = cache.into_inner().unwrap();
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        let inumber = fs.lookup("/file").unwrap();
+        let mut read = vec![0; data.len()];
+        assert_eq!(fs.read(inumber, &mut read, data.len(), 0).unwrap(), data.len());
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_crash_with_a_small_cache() {
+        // evictions between flushes must not break the journal's ordering
+        check_crashes_with(
+            JOURNAL_MIN_BLOCKS,
+            |_| {},
+            |disk: &mut RecordingDisk| {
+                let mut fs = FileSystem::new(BlockCache::with_capacity(disk, 4));
+                fs.mount().unwrap();
+                fs.mkdir("/dir").unwrap();
+                let inumber = fs.open("/dir/file").unwrap();
+                fs.write(inumber, &[7; 3 * Disk::BLOCK_SIZE], 3 * Disk::BLOCK_SIZE, 0).unwrap();
+                fs.unmount().unwrap();
+            },
+            |fs| {
+                if let Ok(inumber) = fs.lookup("/dir/file") {
+                    let size = fs.stat(inumber).unwrap();
+                    let mut data = vec![0; size];
+                    fs.read(inumber, &mut data, size, 0).unwrap();
+                    assert!(data.iter().all(|byte| *byte == 7));
+                }
+            }
+        );
+    }
+}
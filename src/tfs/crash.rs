@@ -0,0 +1,392 @@
+//! Crash-consistency harness: records every write a workload makes, then
+//! rebuilds the image as a power loss at each point could have left it and
+//! checks that it still mounts and passes `FileSystem::check`.
+//!
+//! Writes between two flushes may reach the disk in any order, so besides
+//! every prefix of the log the harness also tries subsets of the writes
+//! after the last completed flush.
+
+use std::io;
+
+use super::device::BlockDevice;
+use super::error::{Error, Result};
+use super::memdisk::MemDisk;
+use super::FileSystem;
+
+// epochs with more writes than this get a sample of subsets instead of all
+const MAX_EXHAUSTIVE_WRITES: usize = 8;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Write(usize, Vec<u8>),
+    Flush
+}
+
+/// A `MemDisk` that logs what is written to it.
+pub struct RecordingDisk {
+    pub disk: MemDisk,
+    pub log: Vec<Event>
+}
+
+impl RecordingDisk {
+    pub fn new(disk: MemDisk) -> RecordingDisk {
+        RecordingDisk { disk, log: Vec::new() }
+    }
+}
+
+impl BlockDevice for RecordingDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.disk.block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.disk.read_block(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.log.push(Event::Write(blocknum, data.to_vec()));
+        self.disk.write_block(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.log.push(Event::Flush);
+        self.disk.flush()
+    }
+
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        self.disk.resize(blocks)
+    }
+}
+
+/// A `MemDisk` that stops working, as an unplugged device would, once it
+/// has taken `writes_left` more writes: later writes and flushes fail.
+pub struct FailingDisk {
+    pub disk: MemDisk,
+    pub writes_left: usize
+}
+
+impl FailingDisk {
+    fn check(&self) -> Result<()> {
+        if self.writes_left == 0 {
+            return Err(Error::Io(io::Error::other("device failed")));
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for FailingDisk {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        self.disk.block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.disk.read_block(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.check()?;
+        self.writes_left -= 1;
+        self.disk.write_block(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.check()?;
+        self.disk.flush()
+    }
+}
+
+/// One state the disk could be found in after a crash: every write of
+/// the epochs before `epoch`, plus the writes of `epoch` picked by `kept`.
+pub struct CrashPoint<'a> {
+    pub epoch: usize,
+    pub kept: Vec<usize>,
+    writes: Vec<&'a (usize, Vec<u8>)>
+}
+
+impl<'a> CrashPoint<'a> {
+    /// `base` with the writes of this crash point applied.
+    pub fn image(&self, base: &MemDisk) -> MemDisk {
+        let disk = base.clone();
+        for (block_num, data) in self.writes.iter() {
+            disk.write(*block_num, data).unwrap();
+        }
+        disk
+    }
+}
+
+/// Splits a log into the writes between consecutive flushes.
+pub fn epochs(log: &[Event]) -> Vec<Vec<(usize, Vec<u8>)>> {
+    let mut epochs = vec![Vec::new()];
+    for event in log {
+        match event {
+            Event::Write(block_num, data) => epochs.last_mut().unwrap().push((*block_num, data.clone())),
+            Event::Flush => epochs.push(Vec::new())
+        }
+    }
+    epochs.retain(|epoch| !epoch.is_empty());
+    epochs
+}
+
+// the subsets of `n` writes worth trying: all of them for small epochs,
+// otherwise every prefix, every single write, and every write left out
+fn subsets(n: usize) -> Vec<Vec<usize>> {
+    if n <= MAX_EXHAUSTIVE_WRITES {
+        return (0..1usize << n)
+            .map(|mask| (0..n).filter(|i| mask & (1 << i) != 0).collect())
+            .collect();
+    }
+
+    let mut subsets: Vec<Vec<usize>> = (0..=n).map(|len| (0..len).collect()).collect();
+    subsets.extend((0..n).map(|i| vec![i]));
+    subsets.extend((0..n).map(|i| (0..n).filter(|j| *j != i).collect()));
+    subsets.sort();
+    subsets.dedup();
+    subsets
+}
+
+/// Every crash point of `epochs`, in order. The last one is the image
+/// the workload left behind.
+pub fn crash_points(epochs: &[Vec<(usize, Vec<u8>)>]) -> Vec<CrashPoint<'_>> {
+    let mut points = Vec::new();
+    for (epoch, writes) in epochs.iter().enumerate() {
+        let done: Vec<&(usize, Vec<u8>)> = epochs[..epoch].iter().flatten().collect();
+        for kept in subsets(writes.len()) {
+            let mut applied = done.clone();
+            applied.extend(kept.iter().map(|i| &writes[*i]));
+            points.push(CrashPoint { epoch, kept, writes: applied });
+        }
+    }
+    points
+}
+
+/// Runs `workload` on a filesystem of `blocks` blocks prepared by `setup`
+/// and checks every crash point: the image must mount, pass the checker
+/// and satisfy `verify`. Returns the number of crash points tried.
+pub fn check_crashes(
+    blocks: usize,
+    setup: impl FnOnce(&mut FileSystem<&mut MemDisk>),
+    workload: impl FnOnce(&mut FileSystem<&mut RecordingDisk>),
+    verify: impl Fn(&mut FileSystem<&mut MemDisk>)
+) -> usize {
+    // mounting is part of the workload: it writes the superblock too
+    check_crashes_with(blocks, setup, |disk| {
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        workload(&mut fs);
+        fs.unmount().unwrap();
+    }, verify)
+}
+
+/// `check_crashes` for workloads that bring their own filesystem (say,
+/// through a cache) on top of the recording device.
+pub fn check_crashes_with(
+    blocks: usize,
+    setup: impl FnOnce(&mut FileSystem<&mut MemDisk>),
+    workload: impl FnOnce(&mut RecordingDisk),
+    verify: impl Fn(&mut FileSystem<&mut MemDisk>)
+) -> usize {
+    let mut base = MemDisk::new(blocks);
+    FileSystem::format(&mut base).unwrap();
+    let mut fs = FileSystem::new(&mut base);
+    fs.mount().unwrap();
+    setup(&mut fs);
+    fs.unmount().unwrap();
+
+    let mut recorder = RecordingDisk::new(base.clone());
+    workload(&mut recorder);
+
+    let epochs = epochs(&recorder.log);
+    let points = crash_points(&epochs);
+    for point in points.iter() {
+        let mut disk = point.image(&base);
+        let context = format!("crash in epoch {} keeping writes {:?}", point.epoch, point.kept);
+
+        let mut fs = FileSystem::new(&mut disk);
+        if let Err(e) = fs.mount() {
+            panic!("{}: mount failed: {}", context, e);
+        }
+        verify(&mut fs);
+        fs.unmount().unwrap();
+
+        let report = FileSystem::check(&mut disk, false).unwrap();
+        assert!(report.is_clean(), "{}: {:?}", context, report.problems);
+    }
+
+    points.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testutil::{assert_file, pattern};
+    use super::super::types::JOURNAL_MIN_BLOCKS;
+
+    #[test]
+    fn test_epochs_and_subsets() {
+        let log = vec![
+            Event::Flush,
+            Event::Write(3, vec![1]),
+            Event::Write(4, vec![2]),
+            Event::Flush,
+            Event::Flush,
+            Event::Write(3, vec![3])
+        ];
+        let epochs = epochs(&log);
+        assert_eq!(epochs.len(), 2);
+        assert_eq!(epochs[0], vec![(3, vec![1]), (4, vec![2])]);
+        assert_eq!(epochs[1], vec![(3, vec![3])]);
+
+        // {}, {0}, {1}, {0, 1}, then {} and {0} on top of the first epoch
+        let points = crash_points(&epochs);
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[2].kept, vec![1]);
+        assert_eq!(points[5].writes.len(), 3);
+
+        assert_eq!(subsets(3).len(), 8);
+        let large = subsets(20);
+        assert!(large.contains(&(0..20).collect()));
+        assert!(large.contains(&vec![7]));
+        assert!(large.contains(&(0..20).filter(|i| *i != 7).collect()));
+    }
+
+    #[test]
+    fn test_crash_while_writing_files() {
+        let points = check_crashes(
+            JOURNAL_MIN_BLOCKS,
+            |_| {},
+            |fs| {
+                let inumber = fs.open("/small").unwrap();
+                fs.write(inumber, &pattern(100, 1), 100, 0).unwrap();
+                let inumber = fs.open("/large").unwrap();
+                let data = pattern(20 * 4096, 2);
+                fs.write(inumber, &data, data.len(), 0).unwrap();
+            },
+            |fs| {
+                // a file holds a prefix of what was written, never garbage
+                for (name, seed) in [("/small", 1), ("/large", 2)].iter() {
+                    if let Ok(inumber) = fs.lookup(name) {
+                        let size = fs.stat(inumber).unwrap();
+                        let mut data = vec![0; size];
+                        fs.read(inumber, &mut data, size, 0).unwrap();
+                        assert_eq!(data, pattern(size, *seed));
+                    }
+                }
+            }
+        );
+        assert!(points > 100);
+    }
+
+    #[test]
+    fn test_crash_during_namespace_changes() {
+        check_crashes(
+            JOURNAL_MIN_BLOCKS,
+            |fs| {
+                fs.mkdir("/a").unwrap();
+                let inumber = fs.open("/a/file").unwrap();
+                fs.write(inumber, &pattern(5000, 3), 5000, 0).unwrap();
+                fs.open("/old").unwrap();
+            },
+            |fs| {
+                fs.mkdir("/b").unwrap();
+                fs.rename("/a/file", "/b/file").unwrap();
+                fs.rmdir("/a").unwrap();
+                fs.unlink("/old").unwrap();
+            },
+            |fs| {
+                // the file is never lost or linked twice by the move
+                let linked = fs.lookup("/a/file").is_ok() as usize + fs.lookup("/b/file").is_ok() as usize;
+                assert_eq!(linked, 1);
+            }
+        );
+    }
+
+    #[test]
+    fn test_crash_while_shrinking_and_removing() {
+        check_crashes(
+            JOURNAL_MIN_BLOCKS,
+            |fs| {
+                for (name, seed) in [("/one", 4), ("/two", 5)].iter() {
+                    let inumber = fs.open(name).unwrap();
+                    let data = pattern(12 * 4096, *seed);
+                    fs.write(inumber, &data, data.len(), 0).unwrap();
+                }
+            },
+            |fs| {
+                let inumber = fs.lookup("/one").unwrap();
+                fs.truncate(inumber, 3000).unwrap();
+                fs.unlink("/two").unwrap();
+            },
+            |fs| {
+                let inumber = fs.lookup("/one").unwrap();
+                let size = fs.stat(inumber).unwrap();
+                assert!(size == 3000 || size == 12 * 4096);
+                let mut data = vec![0; size];
+                fs.read(inumber, &mut data, size, 0).unwrap();
+                assert_eq!(data, pattern(size, 4));
+            }
+        );
+    }
+
+    #[test]
+    fn test_crash_while_removing_a_large_file() {
+        check_crashes(
+            JOURNAL_MIN_BLOCKS,
+            |fs| {
+                let inumber = fs.open("/large").unwrap();
+                let data = pattern(150 * 4096, 6);
+                fs.write(inumber, &data, data.len(), 0).unwrap();
+            },
+            |fs| fs.unlink("/large").unwrap(),
+            |fs| {
+                // gone, or still there holding a prefix of its data
+                if let Ok(inumber) = fs.lookup("/large") {
+                    let size = fs.stat(inumber).unwrap();
+                    let mut data = vec![0; size];
+                    fs.read(inumber, &mut data, size, 0).unwrap();
+                    assert_eq!(data, pattern(size, 6));
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn test_device_failing_part_way_through_a_write() {
+        // the write overwrites two blocks and adds four: the device gives
+        // out before each of them in turn
+        for writes_left in 0..6 {
+            let mut disk = MemDisk::new(JOURNAL_MIN_BLOCKS);
+            FileSystem::format(&mut disk).unwrap();
+            let mut fs = FileSystem::new(FailingDisk { disk, writes_left: usize::MAX });
+            fs.mount().unwrap();
+            let inumber = fs.open("/file").unwrap();
+            fs.write(inumber, &pattern(2 * 4096, 7), 2 * 4096, 0).unwrap();
+
+            fs.disk.writes_left = writes_left;
+            let data = pattern(6 * 4096, 8);
+            assert!(matches!(fs.write(inumber, &data, data.len(), 0), Err(Error::Io(_))));
+
+            // none of the failed transaction is left in memory: the file
+            // kept its size and the blocks it was growing into are free
+            fs.disk.writes_left = usize::MAX;
+            assert_eq!(fs.stat(inumber).unwrap(), 2 * 4096);
+            let (_, dataBitMap) = FileSystem::scan_bitmaps(&mut fs.disk, fs.metaData.as_ref().unwrap()).unwrap();
+            assert_eq!(fs.dataBitMap.as_ref(), Some(&dataBitMap), "failed after {} writes", writes_left);
+
+            // and the filesystem carries on from there
+            assert_eq!(fs.write(inumber, &data, data.len(), 0).unwrap(), data.len());
+            let mut disk = fs.into_disk().unwrap().disk;
+            assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+            let mut fs = FileSystem::new(&mut disk);
+            fs.mount().unwrap();
+            assert_file(&mut fs, "/file", &data);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::error::{Error, Result};
+
+/// A fixed-size array of blocks that a `FileSystem` can live on.
+///
+/// `Disk` is the file-backed implementation; anything else (memory,
+/// network, instrumented wrappers) only has to provide these methods.
+pub trait BlockDevice {
+    /// Number of bytes per block
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn block_count(&self) -> usize;
+
+    /// Reads block `blocknum` into `data`
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()>;
+
+    /// Writes `data` to block `blocknum`
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()>;
+
+    /// Makes every completed write durable
+    fn flush(&mut self) -> Result<()>;
+
+    /// Reads the consecutive blocks starting at `blocknum` into `data`, a
+    /// whole number of blocks long. Devices that can serve a run in one
+    /// request override this; the default reads block by block.
+    fn read_blocks(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        let block_size = self.block_size();
+        for (i, block) in data.chunks_mut(block_size).enumerate() {
+            self.read_block(blocknum + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `blocks` to the consecutive blocks starting at `blocknum`;
+    /// the default writes them one at a time.
+    fn write_blocks(&mut self, blocknum: usize, blocks: &[&[u8]]) -> Result<()> {
+        for (i, block) in blocks.iter().enumerate() {
+            self.write_block(blocknum + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Hints that the `count` blocks from `blocknum` on will be read soon.
+    /// Only caches have a use for it.
+    fn prefetch(&mut self, _blocknum: usize, _count: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// Grows or shrinks the device to `blocks` blocks, keeping the blocks
+    /// both sizes have. Fixed-size devices keep the default, which refuses.
+    fn resize(&mut self, _blocks: usize) -> Result<()> {
+        Err(Error::Io(io::Error::new(io::ErrorKind::Unsupported, "device cannot be resized")))
+    }
+}
+
+// lets a `FileSystem` borrow a device instead of owning it
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        (**self).block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        (**self).read_block(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        (**self).write_block(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn read_blocks(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        (**self).read_blocks(blocknum, data)
+    }
+
+    fn write_blocks(&mut self, blocknum: usize, blocks: &[&[u8]]) -> Result<()> {
+        (**self).write_blocks(blocknum, blocks)
+    }
+
+    fn prefetch(&mut self, blocknum: usize, count: usize) -> Result<()> {
+        (**self).prefetch(blocknum, count)
+    }
+
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        (**self).resize(blocks)
+    }
+}
+
+/// A `BlockDevice` that can also be read and written through a shared
+/// reference, so that several threads can have requests in flight at once.
+/// `SharedFileSystem` does its file data I/O this way, outside the lock
+/// that guards the filesystem's metadata.
+pub trait SharedDevice: BlockDevice + Send + Sync {
+    /// `read_block` through a shared reference
+    fn read_block_shared(&self, blocknum: usize, data: &mut [u8]) -> Result<()>;
+
+    /// `write_block` through a shared reference
+    fn write_block_shared(&self, blocknum: usize, data: &[u8]) -> Result<()>;
+
+    /// `flush` through a shared reference
+    fn flush_shared(&self) -> Result<()>;
+}
+
+// what a `FileSystem` owns when its device is shared with other threads
+impl<D: SharedDevice + ?Sized> BlockDevice for Arc<D> {
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        (**self).block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        (**self).read_block_shared(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        (**self).write_block_shared(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush_shared()
+    }
+}
+
+// any device can be shared by taking turns: the lock is held for a single
+// request, not a whole filesystem operation
+impl<D: BlockDevice + Send> BlockDevice for Mutex<D> {
+    fn block_size(&self) -> usize {
+        lock(self).block_size()
+    }
+
+    fn block_count(&self) -> usize {
+        lock(self).block_count()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read_block_shared(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.write_block_shared(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_shared()
+    }
+
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        self.get_mut().unwrap_or_else(|e| e.into_inner()).resize(blocks)
+    }
+}
+
+impl<D: BlockDevice + Send> SharedDevice for Mutex<D> {
+    fn read_block_shared(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        lock(self).read_block(blocknum, data)
+    }
+
+    fn write_block_shared(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+        lock(self).write_block(blocknum, data)
+    }
+
+    fn flush_shared(&self) -> Result<()> {
+        lock(self).flush()
+    }
+}
+
+// a request that panicked leaves the device as usable as before
+fn lock<D>(device: &Mutex<D>) -> MutexGuard<'_, D> {
+    device.lock().unwrap_or_else(|e| e.into_inner())
+}
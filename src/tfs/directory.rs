@@ -0,0 +1,514 @@
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::*;
+use super::FileSystem;
+
+// Directories are inodes whose data is an array of `DirEntry` slots. Every
+// directory starts with `.` and `..`, so walking a path never needs anything
+// but the entries themselves. Paths are always resolved from the root
+// directory; a leading `/` is optional.
+impl<D: BlockDevice> FileSystem<D> {
+    /// Returns the inode number of the file or directory at `path`.
+    pub fn lookup(&mut self, path: &str) -> Result<usize> {
+        let mut inumber = ROOT_INODE;
+        for name in Self::components(path) {
+            inumber = match self.find_entry(inumber, name, path)? {
+                Some((_, entry)) => entry.Inumber as usize,
+                None => return Err(Error::NotFound(path.to_string()))
+            };
+        }
+        Ok(inumber)
+    }
+
+    /// Returns the inode number of the regular file at `path`, creating an
+    /// empty one if the name does not exist yet.
+    pub fn open(&mut self, path: &str) -> Result<usize> {
+        self.transaction(|fs| {
+            let (parent, name) = fs.lookup_parent(path)?;
+            if let Some((_, entry)) = fs.find_entry(parent, &name, path)? {
+                if fs.is_dir(entry.Inumber as usize)? {
+                    return Err(Error::IsADirectory(path.to_string()));
+                }
+                return Ok(entry.Inumber as usize);
+            }
+
+            let inumber = fs.allocate_inode(FILE_INODE)?;
+            if let Err(e) = fs.add_entry(parent, &name, inumber) {
+                let inode = fs.load_inode(inumber)?;
+                fs.free_inode(inumber, inode)?;
+                return Err(e);
+            }
+            Ok(inumber)
+        })
+    }
+
+    // `open` for a file that must not exist yet, as `mkdir` is for
+    // directories
+    pub(super) fn open_new(&mut self, path: &str) -> Result<usize> {
+        self.transaction(|fs| {
+            let (parent, name) = fs.lookup_parent(path)?;
+            if fs.find_entry(parent, &name, path)?.is_some() {
+                return Err(Error::AlreadyExists(path.to_string()));
+            }
+            fs.open(path)
+        })
+    }
+
+    /// Creates an empty directory at `path` and returns its inode number.
+    pub fn mkdir(&mut self, path: &str) -> Result<usize> {
+        self.transaction(|fs| {
+            let (parent, name) = fs.lookup_parent(path)?;
+            if fs.find_entry(parent, &name, path)?.is_some() {
+                return Err(Error::AlreadyExists(path.to_string()));
+            }
+
+            let inumber = fs.allocate_inode(DIRECTORY_INODE)?;
+            let result = fs.write_entry(inumber, 0, DirEntry::new(".", inumber))
+                .and_then(|_| fs.write_entry(inumber, 1, DirEntry::new("..", parent)))
+                .and_then(|_| fs.add_entry(parent, &name, inumber));
+            if let Err(e) = result {
+                let inode = fs.load_inode(inumber)?;
+                fs.free_inode(inumber, inode)?;
+                return Err(e);
+            }
+            Ok(inumber)
+        })
+    }
+
+    /// Lists the names (other than `.` and `..`) in the directory at `path`
+    /// together with their inode numbers.
+    pub fn readdir(&mut self, path: &str) -> Result<Vec<(String, usize)>> {
+        let inumber = self.lookup(path)?;
+        let entries = self.read_entries(inumber, path)?;
+        Ok(entries.iter()
+            .filter(|entry| entry.Valid != 0)
+            .map(|entry| (entry.name(), entry.Inumber as usize))
+            .filter(|(name, _)| name != "." && name != "..")
+            .collect())
+    }
+
+    /// Removes the regular file at `path`. A crash while a large file is
+    /// removed may leave it linked but shortened.
+    pub fn unlink(&mut self, path: &str) -> Result<()> {
+        if let Ok(inumber) = self.lookup(path) {
+            if !self.is_dir(inumber)? {
+                self.shrink_before_free(inumber)?;
+            }
+        }
+        self.transaction(|fs| {
+            let (parent, name) = fs.lookup_parent(path)?;
+            let (slot, entry) = fs.find_entry(parent, &name, path)?
+                .ok_or_else(|| Error::NotFound(path.to_string()))?;
+
+            let inode = fs.load_valid_inode(entry.Inumber as usize)?;
+            if inode.is_directory() {
+                return Err(Error::IsADirectory(path.to_string()));
+            }
+
+            fs.write_entry(parent, slot, DirEntry::blank())?;
+            fs.free_inode(entry.Inumber as usize, inode)
+        })
+    }
+
+    /// Removes the empty directory at `path`.
+    pub fn rmdir(&mut self, path: &str) -> Result<()> {
+        // an empty directory loses nothing but blank entries by shrinking
+        if let Ok(inumber) = self.lookup(path) {
+            if self.check_empty_directory(inumber, path).is_ok() {
+                self.shrink_before_free(inumber)?;
+            }
+        }
+        self.transaction(|fs| {
+            let (parent, name) = fs.lookup_parent(path)?;
+            let (slot, entry) = fs.find_entry(parent, &name, path)?
+                .ok_or_else(|| Error::NotFound(path.to_string()))?;
+
+            let inumber = entry.Inumber as usize;
+            fs.check_empty_directory(inumber, path)?;
+
+            fs.write_entry(parent, slot, DirEntry::blank())?;
+            let inode = fs.load_inode(inumber)?;
+            fs.free_inode(inumber, inode)
+        })
+    }
+
+    /// Moves the file or directory at `from` to `to`. An existing regular
+    /// file, or empty directory, at `to` is replaced.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        // the file being replaced is shrunk first, as `unlink` does; only
+        // once the move is sure to go ahead, unless it is an empty directory
+        if let (Ok(source), Ok(replaced)) = (self.lookup(from), self.lookup(to)) {
+            let replaceable = match (self.is_dir(source)?, self.is_dir(replaced)?) {
+                (false, false) => true,
+                (true, true) => self.check_empty_directory(replaced, to).is_ok(),
+                _ => false
+            };
+            if source != replaced && replaceable {
+                self.shrink_before_free(replaced)?;
+            }
+        }
+        self.transaction(|fs| {
+            let (src_parent, src_name) = fs.lookup_parent(from)?;
+            let (src_slot, src_entry) = fs.find_entry(src_parent, &src_name, from)?
+                .ok_or_else(|| Error::NotFound(from.to_string()))?;
+            let inumber = src_entry.Inumber as usize;
+            let is_dir = fs.is_dir(inumber)?;
+
+            let (dst_parent, dst_name) = fs.lookup_parent(to)?;
+
+            // a directory cannot be moved underneath itself
+            if is_dir {
+                let mut ancestor = dst_parent;
+                loop {
+                    if ancestor == inumber {
+                        return Err(Error::InvalidPath(to.to_string()));
+                    }
+                    if ancestor == ROOT_INODE {
+                        break;
+                    }
+                    ancestor = match fs.find_entry(ancestor, "..", to)? {
+                        Some((_, entry)) => entry.Inumber as usize,
+                        None => return Err(Error::CorruptMetadata(
+                            format!("directory inode {} has no parent entry", ancestor)
+                        ))
+                    };
+                }
+            }
+
+            match fs.find_entry(dst_parent, &dst_name, to)? {
+                Some((_, existing)) if existing.Inumber as usize == inumber => return Ok(()),
+                Some((dst_slot, existing)) => {
+                    // check the replaced file before anything is changed
+                    let replaced = existing.Inumber as usize;
+                    if is_dir {
+                        fs.check_empty_directory(replaced, to)?;
+                    } else if fs.is_dir(replaced)? {
+                        return Err(Error::IsADirectory(to.to_string()));
+                    }
+
+                    fs.write_entry(dst_parent, dst_slot, DirEntry::new(&dst_name, inumber))?;
+                    let inode = fs.load_inode(replaced)?;
+                    fs.free_inode(replaced, inode)?;
+                },
+                None => fs.add_entry(dst_parent, &dst_name, inumber)?
+            }
+
+            fs.write_entry(src_parent, src_slot, DirEntry::blank())?;
+
+            // a moved directory has a new parent
+            if is_dir && src_parent != dst_parent {
+                if let Some((slot, _)) = fs.find_entry(inumber, "..", to)? {
+                    fs.write_entry(inumber, slot, DirEntry::new("..", dst_parent))?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Whether `inumber` is a directory.
+    pub fn is_dir(&mut self, inumber: usize) -> Result<bool> {
+        Ok(self.load_valid_inode(inumber)?.is_directory())
+    }
+
+    // ****************** helper methods and functions *******************
+
+    /// Splits `path` into its names, dropping empty and `.` components.
+    fn components(path: &str) -> Vec<&str> {
+        path.split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect()
+    }
+
+    /// Resolves everything but the last name of `path`, returning the parent
+    /// directory and the (validated) last name.
+    fn lookup_parent(&mut self, path: &str) -> Result<(usize, String)> {
+        let mut names = Self::components(path);
+        let name = match names.pop() {
+            Some(name) => name,
+            None => return Err(Error::InvalidPath(path.to_string()))
+        };
+        if name == ".." || name.len() > NAME_MAX || name.contains('\0') {
+            return Err(Error::InvalidPath(path.to_string()));
+        }
+
+        let mut parent = ROOT_INODE;
+        for dir in names {
+            parent = match self.find_entry(parent, dir, path)? {
+                Some((_, entry)) => entry.Inumber as usize,
+                None => return Err(Error::NotFound(path.to_string()))
+            };
+        }
+        Ok((parent, name.to_string()))
+    }
+
+    fn read_entries(&mut self, dir: usize, path: &str) -> Result<Vec<DirEntry>> {
+        let inode = self.load_valid_inode(dir)?;
+        if !inode.is_directory() {
+            return Err(Error::NotADirectory(path.to_string()));
+        }
+
+        let size = inode.Size as usize;
+        let mut data = vec![0; size.div_ceil(Disk::BLOCK_SIZE) * Disk::BLOCK_SIZE];
+        self.read(dir, &mut data, size, 0)?;
+
+        // interpret each block of the directory as entries
+        let mut entries = Vec::new();
+        let mut block = Block::new();
+        for chunk in data.chunks(Disk::BLOCK_SIZE) {
+            let mut d = block.data();
+            d.copy_from_slice(chunk);
+            block.set_data(d);
+            entries.extend_from_slice(&block.entries());
+        }
+        entries.truncate(size / DIR_ENTRY_SIZE);
+        Ok(entries)
+    }
+
+    /// Finds `name` in directory `dir`, returning its slot and entry.
+    fn find_entry(&mut self, dir: usize, name: &str, path: &str) -> Result<Option<(usize, DirEntry)>> {
+        let entries = self.read_entries(dir, path)?;
+        Ok(entries.into_iter()
+            .enumerate()
+            .find(|(_, entry)| entry.Valid != 0 && entry.name() == name))
+    }
+
+    /// Links `name` to `inumber` in directory `dir`, reusing a free slot
+    /// when there is one.
+    fn add_entry(&mut self, dir: usize, name: &str, inumber: usize) -> Result<()> {
+        let entries = self.read_entries(dir, name)?;
+        let slot = entries.iter()
+            .position(|entry| entry.Valid == 0)
+            .unwrap_or(entries.len());
+        self.write_entry(dir, slot, DirEntry::new(name, inumber))
+    }
+
+    fn write_entry(&mut self, dir: usize, slot: usize, entry: DirEntry) -> Result<()> {
+        let mut entries = [DirEntry::blank(); ENTRIES_PER_BLOCK];
+        entries[0] = entry;
+        let mut block = Block::new();
+        block.set_entries(entries);
+
+        // entries never straddle a block, so this either fully succeeds or fails
+        let mut inode = self.load_valid_inode(dir)?;
+        self.write_inode(dir, &mut inode, &block.data()[..DIR_ENTRY_SIZE], slot * DIR_ENTRY_SIZE)?;
+        Ok(())
+    }
+
+    fn check_empty_directory(&mut self, inumber: usize, path: &str) -> Result<()> {
+        if inumber == ROOT_INODE {
+            return Err(Error::InvalidPath(path.to_string()));
+        }
+
+        let entries = self.read_entries(inumber, path)?;
+        let in_use = entries.iter()
+            .filter(|entry| entry.Valid != 0)
+            .any(|entry| {
+                let name = entry.name();
+                name != "." && name != ".."
+            });
+        if in_use {
+            return Err(Error::DirectoryNotEmpty(path.to_string()));
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::memdisk::MemDisk;
+    use super::*;
+
+    fn filesystem() -> FileSystem<MemDisk> {
+        FileSystem::from_disk(MemDisk::new(50)).unwrap()
+    }
+
+    fn names(fs: &mut FileSystem<MemDisk>, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs.readdir(path).unwrap().into_iter().map(|(name, _)| name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_root_directory() {
+        let mut fs = filesystem();
+        assert_eq!(fs.lookup("/").unwrap(), ROOT_INODE);
+        assert!(fs.is_dir(ROOT_INODE).unwrap());
+        assert!(fs.readdir("/").unwrap().is_empty());
+
+        // the root inode is taken, so plain inodes start after it
+        assert_eq!(fs.create().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_open_creates_and_finds() {
+        let mut fs = filesystem();
+        fs.mkdir("/a").unwrap();
+        let inumber = fs.open("/a/b.txt").unwrap();
+        fs.write(inumber, b"hello", 5, 0).unwrap();
+
+        assert_eq!(fs.open("/a/b.txt").unwrap(), inumber);
+        assert_eq!(fs.lookup("a/./b.txt").unwrap(), inumber);
+        assert_eq!(fs.lookup("/a/../a/b.txt").unwrap(), inumber);
+        assert_eq!(fs.stat(inumber).unwrap(), 5);
+
+        match fs.open("/missing/b.txt") {
+            Err(Error::NotFound(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.open("/a") {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.open("/a/b.txt/c") {
+            Err(Error::NotADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let mut fs = filesystem();
+        let long = format!("/{}", "x".repeat(NAME_MAX + 1));
+        for path in &["/", "", "/..", &long] {
+            match fs.open(path) {
+                Err(Error::InvalidPath(_)) => {},
+                r => panic!("unexpected result for {:?}: {:?}", path, r)
+            }
+        }
+        assert!(fs.open(&format!("/{}", "x".repeat(NAME_MAX))).is_ok());
+    }
+
+    #[test]
+    fn test_mkdir_readdir() {
+        let mut fs = filesystem();
+        fs.mkdir("/docs").unwrap();
+        fs.mkdir("/docs/old").unwrap();
+        fs.open("/docs/a").unwrap();
+        fs.open("/docs/b").unwrap();
+
+        assert_eq!(names(&mut fs, "/"), vec!["docs"]);
+        assert_eq!(names(&mut fs, "/docs"), vec!["a", "b", "old"]);
+        assert!(fs.readdir("/docs/old").unwrap().is_empty());
+
+        match fs.mkdir("/docs/a") {
+            Err(Error::AlreadyExists(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.readdir("/docs/a") {
+            Err(Error::NotADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_directory_spans_blocks() {
+        let mut fs = filesystem();
+        fs.mkdir("/many").unwrap();
+        for i in 0..ENTRIES_PER_BLOCK + 10 {
+            fs.open(&format!("/many/file{}", i)).unwrap();
+        }
+        assert_eq!(fs.readdir("/many").unwrap().len(), ENTRIES_PER_BLOCK + 10);
+        assert!(fs.lookup(&format!("/many/file{}", ENTRIES_PER_BLOCK + 9)).is_ok());
+    }
+
+    #[test]
+    fn test_unlink_and_rmdir() {
+        let mut fs = filesystem();
+        fs.mkdir("/d").unwrap();
+        let inumber = fs.open("/d/f").unwrap();
+        fs.write(inumber, &[1; 3 * Disk::BLOCK_SIZE], 3 * Disk::BLOCK_SIZE, 0).unwrap();
+
+        match fs.rmdir("/d") {
+            Err(Error::DirectoryNotEmpty(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.unlink("/d") {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+
+        fs.unlink("/d/f").unwrap();
+        assert!(fs.stat(inumber).is_err());
+        fs.rmdir("/d").unwrap();
+        assert!(fs.readdir("/").unwrap().is_empty());
+
+        // only the root directory's block is still in use
+        let used = fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count();
+        assert_eq!(used, 1);
+
+        match fs.rmdir("/") {
+            Err(Error::InvalidPath(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = filesystem();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/b").unwrap();
+        let file = fs.open("/a/f").unwrap();
+
+        // within a directory, then across directories
+        fs.rename("/a/f", "/a/g").unwrap();
+        fs.rename("/a/g", "/b/h").unwrap();
+        assert!(fs.readdir("/a").unwrap().is_empty());
+        assert_eq!(fs.lookup("/b/h").unwrap(), file);
+
+        // replacing an existing file frees it
+        let other = fs.open("/b/other").unwrap();
+        fs.rename("/b/h", "/b/other").unwrap();
+        assert_eq!(fs.lookup("/b/other").unwrap(), file);
+        assert!(fs.stat(other).is_err());
+
+        // moving a directory updates its parent
+        fs.rename("/b", "/a/b").unwrap();
+        assert_eq!(fs.lookup("/a/b/../b/other").unwrap(), file);
+        assert_eq!(names(&mut fs, "/"), vec!["a"]);
+
+        match fs.rename("/a", "/a/b/a") {
+            Err(Error::InvalidPath(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.rename("/a/b/other", "/a/b") {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.rename("/a/b", "/a") {
+            Err(Error::DirectoryNotEmpty(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_directories_are_not_files() {
+        let mut fs = filesystem();
+        let dir = fs.mkdir("/d").unwrap();
+        match fs.write(dir, b"x", 1, 0) {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.truncate(dir, 0) {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.remove(dir) {
+            Err(Error::IsADirectory(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_directories_survive_remount() {
+        let mut fs = filesystem();
+        fs.mkdir("/x").unwrap();
+        let inumber = fs.open("/x/y").unwrap();
+
+        let mut fs = FileSystem::new(fs.into_disk().unwrap());
+        fs.mount().unwrap();
+        assert_eq!(fs.lookup("/x/y").unwrap(), inumber);
+    }
+}
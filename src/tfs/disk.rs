@@ -1,90 +1,161 @@
-
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::path::Path;
-// use std::fs::OpenOptions;
+use std::io::{self, IoSlice, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::device::{BlockDevice, SharedDevice};
+use super::error::{Error, Result};
+use super::sfs::SFS_MAGIC_NUMBER;
+use super::types::{get_u32, MAGIC_NUMBER};
 
-#[allow(dead_code)]
-pub struct Disk<'a> {
-    FileDescriptor: Option<File>,
+/// A disk image in a regular file.
+///
+/// Clones share the open file, its size and its counters, so cloning is
+/// cheap and the counters add up every access to the image.
+#[derive(Clone)]
+pub struct Disk {
+    FileDescriptor: Option<Arc<Mutex<Image>>>,  // shared by every clone
+    Mounts: usize,  // Number of mounts
+    Path: PathBuf   // file path for the disk
+}
+
+struct Image {
+    File: File,
     Blocks: usize,  // Number of blocks in disk image
     Reads: usize,   // Number of reads performed
-    Writes: usize,  // Number of writes performed
-    Mounts: usize,  // Number of mounts
-    Path: &'a str  // file path for the disk
+    Writes: usize   // Number of writes performed
 }
 
-impl<'a> Disk<'a> {
+impl Disk {
     pub const BLOCK_SIZE: usize = 4096;  // number of bytes per block
-    fn sanity_check(&self, blocknum: usize) {
-        if blocknum < 0 {
-            panic!("Block Number is negative")
-        }
-
-        if blocknum >= self.Blocks {
-            panic!("Block Number is too big")
+    fn sanity_check(&self, blocknum: usize) -> Result<()> {
+        let blocks = self.size();
+        if blocknum >= blocks {
+            return Err(Error::BlockOutOfRange { block: blocknum, blocks });
         }
 
+        Ok(())
     }
 
-    pub fn new() -> Disk<'a> {
+    pub fn new() -> Disk {
         Disk {
             FileDescriptor: None,
-            Blocks: 0,
-            Reads: 0,
-            Writes: 0,
             Mounts: 0 ,
-            Path: ""
+            Path: PathBuf::new()
         }
     }
 
-    pub fn from_file(path: &'a str, nblocks: usize) -> Disk<'a> {
+    pub fn from_file<P: AsRef<Path>>(path: P, nblocks: usize) -> Result<Disk> {
         let mut disk = Self::new();
-        disk.open(path, nblocks);
-        disk
+        disk.open(path, nblocks)?;
+        Ok(disk)
     }
 
-    pub fn clone(&self) -> Disk<'a> {
-       Self::from_file(self.Path, self.size())
+    /// Opens an existing image at the size its superblock gives. Images
+    /// that are not formatted yet are opened at the size of the file.
+    pub fn from_image<P: AsRef<Path>>(path: P) -> Result<Disk> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path.as_ref())?;
+        let bytes = file.metadata()?.len();
+        let block_size = Self::BLOCK_SIZE as u64;
+        if bytes == 0 || bytes % block_size != 0 {
+            return Err(Error::ImageSize { blocks: None, bytes });
+        }
+
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+        let magic = get_u32(&header, 0);
+        let nblocks = if magic == MAGIC_NUMBER as u32 || magic == SFS_MAGIC_NUMBER {
+            get_u32(&header, 4) as usize
+        } else {
+            (bytes / block_size) as usize
+        };
+        Self::check_size(nblocks, bytes)?;
+
+        let mut disk = Self::new();
+        disk.attach(file, nblocks, path.as_ref());
+        Ok(disk)
+    }
+
+    /// Creates a fresh image of `nblocks` blocks, replacing whatever
+    /// `path` held.
+    pub fn create<P: AsRef<Path>>(path: P, nblocks: usize) -> Result<Disk> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())?;
+        file.set_len((nblocks as u64) * (Self::BLOCK_SIZE as u64))?;
+
+        let mut disk = Self::new();
+        disk.attach(file, nblocks, path.as_ref());
+        Ok(disk)
     }
 
     /// open disk image
     /// @param path     path to disk image
     /// @param nblocks  Number of blocks in disk image
-    pub fn open(&mut self, path: &'a str, nblocks: usize) {
-        let path_str = path;
-        let path = Path::new(path);
-        let display = path.display();
-
-        let mut file = OpenOptions::new()
+    ///
+    /// A missing or empty image is created with `nblocks` blocks; an
+    /// existing one must already have that many.
+    pub fn open<P: AsRef<Path>>(&mut self, path: P, nblocks: usize) -> Result<()> {
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path);
-        
-        self.FileDescriptor = match file {
-            Ok(f) => {
-                f.set_len((nblocks as u64) * (Self::BLOCK_SIZE as u64));
-                Some(f)
-            },
-            Err(e) => {
-                panic!("Could not open: {}, {}", display, e)
-            }
-        };
+            .truncate(false)
+            .open(path.as_ref())?;
+        let bytes = file.metadata()?.len();
+        if bytes == 0 {
+            file.set_len((nblocks as u64) * (Self::BLOCK_SIZE as u64))?;
+        } else {
+            Self::check_size(nblocks, bytes)?;
+        }
 
-        self.Blocks = nblocks;
-        self.Reads = 0;
-        self.Writes = 0;
-        self.Path = path_str;
+        self.attach(file, nblocks, path.as_ref());
+        Ok(())
+    }
+
+    // an image is never resized to fit: that would truncate a filesystem
+    fn check_size(nblocks: usize, bytes: u64) -> Result<()> {
+        if bytes != (nblocks as u64) * (Self::BLOCK_SIZE as u64) {
+            return Err(Error::ImageSize { blocks: Some(nblocks), bytes });
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, file: File, nblocks: usize, path: &Path) {
+        self.FileDescriptor = Some(Arc::new(Mutex::new(Image { File: file, Blocks: nblocks, Reads: 0, Writes: 0 })));
+        self.Path = path.to_path_buf();
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.Path
     }
 
     pub fn size(&self) -> usize {
-        self.Blocks
+        self.image().map_or(0, |image| image.Blocks)
+    }
+
+    /// Grows or truncates the image to `nblocks` blocks, for every clone.
+    pub fn set_size(&mut self, nblocks: usize) -> Result<()> {
+        let mut image = self.open_image()?;
+        image.File.set_len((nblocks as u64) * (Self::BLOCK_SIZE as u64))?;
+        image.Blocks = nblocks;
+        Ok(())
+    }
+
+    pub fn reads(&self) -> usize {
+        self.image().map_or(0, |image| image.Reads)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.image().map_or(0, |image| image.Writes)
     }
 
     pub fn mount(&mut self) {
-        self.Mounts = self.Mounts + 1; 
+        self.Mounts += 1;
     }
 
     pub fn mounted(&mut self) -> bool {
@@ -93,33 +164,134 @@ impl<'a> Disk<'a> {
 
     pub fn unmount(&mut self) {
         if self.Mounts > 0 {
-            self.Mounts = self.Mounts - 1;
+            self.Mounts -= 1;
+        }
+    }
+
+    pub fn read(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.sanity_check(blocknum)?;
+
+        let mut image = self.open_image()?;
+        image.File.seek(SeekFrom::Start(blocknum as u64 * Self::BLOCK_SIZE as u64))?;
+        image.File.read_exact(data)?;
+        image.Reads += 1;
+        Ok(())
+    }
+
+    pub fn write(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.sanity_check(blocknum)?;
+
+        let mut image = self.open_image()?;
+        image.File.seek(SeekFrom::Start(blocknum as u64 * Self::BLOCK_SIZE as u64))?;
+        image.File.write_all(data)?;
+        image.Writes += 1;
+        Ok(())
+    }
+
+    /// Reads a run of consecutive blocks with a single request.
+    pub fn read_run(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        let count = data.len().div_ceil(Self::BLOCK_SIZE);
+        if count == 0 {
+            return Ok(());
+        }
+        self.sanity_check(blocknum + count - 1)?;
+
+        let mut image = self.open_image()?;
+        image.File.seek(SeekFrom::Start(blocknum as u64 * Self::BLOCK_SIZE as u64))?;
+        image.File.read_exact(data)?;
+        image.Reads += count;
+        Ok(())
+    }
+
+    /// Writes `blocks` to consecutive blocks with a single vectored write.
+    pub fn write_run(&mut self, blocknum: usize, blocks: &[&[u8]]) -> Result<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        self.sanity_check(blocknum + blocks.len() - 1)?;
+
+        let mut image = self.open_image()?;
+        image.File.seek(SeekFrom::Start(blocknum as u64 * Self::BLOCK_SIZE as u64))?;
+        let mut slices: Vec<IoSlice> = blocks.iter().map(|block| IoSlice::new(block)).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let written = image.File.write_vectored(slices)?;
+            if written == 0 {
+                return Err(Error::Io(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            IoSlice::advance_slices(&mut slices, written);
         }
+        image.Writes += blocks.len();
+        Ok(())
     }
 
-    pub fn read<'b>(&mut self, blocknum: usize, data: &'b mut [u8]) {
-        self.sanity_check(blocknum);
+    fn image(&self) -> Option<MutexGuard<'_, Image>> {
+        // a clone that panicked mid-request leaves the file as usable as before
+        self.FileDescriptor.as_ref().map(|image| image.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn open_image(&self) -> Result<MutexGuard<'_, Image>> {
+        match self.image() {
+            Some(image) => Ok(image),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::NotConnected, "disk image is not open")))
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        Self::BLOCK_SIZE
+    }
 
-        match self.FileDescriptor.as_mut() {
-            Some(mut file) => {
-                file.seek(SeekFrom::Start( blocknum as u64 * Self::BLOCK_SIZE as u64));
-                file.read(data);
-                self.Reads = self.Reads + 1;
-            },
-            None => println!("No file yet")
-        }        
+    fn block_count(&self) -> usize {
+        self.size()
     }
 
-    pub fn write<'c>(&mut self, blocknum: usize, data: &'c mut [u8]) {
-        self.sanity_check(blocknum);
-        match self.FileDescriptor.as_mut() {
-            Some(mut file) => {
-                file.seek(SeekFrom::Start(blocknum as u64 * Self::BLOCK_SIZE as u64));
-                file.write(data);
-                self.Writes = self.Writes + 1;
-            },
-            None => println!("No file yet")
-        }        
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.write(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.open_image()?.File.sync_data()?;
+        Ok(())
+    }
+
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        self.set_size(blocks)
+    }
+
+    fn read_blocks(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read_run(blocknum, data)
+    }
+
+    fn write_blocks(&mut self, blocknum: usize, blocks: &[&[u8]]) -> Result<()> {
+        self.write_run(blocknum, blocks)
+    }
+}
+
+// requests from different threads take turns on the image file
+impl SharedDevice for Disk {
+    fn read_block_shared(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read(blocknum, data)
+    }
+
+    fn write_block_shared(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.write(blocknum, data)
+    }
+
+    fn flush_shared(&self) -> Result<()> {
+        self.open_image()?.File.sync_data()?;
+        Ok(())
+    }
+}
+
+impl Default for Disk {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -128,39 +300,146 @@ impl<'a> Disk<'a> {
 mod tests {
     use super::*;
 
-    // #[test]
+    fn image_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("tfs-disk-{}-{}.img", std::process::id(), name))
+            .to_str().unwrap().to_string()
+    }
+
+    #[test]
     fn disk_open() {
+        let path = image_path("open");
         let mut disk = Disk::new();
-        assert_eq!(disk.open("./data/image.5", 5), ());
+        assert!(disk.open(&path, 5).is_ok());
         assert_eq!(disk.size(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_open_keeps_size() {
+        let path = image_path("keeps-size");
+        Disk::from_file(&path, 5).unwrap().write(4, &[1; Disk::BLOCK_SIZE]).unwrap();
+
+        // a wrong block count is an error, not a resize
+        match Disk::from_file(&path, 3) {
+            Err(Error::ImageSize { blocks: Some(3), bytes }) => assert_eq!(bytes, 5 * Disk::BLOCK_SIZE as u64),
+            r => panic!("unexpected result {:?}", r.map(|disk| disk.size()))
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5 * Disk::BLOCK_SIZE as u64);
+        assert_eq!(Disk::from_file(&path, 5).unwrap().size(), 5);
+
+        // create starts over at the size it is given
+        assert_eq!(Disk::create(&path, 3).unwrap().size(), 3);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * Disk::BLOCK_SIZE as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_from_image() {
+        let path = image_path("from-image");
+        let disk = Disk::create(&path, 8).unwrap();
+
+        // not formatted yet: the file gives the size
+        assert_eq!(Disk::from_image(&path).unwrap().size(), 8);
+
+        let mut data = [0; Disk::BLOCK_SIZE];
+        data[..8].copy_from_slice(&[0x11, 0x34, 0xf0, 0xf0, 8, 0, 0, 0]);
+        disk.write(0, &data).unwrap();
+        assert_eq!(Disk::from_image(&path).unwrap().size(), 8);
+
+        // the superblock and the file disagree
+        data[4] = 6;
+        disk.write(0, &data).unwrap();
+        assert!(matches!(Disk::from_image(&path), Err(Error::ImageSize { blocks: Some(6), .. })));
+
+        std::fs::write(&path, [0; 100]).unwrap();
+        assert!(matches!(Disk::from_image(&path), Err(Error::ImageSize { blocks: None, bytes: 100 })));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(Disk::from_image(&path), Err(Error::Io(_))));
     }
 
-    // #[test]
+    #[test]
+    fn disk_outlives_its_path() {
+        let disk = {
+            let path = image_path("owned");
+            Disk::from_file(&path, 5).unwrap()
+        };
+        disk.write(4, &[1; Disk::BLOCK_SIZE]).unwrap();
+        assert!(disk.path().ends_with(format!("tfs-disk-{}-owned.img", std::process::id())));
+        std::fs::remove_file(disk.path()).unwrap();
+    }
+
+    #[test]
     fn disk_read_write() {
+        let path = image_path("read-write");
         let mut disk = Disk::new();
-        disk.open("./data/image.5", 5);
-        let mut data = [3; Disk::BLOCK_SIZE];
-        disk.write(1,&mut data);
+        disk.open(&path, 5).unwrap();
+        let data = [3; Disk::BLOCK_SIZE];
+        disk.write(1,&data).unwrap();
 
         let mut data2 = [0; Disk::BLOCK_SIZE];
-        disk.read(1, &mut data2);
+        disk.read(1, &mut data2).unwrap();
 
         assert_eq!(data, data2);
+        assert_eq!(disk.reads(), 1);
+        assert_eq!(disk.writes(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_runs() {
+        let path = image_path("runs");
+        let mut disk = Disk::new();
+        disk.open(&path, 5).unwrap();
+        let blocks = [[1; Disk::BLOCK_SIZE], [2; Disk::BLOCK_SIZE], [3; Disk::BLOCK_SIZE]];
+        disk.write_run(2, &[&blocks[0], &blocks[1], &blocks[2]]).unwrap();
+        assert!(disk.write_run(4, &[&blocks[0], &blocks[1]]).is_err());
+
+        let mut data = vec![0; 2 * Disk::BLOCK_SIZE];
+        disk.read_run(3, &mut data).unwrap();
+        assert_eq!(data[..Disk::BLOCK_SIZE], blocks[1]);
+        assert_eq!(data[Disk::BLOCK_SIZE..], blocks[2]);
+        assert_eq!(disk.reads(), 2);
+        assert_eq!(disk.writes(), 3);
+        std::fs::remove_file(&path).unwrap();
     }
 
-    // #[test]
+    #[test]
     fn disk_clone() {
+        let path = image_path("clone");
         let mut disk = Disk::new();
-        disk.open("./data/image.50", 50);
-        let mut data = [3; Disk::BLOCK_SIZE];
-        disk.write(1,&mut data);
+        disk.open(&path, 50).unwrap();
+        let data = [3; Disk::BLOCK_SIZE];
+        disk.write(1,&data).unwrap();
 
         let mut disk_clone = disk.clone();
+        assert_eq!(disk_clone.path(), std::path::Path::new(&path));
 
         let mut data2 = [4; Disk::BLOCK_SIZE];
-        disk_clone.read(1,&mut data2);
+        disk_clone.read(1,&mut data2).unwrap();
 
         // compare values
         assert_eq!(data, data2);
+
+        // the clone shares the file, counters included
+        disk_clone.write(2, &data).unwrap();
+        assert_eq!((disk.reads(), disk.writes()), (1, 2));
+        assert_eq!((disk_clone.reads(), disk_clone.writes()), (1, 2));
+
+        // and its size
+        disk_clone.set_size(60).unwrap();
+        assert_eq!(disk.size(), 60);
+        disk.write(59, &data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_out_of_range() {
+        let disk = Disk::new();
+        let mut data = [0; Disk::BLOCK_SIZE];
+        match disk.read(0, &mut data) {
+            Err(Error::BlockOutOfRange { block: 0, blocks: 0 }) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
     }
-}
\ No newline at end of file
+}
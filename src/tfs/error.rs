@@ -0,0 +1,148 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while talking to a disk image or a mounted
+/// filesystem.
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying image file could not be opened, read or written
+    Io(io::Error),
+    /// Block 0 does not carry the tfs magic number
+    BadMagic(u32),
+    /// A block number outside of the disk image was requested
+    BlockOutOfRange { block: usize, blocks: usize },
+    /// The block device does not use the block size the filesystem is laid out for
+    UnsupportedBlockSize(usize),
+    /// The inode number is out of range or refers to an unallocated inode
+    InvalidInode(usize),
+    /// There are no free inodes or data blocks left
+    NoSpace,
+    /// The file would grow past what an inode can address
+    FileTooLarge,
+    /// No directory entry exists for the path
+    NotFound(String),
+    /// The path already names a file or directory
+    AlreadyExists(String),
+    /// A directory was expected but the path names a regular file
+    NotADirectory(String),
+    /// The operation does not apply to directories
+    IsADirectory(String),
+    /// The directory still has entries in it
+    DirectoryNotEmpty(String),
+    /// The path or file name cannot be used (empty, too long, `.`/`..`, ...)
+    InvalidPath(String),
+    /// On-disk metadata contradicts itself (e.g. a pointer outside the data region)
+    CorruptMetadata(String),
+    /// The operation needs a mounted filesystem
+    NotMounted,
+    /// A device is already mounted on this filesystem
+    AlreadyMounted,
+    /// The file descriptor is not open, or not open for this kind of access
+    BadFileDescriptor(usize),
+    /// A seek would move the cursor before the start of the file, or a
+    /// write would start past the end of a file that cannot have holes
+    InvalidSeek(i64),
+    /// The image file is not the size its block count says (`None`: the
+    /// file is not a whole number of blocks at all)
+    ImageSize { blocks: Option<usize>, bytes: u64 },
+    /// An operation changed more metadata blocks than the journal commits
+    /// at once, and was undone
+    TransactionTooLarge { blocks: usize, capacity: usize },
+    /// A tar archive being imported is malformed
+    InvalidArchive(String),
+    /// The format options cannot describe a filesystem (bad label, UUID, inode count, ...)
+    InvalidFormat(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::BadMagic(magic) => write!(f, "bad magic number {:#x}", magic),
+            Error::BlockOutOfRange { block, blocks } => {
+                write!(f, "block {} is out of range (disk has {} blocks)", block, blocks)
+            }
+            Error::UnsupportedBlockSize(size) => write!(f, "unsupported block size {}", size),
+            Error::InvalidInode(inumber) => write!(f, "invalid inode {}", inumber),
+            Error::NoSpace => write!(f, "no space left on disk"),
+            Error::FileTooLarge => write!(f, "file too large"),
+            Error::NotFound(path) => write!(f, "no such file or directory: {}", path),
+            Error::AlreadyExists(path) => write!(f, "file exists: {}", path),
+            Error::NotADirectory(path) => write!(f, "not a directory: {}", path),
+            Error::IsADirectory(path) => write!(f, "is a directory: {}", path),
+            Error::DirectoryNotEmpty(path) => write!(f, "directory not empty: {}", path),
+            Error::InvalidPath(path) => write!(f, "invalid path: {}", path),
+            Error::CorruptMetadata(msg) => write!(f, "corrupt metadata: {}", msg),
+            Error::NotMounted => write!(f, "filesystem is not mounted"),
+            Error::AlreadyMounted => write!(f, "filesystem is already mounted"),
+            Error::BadFileDescriptor(fd) => write!(f, "bad file descriptor {}", fd),
+            Error::InvalidSeek(offset) => write!(f, "invalid seek to offset {}", offset),
+            Error::ImageSize { blocks: Some(blocks), bytes } => {
+                write!(f, "image of {} bytes does not hold {} blocks", bytes, blocks)
+            }
+            Error::ImageSize { blocks: None, bytes } => {
+                write!(f, "image of {} bytes is not a whole number of blocks", bytes)
+            }
+            Error::TransactionTooLarge { blocks, capacity } => {
+                write!(f, "transaction of {} blocks does not fit in a journal of {}", blocks, capacity)
+            }
+            Error::InvalidArchive(msg) => write!(f, "invalid tar archive: {}", msg),
+            Error::InvalidFormat(msg) => write!(f, "invalid format options: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Error {
+    /// The closest `std::io` error kind.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::NotFound(_) => io::ErrorKind::NotFound,
+            Error::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            Error::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            Error::IsADirectory(_) => io::ErrorKind::IsADirectory,
+            Error::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            Error::NoSpace => io::ErrorKind::StorageFull,
+            Error::FileTooLarge => io::ErrorKind::FileTooLarge,
+            Error::InvalidInode(_)
+            | Error::InvalidPath(_)
+            | Error::BadFileDescriptor(_)
+            | Error::InvalidSeek(_)
+            | Error::InvalidFormat(_)
+            | Error::BlockOutOfRange { .. } => io::ErrorKind::InvalidInput,
+            Error::BadMagic(_)
+            | Error::ImageSize { .. }
+            | Error::InvalidArchive(_)
+            | Error::UnsupportedBlockSize(_)
+            | Error::CorruptMetadata(_) => io::ErrorKind::InvalidData,
+            Error::NotMounted | Error::AlreadyMounted | Error::TransactionTooLarge { .. } => io::ErrorKind::Other
+        }
+    }
+}
+
+// lets tfs errors travel through the `std::io` traits (see `TfsFile`)
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.io_kind(), e)
+        }
+    }
+}
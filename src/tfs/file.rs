@@ -0,0 +1,202 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::device::BlockDevice;
+use super::error::{Error, Result};
+use super::FileSystem;
+
+/// A regular file on a mounted `FileSystem`, usable wherever `std::io`'s
+/// `Read`, `Write` and `Seek` are expected (wrap it in a `BufReader` or
+/// `BufWriter` for small reads and writes).
+///
+/// The file keeps its own position; every call goes straight to
+/// `FileSystem::read`/`write`, so several `TfsFile`s may take turns on the
+/// same inode.
+pub struct TfsFile<'a, D: BlockDevice> {
+    fs: &'a mut FileSystem<D>,
+    inumber: usize,
+    position: u64
+}
+
+impl<'a, D: BlockDevice> TfsFile<'a, D> {
+    /// Wraps the regular file `inumber`, positioned at its start.
+    pub fn new(fs: &'a mut FileSystem<D>, inumber: usize) -> Result<Self> {
+        if fs.is_dir(inumber)? {
+            return Err(Error::IsADirectory(format!("inode {}", inumber)));
+        }
+        Ok(TfsFile { fs, inumber, position: 0 })
+    }
+
+    /// Opens the existing regular file at `path`.
+    pub fn open(fs: &'a mut FileSystem<D>, path: &str) -> Result<Self> {
+        let inumber = fs.lookup(path)?;
+        if fs.is_dir(inumber)? {
+            return Err(Error::IsADirectory(path.to_string()));
+        }
+        Self::new(fs, inumber)
+    }
+
+    /// Opens the regular file at `path`, creating it if needed, and empties it.
+    pub fn create(fs: &'a mut FileSystem<D>, path: &str) -> Result<Self> {
+        let inumber = fs.open(path)?;
+        fs.truncate(inumber, 0)?;
+        Self::new(fs, inumber)
+    }
+
+    pub fn inumber(&self) -> usize {
+        self.inumber
+    }
+
+    /// Current size of the file in bytes.
+    pub fn len(&mut self) -> Result<u64> {
+        Ok(self.fs.stat(self.inumber)? as u64)
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Cuts the file down (or zero-extends it) to `size` bytes; the position
+    /// is left alone.
+    pub fn set_len(&mut self, size: u64) -> Result<()> {
+        self.fs.truncate(self.inumber, usize::try_from(size).map_err(|_| Error::FileTooLarge)?)
+    }
+}
+
+impl<'a, D: BlockDevice> Read for TfsFile<'a, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // nothing is stored past what a usize can address
+        let offset = match usize::try_from(self.position) {
+            Ok(offset) => offset,
+            Err(_) => return Ok(0)
+        };
+        let bytes = self.fs.read(self.inumber, buf, buf.len(), offset)?;
+        self.position += bytes as u64;
+        Ok(bytes)
+    }
+}
+
+impl<'a, D: BlockDevice> Write for TfsFile<'a, D> {
+    /// A full disk shows up as a short write, or `StorageFull` once nothing
+    /// fits at all; a position past the largest file is `FileTooLarge`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = usize::try_from(self.position).map_err(|_| Error::FileTooLarge)?;
+        let bytes = self.fs.write(self.inumber, buf, buf.len(), offset)?;
+        self.position += bytes as u64;
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.disk.flush()?;
+        Ok(())
+    }
+}
+
+impl<'a, D: BlockDevice> Seek for TfsFile<'a, D> {
+    /// Seeking past the end is allowed; a later write fills the gap with zeros.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (self.position, delta),
+            SeekFrom::End(delta) => (self.len()?, delta)
+        };
+
+        match base.checked_add_signed(delta) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => {
+                let position = i64::try_from(base).ok().and_then(|base| base.checked_add(delta));
+                Err(Error::InvalidSeek(position.unwrap_or(i64::MAX)).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memdisk::MemDisk;
+    use std::io::{BufRead, BufReader};
+
+    fn filesystem(blocks: usize) -> FileSystem<MemDisk> {
+        let mut disk = MemDisk::new(blocks);
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_io_copy_round_trip() {
+        let mut fs = filesystem(100);
+        let data: Vec<u8> = (0..50_000).map(|i| (i % 253) as u8).collect();
+
+        let mut file = TfsFile::create(&mut fs, "/data").unwrap();
+        assert_eq!(io::copy(&mut &data[..], &mut file).unwrap(), data.len() as u64);
+        file.flush().unwrap();
+        assert_eq!(file.len().unwrap(), data.len() as u64);
+
+        let mut file = TfsFile::open(&mut fs, "/data").unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_seek_and_lines() {
+        let mut fs = filesystem(20);
+        let mut file = TfsFile::create(&mut fs, "/lines").unwrap();
+        write!(file, "one\ntwo\nthree\n").unwrap();
+
+        assert_eq!(file.seek(SeekFrom::End(-6)).unwrap(), 8);
+        assert_eq!(file.seek(SeekFrom::Current(-4)).unwrap(), 4);
+        let err = file.seek(SeekFrom::Current(-5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file.stream_position().unwrap(), 4);
+
+        let lines: Vec<String> = BufReader::new(file).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(lines, vec!["two", "three"]);
+
+        // a write past the end leaves zeros behind
+        let mut file = TfsFile::open(&mut fs, "/lines").unwrap();
+        file.seek(SeekFrom::Start(16)).unwrap();
+        file.write_all(b"!").unwrap();
+        file.seek(SeekFrom::Start(13)).unwrap();
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, b"\n\0\0!");
+
+        // any position can be sought, but nothing can be written that far
+        assert_eq!(file.seek(SeekFrom::Start(u64::MAX)).unwrap(), u64::MAX);
+        let err = file.write_all(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(file.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(file.seek(SeekFrom::Current(1)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(file.len().unwrap(), 17);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut fs = filesystem(5);
+        assert!(matches!(TfsFile::open(&mut fs, "/missing"), Err(Error::NotFound(_))));
+        assert!(matches!(TfsFile::open(&mut fs, "/"), Err(Error::IsADirectory(_))));
+
+        // a 5 block disk has room for one data block after the root directory
+        let mut file = TfsFile::create(&mut fs, "/big").unwrap();
+        let err = file.write_all(&[1; 3 * 4096]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(file.len().unwrap(), 4096);
+
+        let err: io::Error = Error::NotFound("/x".to_string()).into();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "no such file or directory: /x");
+    }
+}
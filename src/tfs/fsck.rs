@@ -0,0 +1,631 @@
+use std::fmt;
+
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::Result;
+use super::types::*;
+use super::FileSystem;
+
+/// An inconsistency found by `FileSystem::check`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Block 0 does not carry the tfs magic number
+    BadMagic(u32),
+    /// The superblock's block counts do not fit the disk or each other
+    BadGeometry {
+        blocks: u32, inode_blocks: u32, bitmap_blocks: u32, journal_blocks: u32, disk_blocks: usize
+    },
+    /// The journal holds a committed transaction that never made it into
+    /// place; nothing else is checked until it has been replayed
+    UnreplayedJournal { blocks: usize },
+    /// An allocated inode has a `Valid` word that is not a known kind of file
+    BadInodeType { inumber: usize, valid: u32 },
+    /// The root inode is not a directory (`valid` is its `Valid` word)
+    RootNotDirectory { valid: u32 },
+    /// A block pointer points outside the data region
+    PointerOutOfRange { inumber: usize, block: u32 },
+    /// A block is already used by another inode (or elsewhere in the same one)
+    DuplicateBlock { inumber: usize, block: u32, owner: usize },
+    /// The file is larger than the blocks it has
+    SizeMismatch { inumber: usize, size: u64, covered: u64 },
+    /// The file has blocks past its end
+    BlocksPastEnd { inumber: usize, blocks: usize },
+    /// A directory entry names an inode that is out of range or not in use
+    DanglingEntry { directory: usize, name: String, inumber: u32 },
+    /// The superblock's `Inodes` does not match the inodes in use
+    WrongInodeCount { recorded: u32, actual: u32 },
+    /// The bitmaps of a cleanly unmounted image disagree with the inodes
+    /// (counts of wrong inode and data block bits)
+    BitmapMismatch { inodes: usize, blocks: usize },
+}
+
+impl Problem {
+    /// Whether `check` knows how to repair the problem. The superblock
+    /// problems stop the check before anything else is looked at.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Problem::BadMagic(_) | Problem::BadGeometry { .. })
+    }
+
+    /// What a repair does about the problem.
+    pub fn fix(&self) -> String {
+        match self {
+            Problem::BadMagic(_) | Problem::BadGeometry { .. } => "none".to_string(),
+            Problem::UnreplayedJournal { .. } => "replayed the journal".to_string(),
+            Problem::BadInodeType { .. } => "freed the inode".to_string(),
+            Problem::RootNotDirectory { .. } => "replaced it with an empty directory".to_string(),
+            Problem::PointerOutOfRange { .. } | Problem::DuplicateBlock { .. } => {
+                "cleared the pointer".to_string()
+            }
+            Problem::SizeMismatch { covered, .. } => format!("set the size to {} bytes", covered),
+            Problem::BlocksPastEnd { .. } => "released the blocks".to_string(),
+            Problem::DanglingEntry { .. } => "removed the entry".to_string(),
+            Problem::WrongInodeCount { actual, .. } => format!("set the inode count to {}", actual),
+            Problem::BitmapMismatch { .. } => "rebuilt the bitmaps".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::BadMagic(magic) => write!(f, "bad magic number {:#x}, not a tfs image", magic),
+            Problem::BadGeometry { blocks, inode_blocks, bitmap_blocks, journal_blocks, disk_blocks } => write!(
+                f, "superblock describes {} blocks with {} inode, {} bitmap and {} journal blocks on a {} block disk",
+                blocks, inode_blocks, bitmap_blocks, journal_blocks, disk_blocks
+            ),
+            Problem::UnreplayedJournal { blocks } => {
+                write!(f, "journal holds a committed transaction of {} blocks", blocks)
+            }
+            Problem::BadInodeType { inumber, valid } => {
+                write!(f, "inode {}: unknown inode type {}", inumber, valid)
+            }
+            Problem::RootNotDirectory { valid } => {
+                write!(f, "root inode is not a directory (type {})", valid)
+            }
+            Problem::PointerOutOfRange { inumber, block } => {
+                write!(f, "inode {}: block pointer {} is outside the data region", inumber, block)
+            }
+            Problem::DuplicateBlock { inumber, block, owner } => {
+                write!(f, "inode {}: block {} is already used by inode {}", inumber, block, owner)
+            }
+            Problem::SizeMismatch { inumber, size, covered } => write!(
+                f, "inode {}: size is {} bytes but only {} bytes have blocks", inumber, size, covered
+            ),
+            Problem::BlocksPastEnd { inumber, blocks } => {
+                write!(f, "inode {}: {} blocks past the end of the file", inumber, blocks)
+            }
+            Problem::DanglingEntry { directory, name, inumber } => {
+                write!(f, "inode {}: entry {} names unused inode {}", directory, name, inumber)
+            }
+            Problem::WrongInodeCount { recorded, actual } => {
+                write!(f, "superblock records {} inodes but {} are in use", recorded, actual)
+            }
+            Problem::BitmapMismatch { inodes, blocks } => write!(
+                f, "bitmaps are wrong about {} inodes and {} data blocks", inodes, blocks
+            ),
+        }
+    }
+}
+
+/// What `FileSystem::check` found on an image.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub repaired: bool  // whether the fixes were written back to the image
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for problem in self.problems.iter() {
+            if self.repaired && problem.is_repairable() {
+                writeln!(f, "{} (fixed: {})", problem, problem.fix())?;
+            } else {
+                writeln!(f, "{}", problem)?;
+            }
+        }
+
+        let count = self.problems.len();
+        let plural = if count == 1 { "" } else { "s" };
+        if self.is_clean() {
+            write!(f, "filesystem is clean")
+        } else if self.repaired {
+            write!(f, "{} problem{} repaired", count, plural)
+        } else {
+            write!(f, "{} problem{} found", count, plural)
+        }
+    }
+}
+
+// state of the walk over every inode's blocks
+struct Scan<'a, D: BlockDevice> {
+    disk: &'a mut D,
+    repair: bool,
+    dataStart: usize,               // first block of the data region
+    blocks: usize,                  // end of the data region
+    owners: Vec<Option<usize>>,     // inode using each data block
+    problems: Vec<Problem>
+}
+
+impl<'a, D: BlockDevice> Scan<'a, D> {
+    /// Checks pointer `ptr` of inode `inumber`, which is `level` pointer
+    /// blocks above the data and leads to the `index`-th block of the file
+    /// onwards. The file blocks found are collected in `present`, as pairs
+    /// of index and block number. Returns false if the pointer is bad and
+    /// has to be dropped.
+    fn visit(
+        &mut self, inumber: usize, ptr: u32, level: u32,
+        index: usize, present: &mut Vec<(usize, usize)>
+    ) -> Result<bool> {
+        if ptr == 0 {
+            return Ok(true);
+        }
+
+        let block_num = ptr as usize;
+        if block_num < self.dataStart || block_num >= self.blocks {
+            self.problems.push(Problem::PointerOutOfRange { inumber, block: ptr });
+            return Ok(false);
+        }
+        if let Some(owner) = self.owners[block_num - self.dataStart] {
+            self.problems.push(Problem::DuplicateBlock { inumber, block: ptr, owner });
+            return Ok(false);
+        }
+        self.owners[block_num - self.dataStart] = Some(inumber);
+
+        if level == 0 {
+            present.push((index, block_num));
+            return Ok(true);
+        }
+
+        let mut block = Block::new();
+        let mut data = block.data();
+        self.disk.read_block(block_num, &mut data)?;
+        block.set_data(data);
+
+        let span = POINTERS_PER_BLOCK.pow(level - 1);
+        let mut ptrs = block.pointers();
+        let mut changed = false;
+        for (i, child) in ptrs.iter_mut().enumerate() {
+            if !self.visit(inumber, *child, level - 1, index + i * span, present)? {
+                *child = 0;
+                changed = true;
+            }
+        }
+
+        if changed && self.repair {
+            block.set_pointers(ptrs);
+            self.disk.write_block(block_num, &block.data())?;
+        }
+        Ok(true)
+    }
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Checks the (unmounted) filesystem on `disk` for inconsistencies and,
+    /// when `repair` is set, fixes everything it can. Errors are only
+    /// returned when the disk itself cannot be read or written: a superblock
+    /// that makes no sense is reported as a problem, not an error.
+    pub fn check(disk: &mut D, repair: bool) -> Result<Report> {
+        let mut report = Report::default();
+
+        // STEP 1: nothing else can be trusted without a sane superblock
+        let mut superBlock = Self::read_super_block(disk)?;
+        if superBlock.MagicNumber != MAGIC_NUMBER as u32 {
+            report.problems.push(Problem::BadMagic(superBlock.MagicNumber));
+            return Ok(report);
+        }
+
+        // the regions have to fit on the disk in order, the bitmaps (when
+        // kept) need a bit per inode and data block, and a journal needs
+        // room for a header and at least one block
+        let blocks = superBlock.data_end();
+        let dataStart = superBlock.data_start();
+        let bits = superBlock.inode_count() + blocks.saturating_sub(dataStart);
+        if superBlock.Blocks as usize != disk.block_count() || superBlock.InodeBlocks == 0 || dataStart >= blocks
+            || (superBlock.BitmapBlocks > 0 && (superBlock.BitmapBlocks as usize) * BITS_PER_BLOCK < bits)
+            || superBlock.JournalBlocks == 1
+        {
+            report.problems.push(Problem::BadGeometry {
+                blocks: superBlock.Blocks,
+                inode_blocks: superBlock.InodeBlocks,
+                bitmap_blocks: superBlock.BitmapBlocks,
+                journal_blocks: superBlock.JournalBlocks,
+                disk_blocks: disk.block_count()
+            });
+            return Ok(report);
+        }
+
+        // a transaction interrupted by a crash is finished before anything
+        // else, as mount would do
+        let journaled = Self::journaled_blocks(disk, &superBlock)?.len();
+        if journaled > 0 {
+            report.problems.push(Problem::UnreplayedJournal { blocks: journaled });
+            if !repair {
+                return Ok(report);
+            }
+            Self::replay_journal(disk, &superBlock)?;
+            superBlock = Self::read_super_block(disk)?;
+        }
+
+        // STEP 2: walk the blocks of every inode
+        let mut metaData = Self::read_meta_data(disk)?;
+        let mut scan = Scan {
+            disk: &mut *disk,
+            repair,
+            dataStart,
+            blocks,
+            owners: vec![None; blocks - dataStart],
+            problems: Vec::new()
+        };
+        let mut inodes = 0;
+        let mut inode_bit_map = vec![false; superBlock.inode_count()];
+        let mut truncations = Vec::new();
+        let mut directories = Vec::new();  // (inumber, entry count, blocks)
+        let mut new_root = false;
+
+        for (i, table) in metaData.inodeTable.iter_mut().enumerate() {
+            let mut changed = false;
+            for (row, inode) in table.iter_mut().enumerate() {
+                let inumber = i * INODES_PER_BLOCK + row;
+                // everything else hangs off the root, so a root that is not
+                // a directory is swapped for an empty one; its old blocks
+                // become free
+                if inumber == ROOT_INODE && !inode.is_directory() {
+                    scan.problems.push(Problem::RootNotDirectory { valid: inode.Valid });
+                    *inode = Inode::blank();
+                    inode.Valid = DIRECTORY_INODE;
+                    changed = true;
+                    new_root = true;
+                }
+                if inode.Valid == FREE_INODE {
+                    continue;
+                }
+                if inode.Valid != FILE_INODE && inode.Valid != DIRECTORY_INODE {
+                    scan.problems.push(Problem::BadInodeType { inumber, valid: inode.Valid });
+                    *inode = Inode::blank();
+                    changed = true;
+                    continue;
+                }
+                inodes += 1;
+                inode_bit_map[inumber] = true;
+
+                let mut present = Vec::new();
+                for (index, ptr) in inode.Direct.iter_mut().enumerate() {
+                    if !scan.visit(inumber, *ptr, 0, index, &mut present)? {
+                        *ptr = 0;
+                        changed = true;
+                    }
+                }
+                let mut base = POINTERS_PER_INODE;
+                let mut span = 1;
+                for level in 1..=3 {
+                    span *= POINTERS_PER_BLOCK;
+                    if !scan.visit(inumber, inode.tree_root(level), level, base, &mut present)? {
+                        *inode.tree_root_mut(level) = 0;
+                        changed = true;
+                    }
+                    base += span;
+                }
+
+                // files have no holes: every block up to the end of file is
+                // present, and none after it
+                let covered = present.iter()
+                    .enumerate()
+                    .take_while(|(i, (index, _))| i == index)
+                    .count();
+                let covered = (covered * Disk::BLOCK_SIZE) as u64;
+                if inode.Size > covered {
+                    scan.problems.push(Problem::SizeMismatch { inumber, size: inode.Size, covered });
+                    inode.Size = covered;
+                    changed = true;
+                }
+
+                let needed = (inode.Size as usize).div_ceil(Disk::BLOCK_SIZE);
+                let past = present.iter().filter(|(index, _)| *index >= needed).count();
+                if past > 0 {
+                    scan.problems.push(Problem::BlocksPastEnd { inumber, blocks: past });
+                    truncations.push(inumber);
+                }
+
+                if inode.is_directory() {
+                    let blocks: Vec<usize> = present.iter().take(needed).map(|(_, block)| *block).collect();
+                    directories.push((inumber, inode.Size as usize / DIR_ENTRY_SIZE, blocks));
+                }
+            }
+
+            if changed && repair {
+                let mut block = Block::new();
+                block.set_inodes(*table);
+                scan.disk.write_block(i + 1, &block.data())?;
+            }
+        }
+
+        // every entry has to name an inode in use, which is only known once
+        // all of them have been seen
+        for (directory, count, blocks) in directories {
+            for (i, block_num) in blocks.into_iter().enumerate() {
+                let mut block = Block::new();
+                let mut data = block.data();
+                scan.disk.read_block(block_num, &mut data)?;
+                block.set_data(data);
+
+                let mut entries = block.entries();
+                let mut changed = false;
+                for entry in entries.iter_mut().take(count.saturating_sub(i * ENTRIES_PER_BLOCK)) {
+                    let inumber = entry.Inumber as usize;
+                    if entry.Valid != 0 && !inode_bit_map.get(inumber).copied().unwrap_or(false) {
+                        scan.problems.push(Problem::DanglingEntry {
+                            directory, name: entry.name(), inumber: entry.Inumber
+                        });
+                        *entry = DirEntry::blank();
+                        changed = true;
+                    }
+                }
+
+                if changed && repair {
+                    block.set_entries(entries);
+                    scan.disk.write_block(block_num, &block.data())?;
+                }
+            }
+        }
+        let data_bit_map: Vec<bool> = scan.owners.iter().map(|owner| owner.is_some()).collect();
+        report.problems.extend(scan.problems);
+
+        // STEP 3: the inode count, and the bitmaps if they are meant to be
+        // up to date
+        if superBlock.Inodes != inodes {
+            report.problems.push(Problem::WrongInodeCount { recorded: superBlock.Inodes, actual: inodes });
+        }
+        if superBlock.BitmapBlocks > 0 && superBlock.State == STATE_CLEAN {
+            let (inode_bits, data_bits) = Self::read_bitmaps(disk, &superBlock)?;
+            let wrong = |a: &[bool], b: &[bool]| a.iter().zip(b.iter()).filter(|(a, b)| a != b).count();
+            let inodes = wrong(&inode_bits, &inode_bit_map);
+            let blocks = wrong(&data_bits, &data_bit_map);
+            if inodes > 0 || blocks > 0 {
+                report.problems.push(Problem::BitmapMismatch { inodes, blocks });
+            }
+        }
+
+        if !repair || report.is_clean() {
+            return Ok(report);
+        }
+
+        // STEP 4: with every pointer sound, the filesystem can be mounted to
+        // release blocks past the end of files. Marking it dirty makes the
+        // mount rebuild the bitmaps from the repaired inodes.
+        superBlock.Inodes = inodes;
+        superBlock.State = STATE_DIRTY;
+        let mut block = Block::new();
+        block.set_superblock(superBlock);
+        disk.write_block(0, &block.data())?;
+
+        let mut fs = FileSystem::new(&mut *disk);
+        fs.mount()?;
+        if new_root {
+            let mut entries = [DirEntry::blank(); ENTRIES_PER_BLOCK];
+            entries[0] = DirEntry::new(".", ROOT_INODE);
+            entries[1] = DirEntry::new("..", ROOT_INODE);
+            let mut block = Block::new();
+            block.set_entries(entries);
+            fs.transaction(|fs| {
+                let mut root = fs.load_inode(ROOT_INODE)?;
+                fs.write_inode(ROOT_INODE, &mut root, &block.data()[..2 * DIR_ENTRY_SIZE], 0)
+            })?;
+        }
+        for inumber in truncations {
+            let mut inode = fs.load_inode(inumber)?;
+            let size = inode.Size as usize;
+            fs.shrink_inode(inumber, &mut inode, size)?;
+        }
+        fs.unmount()?;
+
+        disk.flush()?;
+        report.repaired = true;
+        Ok(report)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::Error;
+    use super::super::memdisk::MemDisk;
+    use super::*;
+
+    // a filesystem with a two block file in inode 1 and a one block file in inode 2
+    fn populated() -> FileSystem<MemDisk> {
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        let first = fs.create().unwrap();
+        fs.write(first, &[1; 2 * Disk::BLOCK_SIZE], 2 * Disk::BLOCK_SIZE, 0).unwrap();
+        let second = fs.create().unwrap();
+        fs.write(second, &[2; 100], 100, 0).unwrap();
+        fs
+    }
+
+    fn check_and_repair(disk: &mut MemDisk) -> Report {
+        let report = FileSystem::check(disk, true).unwrap();
+        assert!(report.repaired);
+        assert!(FileSystem::check(disk, false).unwrap().is_clean());
+        report
+    }
+
+    #[test]
+    fn test_clean_image() {
+        let mut disk = populated().into_disk().unwrap();
+        let report = FileSystem::check(&mut disk, true).unwrap();
+        assert!(report.is_clean());
+        assert!(!report.repaired);
+        assert_eq!(report.to_string(), "filesystem is clean");
+    }
+
+    #[test]
+    fn test_bad_superblock() {
+        let mut disk = MemDisk::new(20);
+        let report = FileSystem::check(&mut disk, true).unwrap();
+        assert_eq!(report.problems, vec![Problem::BadMagic(0)]);
+        assert!(!report.repaired);
+
+        // an image copied onto a larger disk
+        let mut fs = populated();
+        let mut superblock = FileSystem::<MemDisk>::read_super_block(&mut fs.disk).unwrap();
+        superblock.Blocks = 60;
+        fs.metaData.as_mut().unwrap().superBlock = superblock;
+        fs.save_super_block().unwrap();
+        let report = FileSystem::check(&mut fs.into_disk().unwrap(), true).unwrap();
+        match report.problems.as_slice() {
+            [Problem::BadGeometry { blocks: 60, disk_blocks: 50, .. }] => {},
+            problems => panic!("unexpected problems {:?}", problems)
+        }
+
+        // bitmaps too small for the inodes and data blocks, and a journal
+        // with no room past its header
+        for (inode_blocks, bitmap_blocks, journal_blocks) in [(600, 1, 0), (1, 1, 1)] {
+            let mut fs = FileSystem::from_disk(MemDisk::new(700)).unwrap();
+            let mut superblock = FileSystem::<MemDisk>::read_super_block(&mut fs.disk).unwrap();
+            superblock.InodeBlocks = inode_blocks;
+            superblock.BitmapBlocks = bitmap_blocks;
+            superblock.JournalBlocks = journal_blocks;
+            fs.metaData.as_mut().unwrap().superBlock = superblock;
+            fs.save_super_block().unwrap();
+            let report = FileSystem::check(&mut fs.into_disk().unwrap(), true).unwrap();
+            assert_eq!(report.problems, vec![Problem::BadGeometry {
+                blocks: 700, inode_blocks, bitmap_blocks, journal_blocks, disk_blocks: 700
+            }]);
+            assert!(!report.repaired);
+        }
+    }
+
+    #[test]
+    fn test_root_not_directory() {
+        let mut fs = populated();
+        let mut root = fs.load_inode(ROOT_INODE).unwrap();
+        root.Valid = FILE_INODE;
+        fs.save_inode(ROOT_INODE, &root).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = check_and_repair(&mut disk);
+        assert_eq!(report.problems, vec![
+            Problem::RootNotDirectory { valid: FILE_INODE },
+            Problem::BitmapMismatch { inodes: 0, blocks: 1 }
+        ]);
+
+        let mut fs = FileSystem::from_disk(disk).unwrap();
+        assert!(fs.is_dir(ROOT_INODE).unwrap());
+        assert!(fs.readdir("/").unwrap().is_empty());
+        assert_eq!(fs.lookup("/..").unwrap(), ROOT_INODE);
+        fs.mkdir("/docs").unwrap();
+    }
+
+    #[test]
+    fn test_dangling_entry() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        fs.open("/kept").unwrap();
+        let lost = fs.open("/lost").unwrap();
+        fs.save_inode(lost, &Inode::blank()).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = check_and_repair(&mut disk);
+        assert_eq!(report.problems, vec![
+            Problem::DanglingEntry { directory: ROOT_INODE, name: "lost".to_string(), inumber: lost as u32 },
+            Problem::WrongInodeCount { recorded: 3, actual: 2 },
+            Problem::BitmapMismatch { inodes: 1, blocks: 0 }
+        ]);
+
+        let mut fs = FileSystem::from_disk(disk).unwrap();
+        assert!(matches!(fs.lookup("/lost"), Err(Error::NotFound(_))));
+        assert!(fs.lookup("/kept").is_ok());
+    }
+
+    #[test]
+    fn test_pointer_out_of_range() {
+        let mut fs = populated();
+        let mut inode = fs.load_inode(1).unwrap();
+        inode.Direct[1] = 5000;
+        fs.save_inode(1, &inode).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = check_and_repair(&mut disk);
+        assert_eq!(report.problems, vec![
+            Problem::PointerOutOfRange { inumber: 1, block: 5000 },
+            Problem::SizeMismatch { inumber: 1, size: 2 * Disk::BLOCK_SIZE as u64, covered: Disk::BLOCK_SIZE as u64 },
+            Problem::BitmapMismatch { inodes: 0, blocks: 1 }
+        ]);
+
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.stat(1).unwrap(), Disk::BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_duplicate_block() {
+        let mut fs = populated();
+        let first = fs.load_inode(1).unwrap();
+        let mut second = fs.load_inode(2).unwrap();
+        second.Direct[0] = first.Direct[1];
+        fs.save_inode(2, &second).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = check_and_repair(&mut disk);
+        assert_eq!(report.problems, vec![
+            Problem::DuplicateBlock { inumber: 2, block: first.Direct[1], owner: 1 },
+            Problem::SizeMismatch { inumber: 2, size: 100, covered: 0 },
+            Problem::BitmapMismatch { inodes: 0, blocks: 1 }
+        ]);
+
+        // the first owner keeps its data
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        let mut data = vec![0; 2 * Disk::BLOCK_SIZE];
+        assert_eq!(fs.read(1, &mut data, 2 * Disk::BLOCK_SIZE, 0).unwrap(), 2 * Disk::BLOCK_SIZE);
+        assert!(data.iter().all(|b| *b == 1));
+        assert_eq!(fs.stat(2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_blocks_past_end() {
+        let mut fs = populated();
+        let mut inode = fs.load_inode(1).unwrap();
+        inode.Size = 10;
+        fs.save_inode(1, &inode).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = check_and_repair(&mut disk);
+        assert_eq!(report.problems, vec![Problem::BlocksPastEnd { inumber: 1, blocks: 1 }]);
+
+        let fs = FileSystem::from_disk(disk).unwrap();
+        let used = fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count();
+        assert_eq!(used, 3);
+    }
+
+    #[test]
+    fn test_bad_inode_type_and_count() {
+        let mut fs = populated();
+        let mut inode = fs.load_inode(2).unwrap();
+        inode.Valid = 7;
+        fs.save_inode(2, &inode).unwrap();
+
+        let mut disk = fs.into_disk().unwrap();
+        let report = FileSystem::check(&mut disk, false).unwrap();
+        assert_eq!(report.problems, vec![
+            Problem::BadInodeType { inumber: 2, valid: 7 },
+            Problem::WrongInodeCount { recorded: 3, actual: 2 },
+            Problem::BitmapMismatch { inodes: 1, blocks: 1 }
+        ]);
+        assert!(report.to_string().ends_with("3 problems found"));
+
+        // checking alone changes nothing
+        assert_eq!(FileSystem::check(&mut disk, false).unwrap().problems.len(), 3);
+
+        let report = check_and_repair(&mut disk);
+        assert!(report.to_string().contains("(fixed: set the inode count to 2)"));
+
+        let mut fs = FileSystem::from_disk(disk).unwrap();
+        assert!(fs.stat(2).is_err());
+        assert_eq!(fs.create().unwrap(), 2);
+    }
+}
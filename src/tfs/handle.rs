@@ -0,0 +1,304 @@
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+
+use super::device::BlockDevice;
+use super::error::{Error, Result};
+use super::FileSystem;
+
+/// How a file is opened, after C's `fopen` modes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    /// `r`: read only; the file must exist
+    Read,
+    /// `w`: write only; the file is created if needed and truncated
+    Write,
+    /// `a`: write only; the file is created if needed and every write
+    /// goes to its end
+    Append,
+    /// `r+`: read and write; the file must exist
+    ReadWrite
+}
+
+impl OpenMode {
+    pub fn can_read(self) -> bool {
+        self == OpenMode::Read || self == OpenMode::ReadWrite
+    }
+
+    pub fn can_write(self) -> bool {
+        self != OpenMode::Read
+    }
+}
+
+/// An entry of the open-file table.
+#[derive(Copy, Clone, Debug)]
+pub struct OpenFile {
+    pub inumber: usize,
+    pub cursor: usize,  // byte offset the next read or write starts at
+    pub mode: OpenMode
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Opens the regular file at `path` and returns a file descriptor for
+    /// it, with its cursor at the start of the file.
+    pub fn fopen(&mut self, path: &str, mode: OpenMode) -> Result<usize> {
+        let inumber = match mode {
+            OpenMode::Read | OpenMode::ReadWrite => self.lookup(path)?,
+            OpenMode::Write | OpenMode::Append => self.open(path)?
+        };
+        self.fopen_inode(inumber, mode)
+    }
+
+    /// Like `fopen`, for a file known by inode number (e.g. from `create`).
+    pub fn fopen_inode(&mut self, inumber: usize, mode: OpenMode) -> Result<usize> {
+        if self.is_dir(inumber)? {
+            return Err(Error::IsADirectory(format!("inode {}", inumber)));
+        }
+        if mode == OpenMode::Write {
+            self.truncate(inumber, 0)?;
+        }
+
+        // hand out the lowest free descriptor, as POSIX does
+        let file = OpenFile { inumber, cursor: 0, mode };
+        match self.openFiles.iter().position(|slot| slot.is_none()) {
+            Some(fd) => {
+                self.openFiles[fd] = Some(file);
+                Ok(fd)
+            }
+            None => {
+                self.openFiles.push(Some(file));
+                Ok(self.openFiles.len() - 1)
+            }
+        }
+    }
+
+    /// Releases a file descriptor; the number may be handed out again.
+    pub fn fclose(&mut self, fd: usize) -> Result<()> {
+        self.open_file(fd)?;
+        self.openFiles[fd] = None;
+        Ok(())
+    }
+
+    /// The table entry behind an open file descriptor.
+    pub fn open_file(&self, fd: usize) -> Result<OpenFile> {
+        match self.openFiles.get(fd) {
+            Some(Some(file)) => Ok(*file),
+            _ => Err(Error::BadFileDescriptor(fd))
+        }
+    }
+
+    /// Reads into `data` from the cursor and advances it past what was
+    /// read; returns 0 at the end of the file.
+    pub fn fread(&mut self, fd: usize, data: &mut [u8]) -> Result<usize> {
+        let file = self.open_file(fd)?;
+        if !file.mode.can_read() {
+            return Err(Error::BadFileDescriptor(fd));
+        }
+
+        let bytes = self.read(file.inumber, data, data.len(), file.cursor)?;
+        self.set_cursor(fd, file.cursor + bytes);
+        Ok(bytes)
+    }
+
+    /// Writes `data` at the cursor (at the end of the file in append mode)
+    /// and advances the cursor past it. A short count means the disk is full.
+    pub fn fwrite(&mut self, fd: usize, data: &[u8]) -> Result<usize> {
+        let file = self.open_file(fd)?;
+        if !file.mode.can_write() {
+            return Err(Error::BadFileDescriptor(fd));
+        }
+
+        let offset = match file.mode {
+            OpenMode::Append => self.stat(file.inumber)?,
+            _ => file.cursor
+        };
+        let bytes = self.write(file.inumber, data, data.len(), offset)?;
+        self.set_cursor(fd, offset + bytes);
+        Ok(bytes)
+    }
+
+    /// Moves the cursor and returns its new offset. Seeking past the end is
+    /// allowed; a later write fills the gap with zeros, or fails with
+    /// `FileTooLarge` past the largest file an inode can hold.
+    pub fn fseek(&mut self, fd: usize, pos: SeekFrom) -> Result<usize> {
+        let file = self.open_file(fd)?;
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                let offset = usize::try_from(offset).map_err(|_| Error::InvalidSeek(i64::MAX))?;
+                self.set_cursor(fd, offset);
+                return Ok(offset);
+            }
+            SeekFrom::Current(delta) => (file.cursor, delta),
+            SeekFrom::End(delta) => (self.stat(file.inumber)?, delta)
+        };
+
+        let cursor = i64::try_from(base).ok()
+            .and_then(|base| base.checked_add(delta))
+            .ok_or(Error::InvalidSeek(i64::MAX))?;
+        if cursor < 0 {
+            return Err(Error::InvalidSeek(cursor));
+        }
+        self.set_cursor(fd, cursor as usize);
+        Ok(cursor as usize)
+    }
+
+    /// The cursor of an open file descriptor.
+    pub fn ftell(&self, fd: usize) -> Result<usize> {
+        Ok(self.open_file(fd)?.cursor)
+    }
+
+    fn set_cursor(&mut self, fd: usize, cursor: usize) {
+        if let Some(Some(file)) = self.openFiles.get_mut(fd) {
+            file.cursor = cursor;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memdisk::MemDisk;
+
+    fn filesystem() -> FileSystem<MemDisk> {
+        let mut disk = MemDisk::new(100);
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_independent_cursors() {
+        let mut fs = filesystem();
+        let writer = fs.fopen("/notes", OpenMode::Write).unwrap();
+        assert_eq!(fs.fwrite(writer, b"hello ").unwrap(), 6);
+        assert_eq!(fs.fwrite(writer, b"world").unwrap(), 5);
+        assert_eq!(fs.ftell(writer).unwrap(), 11);
+
+        let first = fs.fopen("/notes", OpenMode::Read).unwrap();
+        let second = fs.fopen("/notes", OpenMode::Read).unwrap();
+        let mut data = [0; 6];
+        assert_eq!(fs.fread(first, &mut data).unwrap(), 6);
+        assert_eq!(&data, b"hello ");
+        assert_eq!(fs.fread(first, &mut data).unwrap(), 5);
+        assert_eq!(&data[..5], b"world");
+        assert_eq!(fs.fread(first, &mut data).unwrap(), 0);
+
+        assert_eq!(fs.fread(second, &mut data[..5]).unwrap(), 5);
+        assert_eq!(&data[..5], b"hello");
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut fs = filesystem();
+        assert!(matches!(fs.fopen("/missing", OpenMode::Read), Err(Error::NotFound(_))));
+        assert!(matches!(fs.fopen("/", OpenMode::Read), Err(Error::IsADirectory(_))));
+
+        let fd = fs.fopen("/log", OpenMode::Append).unwrap();
+        fs.fwrite(fd, b"one").unwrap();
+        assert!(matches!(fs.fread(fd, &mut [0; 3]), Err(Error::BadFileDescriptor(_))));
+
+        // appends go to the end wherever the cursor is
+        fs.fseek(fd, SeekFrom::Start(0)).unwrap();
+        fs.fwrite(fd, b"two").unwrap();
+        assert_eq!(fs.ftell(fd).unwrap(), 6);
+
+        let reader = fs.fopen("/log", OpenMode::Read).unwrap();
+        assert!(matches!(fs.fwrite(reader, b"x"), Err(Error::BadFileDescriptor(_))));
+        let mut data = [0; 6];
+        fs.fread(reader, &mut data).unwrap();
+        assert_eq!(&data, b"onetwo");
+
+        // opening for writing truncates, read/write does not
+        let fd = fs.fopen("/log", OpenMode::ReadWrite).unwrap();
+        fs.fwrite(fd, b"ONE").unwrap();
+        fs.fread(fd, &mut data[..3]).unwrap();
+        assert_eq!(&data[..3], b"two");
+        fs.fopen("/log", OpenMode::Write).unwrap();
+        let inumber = fs.lookup("/log").unwrap();
+        assert_eq!(fs.stat(inumber).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut fs = filesystem();
+        let fd = fs.fopen("/data", OpenMode::Write).unwrap();
+        fs.fwrite(fd, b"0123456789").unwrap();
+        fs.fclose(fd).unwrap();
+
+        let fd = fs.fopen("/data", OpenMode::ReadWrite).unwrap();
+        assert_eq!(fs.fseek(fd, SeekFrom::End(-3)).unwrap(), 7);
+        let mut data = [0; 3];
+        fs.fread(fd, &mut data).unwrap();
+        assert_eq!(&data, b"789");
+        assert_eq!(fs.fseek(fd, SeekFrom::Current(-8)).unwrap(), 2);
+        assert!(matches!(fs.fseek(fd, SeekFrom::Current(-3)), Err(Error::InvalidSeek(-1))));
+        assert_eq!(fs.ftell(fd).unwrap(), 2);
+
+        // writing past the end leaves a zero-filled gap
+        fs.fseek(fd, SeekFrom::Start(12)).unwrap();
+        fs.fwrite(fd, b"!").unwrap();
+        fs.fseek(fd, SeekFrom::Start(9)).unwrap();
+        let mut data = [0xff; 4];
+        assert_eq!(fs.fread(fd, &mut data).unwrap(), 4);
+        assert_eq!(&data, b"9\0\0!");
+
+        // offsets that do not fit are refused rather than wrapped
+        assert_eq!(fs.fseek(fd, SeekFrom::Start(u64::MAX >> 1)).unwrap(), usize::MAX >> 1);
+        assert!(matches!(fs.fseek(fd, SeekFrom::Current(1)), Err(Error::InvalidSeek(_))));
+        fs.fseek(fd, SeekFrom::Start(1)).unwrap();
+        assert!(matches!(fs.fseek(fd, SeekFrom::Current(i64::MAX)), Err(Error::InvalidSeek(_))));
+        assert_eq!(fs.ftell(fd).unwrap(), 1);
+
+        // the furthest seek is allowed, but nothing can be written there
+        let far = fs.fseek(fd, SeekFrom::Start(u64::MAX)).unwrap();
+        assert!(matches!(fs.fwrite(fd, b"x"), Err(Error::FileTooLarge)));
+        assert_eq!(fs.fread(fd, &mut [0; 1]).unwrap(), 0);
+        assert_eq!(fs.ftell(fd).unwrap(), far);
+        let inumber = fs.lookup("/data").unwrap();
+        assert_eq!(fs.stat(inumber).unwrap(), 13);
+    }
+
+    #[test]
+    fn test_close() {
+        let mut fs = filesystem();
+        let inumber = fs.create().unwrap();
+        let a = fs.fopen_inode(inumber, OpenMode::Read).unwrap();
+        let b = fs.fopen_inode(inumber, OpenMode::Read).unwrap();
+        assert_eq!((a, b), (0, 1));
+
+        fs.fclose(a).unwrap();
+        assert!(matches!(fs.fclose(a), Err(Error::BadFileDescriptor(0))));
+        assert!(matches!(fs.ftell(a), Err(Error::BadFileDescriptor(0))));
+        assert_eq!(fs.fopen_inode(inumber, OpenMode::Read).unwrap(), 0);
+
+        // unmounting closes everything
+        fs.unmount().unwrap();
+        assert!(fs.open_file(b).is_err());
+    }
+
+    #[test]
+    fn test_freed_file_closes_descriptors() {
+        let mut fs = filesystem();
+        let writer = fs.fopen("/old", OpenMode::Write).unwrap();
+        fs.fwrite(writer, b"old").unwrap();
+        let stale = fs.fopen("/old", OpenMode::ReadWrite).unwrap();
+        let old = fs.lookup("/old").unwrap();
+        fs.unlink("/old").unwrap();
+        assert!(matches!(fs.ftell(writer), Err(Error::BadFileDescriptor(_))));
+
+        // the inode is handed out again, but the stale descriptor cannot
+        // reach the new file through it
+        let fresh = fs.fopen("/new", OpenMode::Write).unwrap();
+        assert_eq!(fs.lookup("/new").unwrap(), old);
+        assert_ne!(fresh, stale);
+        fs.fwrite(fresh, b"new").unwrap();
+        assert!(matches!(fs.fread(stale, &mut [0; 3]), Err(Error::BadFileDescriptor(_))));
+        assert!(matches!(fs.fwrite(stale, b"x"), Err(Error::BadFileDescriptor(_))));
+        assert_eq!(fs.stat(old).unwrap(), 3);
+
+        let inumber = fs.create().unwrap();
+        let fd = fs.fopen_inode(inumber, OpenMode::Read).unwrap();
+        fs.remove(inumber).unwrap();
+        assert!(matches!(fs.fclose(fd), Err(Error::BadFileDescriptor(_))));
+    }
+}
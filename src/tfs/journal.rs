@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::*;
+use super::FileSystem;
+
+// the header keeps a block number per copy after its three fields
+const HEADER_FIELDS: usize = 3;
+const MAX_TRANSACTION_BLOCKS: usize = Disk::BLOCK_SIZE / 4 - HEADER_FIELDS;
+
+/// The metadata writes of the operation in progress, held back until the
+/// operation is over and they can be committed through the journal together.
+#[derive(Default)]
+pub struct Transaction {
+    depth: usize,                         // nesting of `FileSystem::transaction`
+    blocks: BTreeMap<usize, Vec<u8>>      // new contents, by block number
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Runs `op` as a single transaction: the metadata blocks it writes reach
+    /// their place on disk all together or (after a crash and the replay in
+    /// `mount`) not at all. Nested calls join the outer transaction.
+    ///
+    /// When `op` fails half way its writes are dropped instead, and the
+    /// in-memory metadata (superblock, bitmaps) is reloaded from the disk,
+    /// as for a transaction too large for the journal. File data written in
+    /// place stays, but blocks newly allocated for it go back to being free.
+    pub(super) fn transaction<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        self.transaction.depth += 1;
+        let result = op(self);
+        self.transaction.depth -= 1;
+
+        if self.transaction.depth > 0 {
+            return result;
+        }
+        match result {
+            Ok(value) => {
+                self.commit()?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.transaction.blocks.clear();
+                // should the disk not even be readable, that is the news
+                self.discard_transaction()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn journal_blocks(&self) -> usize {
+        match &self.metaData {
+            Some(metaData) => metaData.superBlock.JournalBlocks as usize,
+            None => 0
+        }
+    }
+
+    /// Reads a block as the filesystem sees it, including writes still
+    /// waiting in the open transaction.
+    pub(super) fn read_block(&mut self, block_num: usize, data: &mut [u8]) -> Result<()> {
+        if let Some(pending) = self.transaction.blocks.get(&block_num) {
+            data.copy_from_slice(pending);
+            return Ok(());
+        }
+        self.disk()?.read_block(block_num, data)
+    }
+
+    /// `read_block` for a run of consecutive blocks, in a single request
+    /// unless part of the run is waiting in the open transaction.
+    pub(super) fn read_blocks(&mut self, block_num: usize, data: &mut [u8]) -> Result<()> {
+        let count = data.len() / Disk::BLOCK_SIZE;
+        if self.transaction.blocks.range(block_num..block_num + count).next().is_some() {
+            for (i, block) in data.chunks_mut(Disk::BLOCK_SIZE).enumerate() {
+                self.read_block(block_num + i, block)?;
+            }
+            return Ok(());
+        }
+        self.disk()?.read_blocks(block_num, data)
+    }
+
+    /// Writes a block of metadata: through the journal when the image has
+    /// one and a transaction is open, in place otherwise.
+    pub(super) fn write_metadata_block(&mut self, block_num: usize, data: &[u8]) -> Result<()> {
+        if self.transaction.depth == 0 || self.journal_blocks() == 0 {
+            return self.disk()?.write_block(block_num, data);
+        }
+        self.transaction.blocks.insert(block_num, data.to_vec());
+        Ok(())
+    }
+
+    /// Writes a block of file data in place. Data reaches the disk before
+    /// the transaction that points at it is committed, so a crash leaves
+    /// new blocks unreferenced rather than metadata pointing at garbage.
+    pub(super) fn write_data_block(&mut self, block_num: usize, data: &[u8]) -> Result<()> {
+        // the block may have been metadata earlier in the transaction
+        self.transaction.blocks.remove(&block_num);
+        self.disk()?.write_block(block_num, data)
+    }
+
+    /// Writes the open transaction to the journal, marks it committed, then
+    /// copies it into place and clears the journal. Operations that change
+    /// many blocks (`write`, `truncate`, freeing a large file) split the work
+    /// into transactions that fit; one that does not anyway is refused whole,
+    /// since committing it in pieces would give up its atomicity.
+    fn commit(&mut self) -> Result<()> {
+        let blocks = mem::take(&mut self.transaction.blocks);
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let superBlock = match &self.metaData {
+            Some(metaData) => metaData.superBlock,
+            None => return Ok(())
+        };
+
+        let start = superBlock.journal_start();
+        let capacity = (superBlock.JournalBlocks as usize - 1).min(MAX_TRANSACTION_BLOCKS);
+        if blocks.len() > capacity {
+            // nothing reached the disk, so bring the bitmaps and inode table
+            // in memory back to what it holds
+            self.discard_transaction()?;
+            return Err(Error::TransactionTooLarge { blocks: blocks.len(), capacity });
+        }
+
+        let blocks: Vec<(usize, Vec<u8>)> = blocks.into_iter().collect();
+        let disk = &mut self.disk;
+
+        // STEP 1: copies first, so a valid header always has them behind it
+        for (i, (_, data)) in blocks.iter().enumerate() {
+            disk.write_block(start + 1 + i, data)?;
+        }
+        disk.flush()?;
+
+        // STEP 2: the header is the commit point
+        disk.write_block(start, &Self::journal_header(&blocks))?;
+        disk.flush()?;
+
+        // STEP 3: write everything in place and retire the transaction
+        for (block_num, data) in blocks.iter() {
+            disk.write_block(*block_num, data)?;
+        }
+        disk.flush()?;
+        disk.write_block(start, &[0; Disk::BLOCK_SIZE])?;
+        disk.flush()?;
+        Ok(())
+    }
+
+    // reloads the metadata of the mounted filesystem from the disk, undoing
+    // a transaction that was never written; blocks reserved for writes in
+    // flight stay taken
+    fn discard_transaction(&mut self) -> Result<()> {
+        if !self.is_mounted() {
+            return Ok(());
+        }
+        let metaData = Self::read_meta_data(&mut self.disk)?;
+        let (inode_bit_map, mut data_bit_map) = Self::scan_bitmaps(&mut self.disk, &metaData)?;
+        for block_num in self.reservedBlocks.iter() {
+            data_bit_map[*block_num - metaData.superBlock.data_start()] = true;
+        }
+        self.metaData = Some(metaData);
+        self.inodeBitMap = Some(inode_bit_map);
+        self.dataBitMap = Some(data_bit_map);
+        Ok(())
+    }
+
+    fn journal_header(blocks: &[(usize, Vec<u8>)]) -> [u8; Disk::BLOCK_SIZE] {
+        let mut header = [0; Disk::BLOCK_SIZE];
+        put_u32(&mut header, 0, JOURNAL_MAGIC);
+        put_u32(&mut header, 4, blocks.len() as u32);
+        put_u32(&mut header, 8, journal_checksum(blocks));
+        for (i, (block_num, _)) in blocks.iter().enumerate() {
+            put_u32(&mut header, 4 * (HEADER_FIELDS + i), *block_num as u32);
+        }
+        header
+    }
+
+    /// The committed transaction waiting in the journal, if there is one:
+    /// a header with the magic number, block numbers outside the journal
+    /// and a checksum matching the copies.
+    pub(super) fn journaled_blocks(disk: &mut D, superBlock: &Superblock) -> Result<Vec<(usize, Vec<u8>)>> {
+        let journal_blocks = superBlock.JournalBlocks as usize;
+        if journal_blocks == 0 {
+            return Ok(Vec::new());
+        }
+
+        let start = superBlock.journal_start();
+        let mut header = [0; Disk::BLOCK_SIZE];
+        disk.read_block(start, &mut header)?;
+        let count = get_u32(&header, 4) as usize;
+        if get_u32(&header, 0) != JOURNAL_MAGIC || count == 0 || count >= journal_blocks
+            || count > MAX_TRANSACTION_BLOCKS
+        {
+            return Ok(Vec::new());
+        }
+
+        let mut blocks = Vec::with_capacity(count);
+        for i in 0..count {
+            let block_num = get_u32(&header, 4 * (HEADER_FIELDS + i)) as usize;
+            let in_journal = block_num >= start && block_num < start + journal_blocks;
+            if block_num >= superBlock.Blocks as usize || in_journal {
+                return Ok(Vec::new());
+            }
+
+            let mut data = vec![0; Disk::BLOCK_SIZE];
+            disk.read_block(start + 1 + i, &mut data)?;
+            blocks.push((block_num, data));
+        }
+
+        if journal_checksum(&blocks) != get_u32(&header, 8) {
+            return Ok(Vec::new());
+        }
+        Ok(blocks)
+    }
+
+    /// Finishes a transaction that was committed before a crash. Returns
+    /// the number of blocks written back into place.
+    pub(super) fn replay_journal(disk: &mut D, superBlock: &Superblock) -> Result<usize> {
+        let blocks = Self::journaled_blocks(disk, superBlock)?;
+        if blocks.is_empty() {
+            return Ok(0);
+        }
+
+        for (block_num, data) in blocks.iter() {
+            disk.write_block(*block_num, data)?;
+        }
+        disk.flush()?;
+        disk.write_block(superBlock.journal_start(), &[0; Disk::BLOCK_SIZE])?;
+        disk.flush()?;
+        Ok(blocks.len())
+    }
+}
+
+/// FNV-1a over the block numbers and contents of a transaction.
+fn journal_checksum(blocks: &[(usize, Vec<u8>)]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    let mut add = |byte: u8| {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    };
+    for (block_num, data) in blocks.iter() {
+        for byte in (*block_num as u32).to_le_bytes().iter() {
+            add(*byte);
+        }
+        for byte in data.iter() {
+            add(*byte);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::crash::{Event, RecordingDisk};
+    use super::super::fsck::Problem;
+    use super::super::memdisk::MemDisk;
+
+    // the blocks written, in order, leaving out the flushes
+    fn writes(log: &[Event]) -> Vec<(usize, Vec<u8>)> {
+        log.iter()
+            .filter_map(|event| match event {
+                Event::Write(block_num, data) => Some((*block_num, data.clone())),
+                Event::Flush => None
+            })
+            .collect()
+    }
+
+    fn formatted(blocks: usize) -> MemDisk {
+        let mut disk = MemDisk::new(blocks);
+        FileSystem::format(&mut disk).unwrap();
+        disk
+    }
+
+    #[test]
+    fn test_journal_reserved_on_big_disks() {
+        let mut fs = FileSystem::new(formatted(JOURNAL_MIN_BLOCKS));
+        fs.mount().unwrap();
+        let superBlock = fs.metaData.as_ref().unwrap().superBlock;
+        assert_eq!(superBlock.JournalBlocks as usize, JOURNAL_BLOCKS);
+        assert_eq!(superBlock.data_start(), superBlock.journal_start() + JOURNAL_BLOCKS);
+        assert!(!fs.dataBitMap.as_ref().unwrap()[1]);
+
+        let mut fs = FileSystem::new(formatted(JOURNAL_MIN_BLOCKS - 1));
+        fs.mount().unwrap();
+        assert_eq!(fs.metaData.as_ref().unwrap().superBlock.JournalBlocks, 0);
+    }
+
+    #[test]
+    fn test_metadata_goes_through_the_journal() {
+        let mut fs = FileSystem::new(RecordingDisk::new(formatted(JOURNAL_MIN_BLOCKS)));
+        fs.mount().unwrap();
+        let superBlock = fs.metaData.as_ref().unwrap().superBlock;
+        fs.disk.log.clear();
+
+        fs.mkdir("/dir").unwrap();
+
+        // copies of the directory, its parent, the inode block, the bitmap
+        // and the superblock; then the header, the blocks in place and the
+        // cleared header
+        let start = superBlock.journal_start();
+        let writes: Vec<usize> = writes(&fs.disk.log).iter().map(|(block_num, _)| *block_num).collect();
+        let copies = writes.iter().take_while(|block_num| **block_num > start).count();
+        assert_eq!(copies, 5);
+        assert_eq!(writes[copies], start);
+        assert_eq!(writes[writes.len() - 1], start);
+        assert_eq!(writes.len(), 2 * copies + 2);
+
+        let mut disk = fs.into_disk().unwrap().disk;
+        assert!(FileSystem::journaled_blocks(&mut disk, &superBlock).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crash_around_the_commit_point() {
+        let base = formatted(JOURNAL_MIN_BLOCKS);
+        let mut fs = FileSystem::new(RecordingDisk::new(base.clone()));
+        fs.mount().unwrap();
+        let start = fs.metaData.as_ref().unwrap().superBlock.journal_start();
+        let mounted = writes(&fs.disk.log).len();
+        fs.mkdir("/dir").unwrap();
+        let writes = writes(&fs.into_disk().unwrap().log);
+        let header = mounted + writes[mounted..].iter().position(|(block_num, _)| *block_num == start).unwrap();
+
+        // power is lost right before, and right after, the header is written
+        for (end, exists) in [(header, false), (header + 1, true)].iter() {
+            let mut disk = base.clone();
+            for (block_num, data) in writes[..*end].iter() {
+                disk.write(*block_num, data).unwrap();
+            }
+
+            let mut fs = FileSystem::new(&mut disk);
+            fs.mount().unwrap();
+            assert_eq!(fs.lookup("/dir").is_ok(), *exists);
+            fs.unmount().unwrap();
+            assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+        }
+    }
+
+    #[test]
+    fn test_oversized_transaction_is_refused() {
+        let mut fs = FileSystem::new(RecordingDisk::new(formatted(JOURNAL_MIN_BLOCKS)));
+        fs.mount().unwrap();
+        let dataStart = fs.metaData.as_ref().unwrap().superBlock.data_start();
+        fs.disk.log.clear();
+
+        // a file, then more blocks than the journal holds
+        let result = fs.transaction(|fs| {
+            let inumber = fs.allocate_inode(FILE_INODE)?;
+            for block_num in dataStart + 1..dataStart + 1 + MAX_TRANSACTION_BLOCKS {
+                fs.write_metadata_block(block_num, &[0xaa; Disk::BLOCK_SIZE])?;
+            }
+            Ok(inumber)
+        });
+        assert!(matches!(result, Err(Error::TransactionTooLarge { capacity: 127, .. })));
+
+        // none of it was written, and none of it is left in memory
+        assert!(writes(&fs.disk.log).is_empty());
+        assert!(matches!(fs.stat(1), Err(Error::InvalidInode(1))));
+        assert_eq!(fs.create().unwrap(), 1);
+        fs.unmount().unwrap();
+        assert!(FileSystem::check(&mut fs.disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_large_truncate_commits_in_steps() {
+        let mut fs = FileSystem::new(RecordingDisk::new(formatted(JOURNAL_MIN_BLOCKS)));
+        fs.mount().unwrap();
+        let start = fs.metaData.as_ref().unwrap().superBlock.journal_start();
+        let inumber = fs.open("/large").unwrap();
+        let data = vec![1; 300 * Disk::BLOCK_SIZE];
+        fs.write(inumber, &data, data.len(), 0).unwrap();
+        fs.disk.log.clear();
+
+        // 300 blocks go back 64 at a time
+        fs.truncate(inumber, 10).unwrap();
+        let commits = writes(&fs.disk.log).iter()
+            .filter(|(block_num, data)| *block_num == start && get_u32(data, 0) == JOURNAL_MAGIC)
+            .count();
+        assert_eq!(commits, 5);
+        assert_eq!(fs.stat(inumber).unwrap(), 10);
+
+        fs.write(inumber, &data, data.len(), 0).unwrap();
+        fs.unlink("/large").unwrap();
+        fs.unmount().unwrap();
+        assert!(FileSystem::check(&mut fs.disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_fsck_reports_unreplayed_journal() {
+        let base = formatted(JOURNAL_MIN_BLOCKS);
+        let mut fs = FileSystem::new(RecordingDisk::new(base.clone()));
+        fs.mount().unwrap();
+        let start = fs.metaData.as_ref().unwrap().superBlock.journal_start();
+        fs.create().unwrap();
+        let writes = writes(&fs.into_disk().unwrap().log);
+        let header = writes.iter().position(|(block_num, _)| *block_num == start).unwrap();
+
+        let mut disk = base.clone();
+        for (block_num, data) in writes[..=header].iter() {
+            disk.write(*block_num, data).unwrap();
+        }
+        let report = FileSystem::check(&mut disk, false).unwrap();
+        assert!(matches!(report.problems[..], [Problem::UnreplayedJournal { blocks: 3 }]));
+
+        let report = FileSystem::check(&mut disk, true).unwrap();
+        assert!(report.repaired);
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+
+        let mut fs = FileSystem::new(&mut disk);
+        fs.mount().unwrap();
+        assert!(matches!(fs.stat(1), Ok(0)));
+        assert!(matches!(fs.stat(2), Err(Error::InvalidInode(2))));
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::device::{BlockDevice, SharedDevice};
+use super::disk::Disk;
+use super::error::{Error, Result};
+
+// blocks under each lock: requests for blocks in different stripes can run
+// at the same time
+const STRIPE_BLOCKS: usize = 64;
+
+/// A disk image that lives entirely in memory.
+///
+/// Useful for tests and scratch filesystems; `from_image` and `dump` move
+/// the contents to and from a regular image file.
+pub struct MemDisk {
+    Data: Vec<RwLock<Vec<u8>>>,  // contents of every block, STRIPE_BLOCKS to a lock
+    Blocks: usize,               // Number of blocks in disk image
+    Reads: AtomicUsize,          // Number of reads performed
+    Writes: AtomicUsize          // Number of writes performed
+}
+
+impl MemDisk {
+    pub fn new(nblocks: usize) -> MemDisk {
+        Self::from_data(vec![0; nblocks * Disk::BLOCK_SIZE])
+    }
+
+    /// load a disk image file into memory
+    pub fn from_image<P: AsRef<Path>>(path: P) -> Result<MemDisk> {
+        let data = fs::read(path)?;
+        if data.len() % Disk::BLOCK_SIZE != 0 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "image size is not a multiple of the block size"
+            )));
+        }
+        Ok(Self::from_data(data))
+    }
+
+    /// write the whole disk out as an image file
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.contents())?;
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        self.Blocks
+    }
+
+    pub fn reads(&self) -> usize {
+        self.Reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.Writes.load(Ordering::Relaxed)
+    }
+
+    pub fn read(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        let start = self.offset(blocknum)?;
+        data.copy_from_slice(&self.stripe(blocknum)[start..start + data.len()]);
+        self.Reads.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn write(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+        let start = self.offset(blocknum)?;
+        self.stripe_mut(blocknum)[start..start + data.len()].copy_from_slice(data);
+        self.Writes.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Grows (with zeroed blocks) or truncates the disk to `nblocks` blocks.
+    pub fn set_size(&mut self, nblocks: usize) {
+        let mut data = self.contents();
+        data.resize(nblocks * Disk::BLOCK_SIZE, 0);
+        *self = Self::from_data(data);
+    }
+
+    fn from_data(data: Vec<u8>) -> MemDisk {
+        MemDisk {
+            Blocks: data.len() / Disk::BLOCK_SIZE,
+            Data: data.chunks(STRIPE_BLOCKS * Disk::BLOCK_SIZE).map(|stripe| RwLock::new(stripe.to_vec())).collect(),
+            Reads: AtomicUsize::new(0),
+            Writes: AtomicUsize::new(0)
+        }
+    }
+
+    // every block, back to back
+    fn contents(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.Blocks * Disk::BLOCK_SIZE);
+        for stripe in 0..self.Data.len() {
+            data.extend_from_slice(&self.stripe(stripe * STRIPE_BLOCKS));
+        }
+        data
+    }
+
+    // where `blocknum` starts within its stripe
+    fn offset(&self, blocknum: usize) -> Result<usize> {
+        if blocknum >= self.Blocks {
+            return Err(Error::BlockOutOfRange { block: blocknum, blocks: self.Blocks });
+        }
+        Ok(blocknum % STRIPE_BLOCKS * Disk::BLOCK_SIZE)
+    }
+
+    // a request that panicked cannot leave a block half copied, so a
+    // poisoned lock is as good as any
+    fn stripe(&self, blocknum: usize) -> RwLockReadGuard<'_, Vec<u8>> {
+        self.Data[blocknum / STRIPE_BLOCKS].read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stripe_mut(&self, blocknum: usize) -> RwLockWriteGuard<'_, Vec<u8>> {
+        self.Data[blocknum / STRIPE_BLOCKS].write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clone for MemDisk {
+    fn clone(&self) -> Self {
+        let mut disk = Self::from_data(self.contents());
+        disk.Reads = AtomicUsize::new(self.reads());
+        disk.Writes = AtomicUsize::new(self.writes());
+        disk
+    }
+}
+
+impl BlockDevice for MemDisk {
+    fn block_size(&self) -> usize {
+        Disk::BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> usize {
+        self.size()
+    }
+
+    fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read(blocknum, data)
+    }
+
+    fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.write(blocknum, data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&mut self, blocks: usize) -> Result<()> {
+        self.set_size(blocks);
+        Ok(())
+    }
+}
+
+impl SharedDevice for MemDisk {
+    fn read_block_shared(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+        self.read(blocknum, data)
+    }
+
+    fn write_block_shared(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+        self.write(blocknum, data)
+    }
+
+    fn flush_shared(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memdisk_read_write() {
+        let disk = MemDisk::new(5);
+        let data = [3; Disk::BLOCK_SIZE];
+        disk.write(1, &data).unwrap();
+
+        let mut data2 = [0; Disk::BLOCK_SIZE];
+        disk.read(1, &mut data2).unwrap();
+
+        assert_eq!(data, data2);
+        assert_eq!(disk.reads(), 1);
+        assert_eq!(disk.writes(), 1);
+    }
+
+    #[test]
+    fn memdisk_out_of_range() {
+        let disk = MemDisk::new(5);
+        let mut data = [0; Disk::BLOCK_SIZE];
+        match disk.read(5, &mut data) {
+            Err(Error::BlockOutOfRange { block: 5, blocks: 5 }) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn memdisk_dump_and_load() {
+        let path = std::env::temp_dir().join(format!("tfs-memdisk-{}.img", std::process::id()));
+        let disk = MemDisk::new(5);
+        disk.write(4, &[7; Disk::BLOCK_SIZE]).unwrap();
+        disk.dump(&path).unwrap();
+
+        let loaded = MemDisk::from_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut data = [0; Disk::BLOCK_SIZE];
+        loaded.read(4, &mut data).unwrap();
+        assert_eq!(loaded.size(), 5);
+        assert_eq!(data, [7; Disk::BLOCK_SIZE]);
+    }
+}
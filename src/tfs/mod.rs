@@ -1,787 +1,1217 @@
+mod build;
+mod cache;
+#[cfg(test)]
+mod crash;
+mod device;
+mod directory;
 mod disk;
+mod error;
+mod file;
+mod fsck;
+mod handle;
+mod journal;
+mod memdisk;
+mod options;
+mod readahead;
+mod resize;
+mod sfs;
+mod shared;
+mod tar;
+#[cfg(test)]
+mod testutil;
 mod types;
+#[allow(dead_code, clippy::identity_op)]
 mod utility;
 
+use std::collections::BTreeSet;
+
+pub use self::build::Manifest;
+pub use self::cache::BlockCache;
+pub use self::device::{BlockDevice, SharedDevice};
 use self::disk::Disk;
+pub use self::memdisk::MemDisk;
+pub use self::options::FormatOptions;
+pub use self::error::{Error, Result};
+pub use self::file::TfsFile;
+pub use self::fsck::{Problem, Report};
+pub use self::handle::{OpenFile, OpenMode};
+pub use self::sfs::SimpleFs;
+pub use self::shared::SharedFileSystem;
+use self::journal::Transaction;
+use self::options::format_uuid;
+use self::readahead::ReadAhead;
 use self::types::*;
 
-pub struct FileSystem<'a> {
+static ZEROS: [u8; Disk::BLOCK_SIZE] = [0; Disk::BLOCK_SIZE];
+
+// the most blocks a single transaction of `write` covers, or of
+// `truncate` releases
+const WRITE_CHUNK_BLOCKS: usize = 64;
+
+pub struct FileSystem<D: BlockDevice> {
     pub metaData: Option<MetaData>,
     pub inodeBitMap: Option<Vec<bool>>,
     pub dataBitMap: Option<Vec<bool>>,
-    pub disk: Option<Disk<'a>>
+    openFiles: Vec<Option<OpenFile>>,  // indexed by file descriptor
+    transaction: Transaction,          // metadata writes not yet committed
+    readAhead: ReadAhead,              // sequential reads seen so far
+    reservedBlocks: BTreeSet<usize>,   // set aside for writes not yet linked in
+    pub disk: D
 }
 
-impl<'a> FileSystem<'a> {
-    pub fn new() -> Self {
+impl<D: BlockDevice> FileSystem<D> {
+    /// Wraps `disk` in an unmounted filesystem; call `mount` before using it.
+    pub fn new(disk: D) -> Self {
         FileSystem {
             metaData: None,
             inodeBitMap: None,
             dataBitMap: None,
-            disk: None
+            openFiles: Vec::new(),
+            transaction: Transaction::default(),
+            readAhead: ReadAhead::default(),
+            reservedBlocks: BTreeSet::new(),
+            disk
         }
     }
 
-    // pub fn clone(&self) -> Self {
-    //     FileSystem {
-    //         metaData: self.metaData,
-    //         inodeBitMap: self.inodeBitMap,
-    //         dataBitMap: self.dataBitMap,
-    //         disk: self.disk
-    //     }
-    // }
-
-    pub fn from_disk(disk: &mut Disk<'a>) -> Self {
-        let mut fs = Self::new();
-
-        // STEP 1: try mounting the disk
-        let mut disk_clone = disk.clone();
-        let mut disk_clone2 = disk.clone();
-        let mounted = fs.mount(disk);
+    pub fn from_disk(disk: D) -> Result<Self> {
+        let mut fs = Self::new(disk);
 
-        if !mounted {
-            let formatted = Self::format(&mut disk_clone);
-            if formatted {
-                fs.mount(&mut disk_clone2);
-            }
+        // STEP 1: format the disk first if it holds no filesystem yet
+        if Self::read_super_block(&mut fs.disk)?.MagicNumber != MAGIC_NUMBER as u32 {
+            Self::format(&mut fs.disk)?;
         }
 
-        fs
+        // STEP 2: mount it
+        fs.mount()?;
+        Ok(fs)
+    }
+
+    /// Unmounts the filesystem (if needed) and hands back the device.
+    pub fn into_disk(mut self) -> Result<D> {
+        self.unmount()?;
+        Ok(self.disk)
     }
 
-    pub fn info(&self) {
+    pub fn info(&mut self) -> Result<()> {
         match &self.metaData {
-            Some(metaData) => Self::debug_print(metaData),
-            _ => {}
+            Some(metaData) => Self::debug_print(&mut self.disk, metaData),
+            None => Err(Error::NotMounted)
         }
     }
 
-    pub fn debug(disk: &mut Disk<'a>) {
-        let metaData = Self::read_meta_data(disk);
-        Self::debug_print(&metaData);
+    pub fn debug(disk: &mut D) -> Result<()> {
+        let metaData = Self::read_meta_data(disk)?;
+        Self::debug_print(disk, &metaData)
     }
 
-    pub fn debug_print(meta_data: &MetaData) {
+    /// Prints the superblock and every valid inode in the same format as
+    /// SimpleFS's `sfssh`, followed by what tfs adds to it.
+    pub fn debug_print(disk: &mut D, meta_data: &MetaData) -> Result<()> {
         let superblock = &meta_data.superBlock;
-        let inodeTable = &meta_data.inodeTable;
-
-        println!("********* SUPER BLOCK ***********");
-        println!("Magic Number  {} is valid", superblock.MagicNumber);
-        println!("{} blocks", superblock.Blocks);
-        println!("{} inode blocks", superblock.InodeBlocks);
-        println!("{} inodes", superblock.Inodes);
-        println!("********* END SUPER ***********\n");
-
-
-        let mut i_number = 0;
-        let mut end_of_tab = false;
-        println!("********* INODE INFO ***********");
-        for inodes in inodeTable.iter() {
-            if !end_of_tab {
-                for inode in inodes.iter() {
-                    if i_number < superblock.Inodes {
-                        i_number += 1;
-                        println!("Inode {}:", i_number);
-                        println!("  size: {} bytes", inode.Size);
-                        println!("  direct blocks: {}", inode.Direct.len())
-                    } else {
-                        end_of_tab = true;
-                        break
-                    }
+
+        println!("SuperBlock:");
+        if superblock.MagicNumber != MAGIC_NUMBER as u32 {
+            println!("    magic number is invalid");
+            return Ok(());
+        }
+        println!("    magic number is valid");
+        println!("    {} blocks", superblock.Blocks);
+        println!("    {} inode blocks", superblock.InodeBlocks);
+        println!("    {} inodes", superblock.inode_count());
+        println!("    {} inodes in use", superblock.Inodes);
+        println!("    {} bitmap blocks", superblock.BitmapBlocks);
+        if superblock.ReservedBlocks > 0 {
+            println!("    {} reserved blocks", superblock.ReservedBlocks);
+        }
+        if superblock.Label[0] != 0 {
+            println!("    label: {}", superblock.label());
+        }
+        if superblock.Uuid != [0; 16] {
+            println!("    uuid: {}", format_uuid(&superblock.Uuid));
+        }
+
+        let block_list = |pointers: &[u32]| -> String {
+            pointers.iter().filter(|ptr| **ptr != 0).map(|ptr| format!(" {}", ptr)).collect()
+        };
+        for (i, inodes) in meta_data.inodeTable.iter().enumerate() {
+            for (row, inode) in inodes.iter().enumerate() {
+                if inode.Valid == FREE_INODE {
+                    continue;
+                }
+
+                println!("Inode {}:", i * INODES_PER_BLOCK + row);
+                if inode.is_directory() {
+                    println!("    directory");
+                }
+                println!("    size: {} bytes", inode.Size);
+                println!("    direct blocks:{}", block_list(&inode.Direct));
+                if inode.Indirect != 0 {
+                    let mut block = Block::new();
+                    disk.read_block(inode.Indirect as usize, &mut block.Data)?;
+                    println!("    indirect block: {}", inode.Indirect);
+                    println!("    indirect data blocks:{}", block_list(&block.pointers()));
+                }
+                if inode.DoubleIndirect != 0 {
+                    println!("    double indirect block: {}", inode.DoubleIndirect);
+                }
+                if inode.TripleIndirect != 0 {
+                    println!("    triple indirect block: {}", inode.TripleIndirect);
                 }
             }
         }
-        println!("********* END INODE ***********\n");
 
+        Ok(())
     }
 
-    pub fn format(disk: &mut Disk<'a>) -> bool {
-        // STEP 1: set aside 10% of blocks for inodes
-        let total_inode_blocks = (disk.size() as f64 * 0.1).ceil() as usize;
+    pub fn format(disk: &mut D) -> Result<()> {
+        Self::format_with(disk, &FormatOptions::default())
+    }
+
+    /// Formats `disk` laid out as `options` asks.
+    pub fn format_with(disk: &mut D, options: &FormatOptions) -> Result<()> {
+        Self::check_block_size(disk)?;
+
+        // STEP 1: set aside blocks for inodes, followed by enough bitmap
+        // blocks for every inode and block, and a journal on disks big
+        // enough to afford one
+        let superblock = options.superblock(disk.block_count())?;
+        let total_inode_blocks = superblock.InodeBlocks as usize;
+        let journal_blocks = superblock.JournalBlocks as usize;
 
         // STEP 2: clear the inode table
-        for i in 1..total_inode_blocks + 1 {
-            disk.write(i, &mut [0; Disk::BLOCK_SIZE]);
+        for i in 2..total_inode_blocks + 1 {
+            disk.write_block(i, &[0; Disk::BLOCK_SIZE])?;
         }
 
-        // STEP 3: write the super block
-        let superblock = Block {
-            Super: Superblock {
-                MagicNumber: MAGIC_NUMBER as u32,
-                Blocks: disk.size() as u32,
-                InodeBlocks: total_inode_blocks as u32,
-                Inodes: 0
-            }
-        };
-        disk.write(0, &mut superblock.data());
+        // an empty journal header: nothing to replay
+        if journal_blocks > 0 {
+            disk.write_block(superblock.journal_start(), &[0; Disk::BLOCK_SIZE])?;
+        }
 
-        true
+        // STEP 3: create the root directory in the first inode and data block
+        let root_block = superblock.data_start();
+        let mut block = Block::new();
+        let mut entries = [DirEntry::blank(); ENTRIES_PER_BLOCK];
+        entries[0] = DirEntry::new(".", ROOT_INODE);
+        entries[1] = DirEntry::new("..", ROOT_INODE);
+        block.set_entries(entries);
+        disk.write_block(root_block, &block.data())?;
+
+        let mut root = Inode::blank();
+        root.Valid = DIRECTORY_INODE;
+        root.Size = (2 * DIR_ENTRY_SIZE) as u64;
+        root.Direct[0] = root_block as u32;
+        let mut inodes = [Inode::blank(); INODES_PER_BLOCK];
+        inodes[ROOT_INODE] = root;
+        block.set_inodes(inodes);
+        disk.write_block(1, &block.data())?;
+
+        // STEP 4: write the bitmaps with just the root directory in use
+        let mut inode_bit_map = vec![false; superblock.inode_count()];
+        let mut data_bit_map = vec![false; superblock.data_end() - root_block];
+        inode_bit_map[ROOT_INODE] = true;
+        data_bit_map[0] = true;
+        Self::write_bitmaps(disk, &superblock, &inode_bit_map, &data_bit_map)?;
+
+        // STEP 5: write the super block
+        block.set_superblock(superblock);
+        disk.write_block(0, &block.data())?;
+        disk.flush()
     }
 
-    pub fn mount(&mut self, disk: &mut Disk<'a>) -> bool {
-        let metaData = Self::read_meta_data(disk);
-        let metaData2 = Self::read_meta_data(disk); // ...have no idea how to make a copy
-        // println!("metaData: {:?}", metaData.inodeTable);
-        // println!("metaData2: {:?}", metaData.superBlock);
-
-        if metaData.superBlock.MagicNumber != MAGIC_NUMBER as u32 {
-            return false
+    pub fn mount(&mut self) -> Result<()> {
+        if self.is_mounted() {
+            return Err(Error::AlreadyMounted);
         }
 
-        self.metaData = Some(metaData2);
-
-        let nBlocks = metaData.superBlock.Blocks;
-        let inodeBlocks = metaData.superBlock.InodeBlocks;
-
-        let mut inode_bit_map = Vec::new();
-        let mut data_bit_map = Vec::new();
-
-        // fill the data bit map to unused by default
-        for i in 0..nBlocks - inodeBlocks - 1 {
-            data_bit_map.push(false);
+        let disk = &mut self.disk;
+        let superBlock = Self::read_super_block(disk)?;
+        if superBlock.MagicNumber != MAGIC_NUMBER as u32 {
+            return Err(Error::BadMagic(superBlock.MagicNumber));
         }
 
-        for inodes in metaData.inodeTable.iter() {
-            for inode in inodes.iter() {
-                if inode.Valid == 1u32 {
-                    inode_bit_map.push(true);
+        let mut metaData = Self::read_meta_data(disk)?;
 
-                    // next, follow the direct blocks to see what data blocks it has
-                    for direct_ptr in inode.Direct.iter() {
-                        if *direct_ptr != 0u32 {
-                            data_bit_map[(*direct_ptr - inodeBlocks - 1) as usize] = true
-                        }
-                    }
+        // finish the last transaction if a crash interrupted it
+        if Self::replay_journal(disk, &superBlock)? > 0 {
+            metaData = Self::read_meta_data(disk)?;
+        }
+        let superBlock = metaData.superBlock;
 
-                    // also, does this inode has indirect block?
-                    if inode.Indirect != 0u32 { // if so, read the block and scan
-                        let mut tmp_block = Block::new();
-                        let mut tmp_data = tmp_block.data();
-                        disk.read(inode.Indirect as usize, &mut tmp_data);
-                        tmp_block.set_data(tmp_data);
-
-                        // interpret tmp_block as pointers, then scan
-                        let indirect_ptr_blocks = tmp_block.pointers();
-                        for ptr in indirect_ptr_blocks.iter() {
-                            if *ptr != 0u32 {
-                                data_bit_map[(*ptr - inodeBlocks - 1) as usize] = true
-                            }
-                        }
-                    }
-                } else {
-                    inode_bit_map.push(false);
-                }
+        // the bitmaps on disk can only be trusted after a clean unmount;
+        // otherwise (or on images without them) rebuild them from the inodes
+        let persisted = superBlock.BitmapBlocks > 0;
+        let (inode_bit_map, data_bit_map) = if persisted && superBlock.State == STATE_CLEAN {
+            Self::read_bitmaps(disk, &superBlock)?
+        } else {
+            let (inode_bit_map, data_bit_map) = Self::scan_bitmaps(disk, &metaData)?;
+            if persisted {
+                Self::write_bitmaps(disk, &superBlock, &inode_bit_map, &data_bit_map)?;
             }
-        }
+            (inode_bit_map, data_bit_map)
+        };
 
-        // println!("inode BIT MAP: {:?}", inode_bit_map);
-        // println!("data BIT MAP: {:?}", data_bit_map);
+        // until the next clean unmount, the bitmaps may lag behind the inodes
+        if persisted {
+            metaData.superBlock.State = STATE_DIRTY;
+            let mut block = Block::new();
+            block.set_superblock(metaData.superBlock);
+            disk.write_block(0, &block.data())?;
+            disk.flush()?;
+        }
 
+        self.metaData = Some(metaData);
         self.inodeBitMap = Some(inode_bit_map);
         self.dataBitMap = Some(data_bit_map);
-        self.disk = Some(disk.clone());
-        true
+        Ok(())
     }
 
-    pub fn create(&mut self) -> usize {
-        // locate free inode in inode table
-        match &mut self.inodeBitMap {
-            Some(i_bitmap) => {
-                let mut inumber = 0;
-                for bit_map in i_bitmap.iter() {
-                    if *bit_map == false {
-                        i_bitmap[inumber] = true;
-                        let mut inode = Inode::blank();
-                        if self.load_inode(inumber, &mut inode) {
-                            inode.Valid = 1;
-                            self.save_inode(inumber, &mut inode);
-
-                            match &mut self.metaData {
-                                Some(metaData) => {
-                                    metaData.superBlock.Inodes += 1;
-                                    self.save_super_block();
-                                },
-                                _ => {}
-                            }
-
-                            return inumber
-                        }
-                        return 0
-                    }
-                    inumber += 1;
-                }
-                inumber
-            },
-            None => 0
+    /// Marks the image clean and flushes the device. The filesystem is
+    /// unmounted even when that fails, but the image may then still be
+    /// marked dirty or not be durable.
+    pub fn unmount(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        if let Some(metaData) = &mut self.metaData {
+            if metaData.superBlock.BitmapBlocks > 0 {
+                metaData.superBlock.State = STATE_CLEAN;
+                result = self.save_super_block();
+            }
+            result = result.and(self.disk.flush());
         }
+        self.metaData = None;
+        self.inodeBitMap = None;
+        self.dataBitMap = None;
+        self.openFiles.clear();
+        self.readAhead = ReadAhead::default();
+        self.reservedBlocks.clear();
+        result
     }
 
-    pub fn remove(&mut self, inumber: usize) -> bool {
-        // load inode info
-        let mut inode = Inode::blank();
-        let inode_loaded = self.load_inode(inumber, &mut inode);
-
-        let inode_loaded = match &mut self.metaData {
-            Some(metaData) => {
-                match &mut self.disk {
-                    Some(disk) => {
-                        if inode_loaded {
-                            inode.Direct = [0; POINTERS_PER_INODE]; // free direct blocks
-                            inode.Indirect = 0;   // free indirect blocks
-                            inode.Valid = 0;     // set inode to invalid
-                            true
-                        } else {
-                            false
-                        }
-                    },
-                    _ => false
-                }
-            },
-            None => false
-        };
+    pub fn is_mounted(&self) -> bool {
+        self.metaData.is_some()
+    }
 
-        if inode_loaded {
-            // save inode
-            self.save_inode(inumber, &mut inode);
+    /// Allocates an empty regular file that is not linked into any
+    /// directory and returns its inode number.
+    pub fn create(&mut self) -> Result<usize> {
+        self.transaction(|fs| fs.allocate_inode(FILE_INODE))
+    }
 
-            match &mut self.metaData {
-                Some(metaData) => {
-                    metaData.superBlock.Inodes -= 1;
-                    self.save_super_block();
-                },
-                _ => {}
-            }
+    fn allocate_inode(&mut self, kind: u32) -> Result<usize> {
+        // locate free inode in inode table
+        let inumber = match &self.inodeBitMap {
+            Some(i_bitmap) => i_bitmap.iter().position(|used| !used).ok_or(Error::NoSpace)?,
+            None => return Err(Error::NotMounted)
+        };
 
-            // clear inode in inode table
-            match &mut self.inodeBitMap {
-                Some(ibitMap)=> {
-                    ibitMap[inumber] = false;
-                    self.save_inode_table();
-                },
-                _ => {}
-            };
+        let mut inode = Inode::blank();
+        inode.Valid = kind;
+        self.save_inode(inumber, &inode)?;
 
-            true
-        } else {
-            true
+        if let Some(i_bitmap) = &mut self.inodeBitMap {
+            i_bitmap[inumber] = true;
         }
+        self.save_bitmap_bit(inumber)?;
+        if let Some(metaData) = &mut self.metaData {
+            metaData.superBlock.Inodes += 1;
+        }
+        self.save_super_block()?;
 
+        Ok(inumber)
     }
 
-   pub fn stat(&mut self, inumber: usize) -> i64 {
-        let mut inode = Inode::blank();
-        if self.load_inode(inumber, &mut inode) {
-            inode.Size as i64
-        } else {
-            -1
-        }
+    /// Frees a regular file by inode number. Directory entries are not
+    /// touched; use `unlink` for files that have a path.
+    pub fn remove(&mut self, inumber: usize) -> Result<()> {
+        self.load_file_inode(inumber)?;
+        self.shrink_before_free(inumber)?;
+        self.transaction(|fs| {
+            // load inode info
+            let inode = fs.load_file_inode(inumber)?;
+            fs.free_inode(inumber, inode)
+        })
     }
 
-    fn read_from_block(
-        &mut self, block_num: usize, data_offset: usize, 
-        data: &mut [u8], length: usize, offset: usize
-    ) -> i64 {
-        // adjust length
-        let mut read_length = length;
-        let total_length = data_offset + length;
-        if total_length > Disk::BLOCK_SIZE {
-            read_length = length - (total_length - Disk::BLOCK_SIZE);
+    fn free_inode(&mut self, inumber: usize, mut inode: Inode) -> Result<()> {
+        // return every data block (and the indirect block) to the free pool
+        self.release_blocks(&mut inode, 0)?;
+        inode.Size = 0;
+        inode.Valid = FREE_INODE;     // set inode to invalid
+
+        // save inode
+        self.save_inode(inumber, &inode)?;
+
+        if let Some(metaData) = &mut self.metaData {
+            metaData.superBlock.Inodes = metaData.superBlock.Inodes.saturating_sub(1);
+        }
+        self.save_super_block()?;
+
+        // clear inode in inode table
+        if let Some(ibitMap) = &mut self.inodeBitMap {
+            ibitMap[inumber] = false;
+        }
 
-            if data_offset + read_length > data.len() {
-                read_length = data.len() - data_offset;
+        // descriptors still open on the file are closed: left alone they
+        // would follow the inode number to the next file created there
+        for slot in self.openFiles.iter_mut() {
+            if slot.is_some_and(|file| file.inumber == inumber) {
+                *slot = None;
             }
         }
+        self.save_bitmap_bit(inumber)
+    }
 
-        println!("R: {}", read_length);
+    /// Sets the size of the file to `new_size` bytes. Shrinking releases the
+    /// blocks past the new end; growing fills the new range with zeros.
+    pub fn truncate(&mut self, inumber: usize, new_size: usize) -> Result<()> {
+        let old_size = self.load_file_inode(inumber)?.Size as usize;
+        if new_size > old_size {
+            // a zero-length write at the new end zero-fills everything before it
+            if let Err(e) = self.write(inumber, &[], 0, new_size) {
+                self.truncate(inumber, old_size)?;
+                return Err(e);
+            }
+            return Ok(());
+        }
 
+        self.shrink_in_steps(inumber, new_size)?;
+
+        // keep the bytes past the end of file zeroed, so growing the file
+        // again never exposes old data. This waits for the new size to be
+        // committed: a crash before that would find them still in the file.
+        let block_offset = new_size % Disk::BLOCK_SIZE;
+        if block_offset != 0 {
+            let inode = self.load_file_inode(inumber)?;
+            let block_num = self.inode_block(&inode, new_size / Disk::BLOCK_SIZE)?;
+            if block_num != 0 {
+                self.write_to_block(&inode, block_num, false, block_offset, &ZEROS[block_offset..])?;
+            }
+        }
+        Ok(())
+    }
 
-        // sanity check
-        if offset > Disk::BLOCK_SIZE || data_offset > data.len(){
-            return -1;
+    /// Shrinks an inode of any kind to `new_size` bytes, from the end and
+    /// in transactions releasing at most WRITE_CHUNK_BLOCKS blocks each, so
+    /// that every one fits in the journal. A crash part way leaves the file
+    /// at one of the sizes in between.
+    fn shrink_in_steps(&mut self, inumber: usize, new_size: usize) -> Result<()> {
+        let old_size = self.load_valid_inode(inumber)?.Size as usize;
+        if new_size >= old_size {
+            return Ok(());
         }
 
-        // read data from disk
-        match &mut self.disk {
-            Some(disk) => {
-                let mut blk_data = [0; Disk::BLOCK_SIZE];
-                disk.read(block_num, &mut blk_data);
-                let mut bytes_read = 0;
-                let mut i = offset;
-                while i < Disk::BLOCK_SIZE {
-                    if bytes_read < read_length {
-                        data[data_offset + bytes_read] = blk_data[i];
-                        bytes_read += 1;
-                        i += 1;
-                    } else {
-                        return bytes_read as i64;
+        let keep_blocks = new_size.div_ceil(Disk::BLOCK_SIZE);
+        loop {
+            // cut before the last WRITE_CHUNK_BLOCKS blocks that are there;
+            // holes cost nothing to release
+            let inode = self.load_valid_inode(inumber)?;
+            let mut cut = (inode.Size as usize).div_ceil(Disk::BLOCK_SIZE);
+            for _ in 0..WRITE_CHUNK_BLOCKS {
+                match self.last_block_before(&inode, cut)? {
+                    Some(block_index) if block_index >= keep_blocks => cut = block_index,
+                    _ => {
+                        cut = keep_blocks;
+                        break;
                     }
                 }
-                return bytes_read as i64;
-            },
-            _ => {
-                return -1;
+            }
+
+            let size = if cut == keep_blocks { new_size } else { cut * Disk::BLOCK_SIZE };
+            self.transaction(|fs| {
+                let mut inode = fs.load_valid_inode(inumber)?;
+                fs.shrink_inode(inumber, &mut inode, size)
+            })?;
+            if size == new_size {
+                return Ok(());
             }
         }
     }
 
+    /// Run before an operation frees `inumber` in a single transaction:
+    /// releases all but WRITE_CHUNK_BLOCKS of its blocks beforehand, so
+    /// what is left to free fits in the journal.
+    fn shrink_before_free(&mut self, inumber: usize) -> Result<()> {
+        self.shrink_in_steps(inumber, WRITE_CHUNK_BLOCKS * Disk::BLOCK_SIZE)
+    }
+
+    /// The shrinking half of `truncate`, for any kind of inode.
+    fn shrink_inode(&mut self, inumber: usize, inode: &mut Inode, new_size: usize) -> Result<()> {
+        let keep_blocks = new_size.div_ceil(Disk::BLOCK_SIZE);
+        self.release_blocks(inode, keep_blocks)?;
+        inode.Size = new_size as u64;
+        self.save_inode(inumber, inode)
+    }
+
+    pub fn stat(&mut self, inumber: usize) -> Result<usize> {
+        let inode = self.load_valid_inode(inumber)?;
+        Ok(inode.Size as usize)
+    }
+
+    fn read_from_block(
+        &mut self, block_num: usize, data_offset: usize,
+        data: &mut [u8], length: usize, offset: usize
+    ) -> Result<usize> {
+        // read data from disk
+        let mut blk_data = [0; Disk::BLOCK_SIZE];
+        self.read_block(block_num, &mut blk_data)?;
+
+        // adjust length to what is left in both the block and the caller's buffer
+        let read_length = length
+            .min(Disk::BLOCK_SIZE - offset)
+            .min(data.len() - data_offset);
+        data[data_offset..data_offset + read_length]
+            .copy_from_slice(&blk_data[offset..offset + read_length]);
+        Ok(read_length)
+    }
+
     pub fn read(
-        &mut self, inumber: usize, 
+        &mut self, inumber: usize,
         data: &mut [u8], length: usize, offset: usize
-    ) -> i64 {
+    ) -> Result<usize> {
         // load inode info
-        let mut inode = Inode::blank();
-        if !self.load_inode(inumber, &mut inode) {
-            return -1;
-        }
+        let inode = self.load_valid_inode(inumber)?;
 
         // adjust length
-        let mut length = length;
-        let total_length = offset + length;
-        if total_length > inode.Size as usize {
-            length = length - (total_length - inode.Size as usize);
+        let size = inode.Size as usize;
+        if offset >= size {
+            return Ok(0);
         }
+        let length = length.min(size - offset).min(data.len());
 
-        // compute initial block and offset index
-        let mut block_index = (offset as f64 / Disk::BLOCK_SIZE as f64).floor() as usize;
-        let block_offset = offset % Disk::BLOCK_SIZE;
-
-        let mut block = Block::new();
-        let mut has_indirect_blk = false;
-        let mut indirect_blks: [u32; POINTERS_PER_BLOCK] = [0; POINTERS_PER_BLOCK];
+        let first = offset / Disk::BLOCK_SIZE;
+        let blocks = self.inode_blocks(&inode, first, (offset + length - 1) / Disk::BLOCK_SIZE + 1 - first)?;
         let mut bytes_read = 0;
-        let mut j = 0;
-        loop {
-            if bytes_read == length || j == length {
-                return bytes_read as i64;
+        let mut i = 0;
+        while bytes_read < length {
+            // compute current block and offset index
+            let position = offset + bytes_read;
+            let block_num = blocks[i];
+            if block_num == 0 {
+                return Err(Error::CorruptMetadata(
+                    format!("inode {} has no block for byte {}", inumber, position)
+                ));
             }
 
-            if block_index < POINTERS_PER_INODE {
-                if inode.Direct[block_index] > 0 {
-                    // use the block number for reading
-                    let r = self.read_from_block(
-                        inode.Direct[block_index] as usize, bytes_read as usize, 
-                        data, length - bytes_read, block_offset
-                    );
-                    if r > -1 {
-                        bytes_read += r as usize;
-                    } else {
-                        return -1;
-                    }
-                } else {
-                    return -1;
-                }
-            } else {
-                if !has_indirect_blk {
-                    match &mut self.disk {
-                        Some(disk) => {
-                            let mut blk_d = block.data();
-                            disk.read(inode.Indirect as usize, &mut blk_d);
-                            block.set_data(blk_d);
-                            indirect_blks = block.pointers();
-                            has_indirect_blk = true;
-                        },
-                        _ => { return -1; }
-                    }
-                }
-                
-                // use block index to get block number from indirect pointer
-                let indirect_blk_index = block_index - POINTERS_PER_INODE;
-                if indirect_blks[indirect_blk_index] > 0 {
-                    let r = self.read_from_block(
-                        indirect_blks[indirect_blk_index] as usize, bytes_read as usize, 
-                        data, length - bytes_read, block_offset
-                    );
-                    if r > -1 {
-                        bytes_read += r as usize;
-                    } else {
-                        return -1;
-                    }
-                } else {
-                    return -1;
+            // whole blocks that are also neighbours on disk are read in one go
+            if position.is_multiple_of(Disk::BLOCK_SIZE) && length - bytes_read >= Disk::BLOCK_SIZE {
+                let mut run = 1;
+                while i + run < blocks.len()
+                    && (run + 1) * Disk::BLOCK_SIZE <= length - bytes_read
+                    && blocks[i + run] == block_num + run
+                {
+                    run += 1;
                 }
+                self.read_blocks(block_num, &mut data[bytes_read..bytes_read + run * Disk::BLOCK_SIZE])?;
+                bytes_read += run * Disk::BLOCK_SIZE;
+                i += run;
+                continue;
             }
-            j += 1;
-            println!("BYTES INDEX: {}, {}", block_index, block_offset);
+
+            bytes_read += self.read_from_block(
+                block_num, bytes_read, data, length - bytes_read, position % Disk::BLOCK_SIZE
+            )?;
+            i += 1;
         }
 
-        -1
-        
+        // read-ahead is only a hint; the read itself has succeeded
+        let _ = self.read_ahead(inumber, &inode, offset, offset + bytes_read);
+        Ok(bytes_read)
     }
 
+    /// Writes `source` into a block of `inode` at `block_offset`; the
+    /// blocks of directories are metadata and go through the journal.
     fn write_to_block(
-        &mut self, block_num: usize, block_offset: usize, 
-        data: &mut [u8], length: usize, offset: usize
-    ) -> i64 {
-        let mut bytes_writen = 0;
-        if block_offset < Disk::BLOCK_SIZE && offset < data.len() { // this block is not filled yet
-            let disc = match &mut self.disk {
-                Some(disk) => disk,
-                _ => {
-                    return -1;
-                }
-            };
-            let mut disk = disc.clone(); // just to avoid compiler error about multiple mutable ref of disc
-
-            let mut block_offset = block_offset;
-            let mut length = length;
-            if offset + length > data.len() {
-                length = data.len() - offset;
-            }
-            let mut blk_data = [0; Disk::BLOCK_SIZE];
-            disk.read(block_num, &mut blk_data);
-            while block_offset < Disk::BLOCK_SIZE && bytes_writen < length {
-                blk_data[block_offset] = data[offset + bytes_writen];
-                bytes_writen += 1;
-                block_offset += 1;
-            }
-            disk.write(block_num, &mut blk_data);
-            return bytes_writen as i64;
+        &mut self, inode: &Inode, block_num: usize, fresh: bool,
+        block_offset: usize, source: &[u8]
+    ) -> Result<()> {
+        // a freshly allocated block starts out zeroed, and a whole-block
+        // overwrite does not need the old contents either
+        let mut blk_data = [0; Disk::BLOCK_SIZE];
+        if !fresh && source.len() < Disk::BLOCK_SIZE {
+            self.read_block(block_num, &mut blk_data)?;
+        }
+        blk_data[block_offset..block_offset + source.len()].copy_from_slice(source);
+        if inode.is_directory() {
+            self.write_metadata_block(block_num, &blk_data)
+        } else {
+            self.write_data_block(block_num, &blk_data)
         }
-        return bytes_writen as i64;
     }
 
-    pub fn write(&mut self, inumber: usize, data: &mut [u8], length: usize, offset: usize) -> i64 {
-        // load inode
-        let mut inode = Inode::blank();
-        if !self.load_inode(inumber, &mut inode) {
-            return -1;
+    /// Writes `length` bytes from `data` into the file at byte `offset`
+    /// (pwrite semantics): existing bytes are overwritten, writing past the
+    /// end grows the file, and any gap between the old end and `offset`
+    /// reads back as zeros. Running out of space part way through results in
+    /// a short write.
+    pub fn write(&mut self, inumber: usize, data: &[u8], length: usize, offset: usize) -> Result<usize> {
+        let data = &data[..length.min(data.len())];
+        let end = offset.checked_add(data.len()).ok_or(Error::FileTooLarge)?;
+        if end > MAX_FILE_BLOCKS * Disk::BLOCK_SIZE {
+            return Err(Error::FileTooLarge);
         }
 
-        let mut disc = match &mut self.disk {
-            Some(d) => d,
-            _ => { return -1; }
-         };
-         let mut disk = disc.clone();
-
-        // locate the last block in this inode
-        let mut bytes_writen = 0;
-        let block_index = (inode.Size as f64 / Disk::BLOCK_SIZE as f64).floor() as usize;
-        let mut block_offset: usize = (inode.Size as usize % Disk::BLOCK_SIZE) as usize;
-        let mut block_num: usize;
-        if block_index < POINTERS_PER_INODE {
-            if inode.Direct[block_index] > 0 {
-                block_num = inode.Direct[block_index] as usize;
+        // commit every WRITE_CHUNK_BLOCKS blocks (zero-filled gap included)
+        // as a transaction of its own, so none outgrows the journal
+        let mut position = offset.min(self.load_file_inode(inumber)?.Size as usize);
+        let mut bytes_written = 0;
+        while position < end {
+            let chunk_end = end.min((position / Disk::BLOCK_SIZE + WRITE_CHUNK_BLOCKS) * Disk::BLOCK_SIZE);
+            let (source, at) = if chunk_end <= offset {
+                (&data[..0], chunk_end)
             } else {
-                let blk = self.allocate_free_block(inumber);
-                if blk == -1 {
-                    return -1;
-                }
-                inode.Direct[block_index] = blk as u32;
-                block_num = blk as usize;
-            }
-        } else {
-            // make sure an indirect block has been allocated
-            if inode.Indirect == 0 {
-                let blk = self.allocate_free_block(inumber);
-                if blk == -1 {
-                    return -1;
+                (&data[position.max(offset) - offset..chunk_end - offset], position.max(offset))
+            };
+
+            let result = self.transaction(|fs| {
+                let mut inode = fs.load_file_inode(inumber)?;
+                fs.write_inode(inumber, &mut inode, source, at)
+            });
+            match result {
+                Ok(bytes) => {
+                    bytes_written += bytes;
+                    if bytes < source.len() {
+                        break;
+                    }
                 }
-                inode.Indirect = blk as u32;
-                self.save_inode(inumber, &mut inode);
-                disk.write(blk as usize, &mut [0; Disk::BLOCK_SIZE]);
+                Err(Error::NoSpace) if bytes_written > 0 => break,
+                Err(e) => return Err(e)
             }
+            position = chunk_end;
+        }
 
-            let mut block = Block::new();
-            let mut blk_d = block.data();
+        Ok(bytes_written)
+    }
 
+    /// The body of `write`, for callers that already hold the inode; this is
+    /// also how directories update their entries.
+    fn write_inode(&mut self, inumber: usize, inode: &mut Inode, data: &[u8], offset: usize) -> Result<usize> {
+        let end = offset.checked_add(data.len()).ok_or(Error::FileTooLarge)?;
+        if end > MAX_FILE_BLOCKS * Disk::BLOCK_SIZE {
+            return Err(Error::FileTooLarge);
+        }
 
-            disk.read(inode.Indirect as usize, &mut blk_d);
-            block.set_data(blk_d);
-            let mut ptrs = block.pointers();
+        // start at the old end of file when there is a gap to zero-fill
+        let mut position = offset.min(inode.Size as usize);
+        let mut result = Ok(());
+        while position < end {
+            let block_offset = position % Disk::BLOCK_SIZE;
+            let (block_num, fresh) = match self.allocate_inode_block(inode, position / Disk::BLOCK_SIZE) {
+                Ok(block) => block,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
 
-            if ptrs[block_index - POINTERS_PER_INODE] > 0 {
-               block_num = ptrs[block_index - POINTERS_PER_INODE] as usize;
+            let source = if position < offset {
+                let chunk = (Disk::BLOCK_SIZE - block_offset).min(offset - position);
+                &ZEROS[..chunk]
             } else {
-                let blk = self.allocate_free_block(inumber);
-                if blk == -1 {
-                    return -1;
-                }
-                ptrs[block_index - POINTERS_PER_INODE] = blk as u32;
-                block_num = blk as usize
+                let chunk = (Disk::BLOCK_SIZE - block_offset).min(end - position);
+                &data[position - offset..position - offset + chunk]
+            };
+            self.write_to_block(inode, block_num, fresh, block_offset, source)?;
+
+            position += source.len();
+            if position > inode.Size as usize {
+                inode.Size = position as u64;
             }
         }
 
-        // println!("BLOCK NUMBER: {}, b_offset: {}, offset: {}", block_num, block_offset, offset);
+        self.save_inode(inumber, inode)?;
+        let bytes_writen = position.saturating_sub(offset);
+        match result {
+            // running out of space part way through is a short write, not a failure
+            Err(Error::NoSpace) if bytes_writen > 0 => Ok(bytes_writen),
+            Err(e) => Err(e),
+            Ok(()) => Ok(bytes_writen)
+        }
+    }
 
-        // write block and copy to inode data
-        loop {
-            if bytes_writen < length && (offset + bytes_writen) < data.len() {
-                let bytes_wr = self.write_to_block(block_num, block_offset, data, length - bytes_writen, offset + bytes_writen);
-                println!("bytes_writen: {}", bytes_wr);
-                if bytes_wr == 0 {
-                    // allocate more blocks for data
-                    let blk = self.allocate_free_block(inumber);
-                    if blk == -1 {
-                        return -1;
-                    }
-                    block_num = blk as usize;
-                    block_offset = 0;
-                } else if bytes_wr == -1 {
-                    return -1;  // something bad happened
-                } else {
-                    bytes_writen += bytes_wr as usize;
-                    inode.Size += bytes_wr as u32;
-                    block_offset = (inode.Size as usize % Disk::BLOCK_SIZE) as usize;
-                }
-            } else {
-                self.save_inode(inumber, &mut inode);
-                println!("Total bytes writen: {}", bytes_writen);
-                return bytes_writen as i64;
-            }
+    // ****************** helper methods and functions *******************
+
+    fn disk(&mut self) -> Result<&mut D> {
+        if !self.is_mounted() {
+            return Err(Error::NotMounted);
         }
+        Ok(&mut self.disk)
+    }
 
-        bytes_writen as i64
+    fn check_block_size(disk: &D) -> Result<()> {
+        if disk.block_size() != Disk::BLOCK_SIZE {
+            return Err(Error::UnsupportedBlockSize(disk.block_size()));
+        }
+        Ok(())
     }
 
-    // ****************** helper methods and functions *******************
+    fn read_super_block(disk: &mut D) -> Result<Superblock> {
+        Self::check_block_size(disk)?;
 
-    fn read_meta_data(disk: &mut Disk<'a>) -> MetaData {
-        // read the super block
         let mut block = Block::new();
         let mut d = block.data();
-        disk.read(0, &mut d);
+        disk.read_block(0, &mut d)?;
         block.set_data(d);
-        let superBlock = block.superblock();
-        
+        Ok(block.superblock())
+    }
+
+    fn read_meta_data(disk: &mut D) -> Result<MetaData> {
+        // read the super block
+        let superBlock = Self::read_super_block(disk)?;
+        if superBlock.data_start() >= superBlock.data_end()
+            || superBlock.Blocks as usize > disk.block_count() {
+            return Err(Error::CorruptMetadata(format!(
+                "superblock describes {} inode and {} bitmap blocks out of {} blocks on a {} block disk",
+                superBlock.InodeBlocks, superBlock.BitmapBlocks, superBlock.Blocks, disk.block_count()
+            )));
+        }
+
         // read inode blocks ====> read the inode table
+        let mut block = Block::new();
         let mut inodeTable = Vec::new();
         for i in 0..superBlock.InodeBlocks {
             let mut d = block.data();
-            disk.read(1 + i as usize, &mut d);
+            disk.read_block(1 + i as usize, &mut d)?;
             block.set_data(d);
             inodeTable.push(block.inodes());
         }
 
-        MetaData {
+        Ok(MetaData {
             superBlock,
             inodeTable
-        }
+        })
     }
 
-    fn save_super_block(&mut self) -> bool {
-        match &mut self.metaData {
-            Some(metaData) => {
-                match &mut self.disk {
-                    Some(disk) => {
-                        let mut block = Block::new();
-                        block.set_superblock(metaData.superBlock);
-                        disk.write(0, &mut block.data());
+    /// Rebuilds the inode and data bitmaps by following every inode's blocks.
+    fn scan_bitmaps(disk: &mut D, metaData: &MetaData) -> Result<(Vec<bool>, Vec<bool>)> {
+        let nBlocks = metaData.superBlock.data_end();
+        let dataStart = metaData.superBlock.data_start();
+
+        let mut inode_bit_map = Vec::new();
+
+        // fill the data bit map to unused by default
+        let mut data_bit_map = vec![false; nBlocks - dataStart];
+        let mut mark_used = |ptr: u32| {
+            let ptr = ptr as usize;
+            if ptr < dataStart || ptr >= nBlocks {
+                return Err(Error::CorruptMetadata(
+                    format!("block pointer {} is outside the data region", ptr)
+                ));
+            }
+            data_bit_map[ptr - dataStart] = true;
+            Ok(())
+        };
 
-                        true
-                    },
-                    _ => false
+        for inodes in metaData.inodeTable.iter() {
+            for inode in inodes.iter() {
+                if inode.Valid != FREE_INODE {
+                    inode_bit_map.push(true);
+
+                    // next, follow the direct blocks to see what data blocks it has
+                    for direct_ptr in inode.Direct.iter() {
+                        Self::mark_tree(disk, *direct_ptr, 0, &mut mark_used)?;
+                    }
+
+                    // then every block reachable through the indirect trees
+                    for level in 1..=3 {
+                        Self::mark_tree(disk, inode.tree_root(level), level, &mut mark_used)?;
+                    }
+                } else {
+                    inode_bit_map.push(false);
                 }
-            },
-            None => false
+            }
+        }
+
+        Ok((inode_bit_map, data_bit_map))
+    }
+
+    /// The `index`-th bitmap block: inode bits come first, then one bit per
+    /// data block, lowest bit of each byte first.
+    fn bitmap_block(index: usize, inode_bit_map: &[bool], data_bit_map: &[bool]) -> [u8; Disk::BLOCK_SIZE] {
+        let mut data = [0; Disk::BLOCK_SIZE];
+        for i in 0..BITS_PER_BLOCK {
+            let bit = index * BITS_PER_BLOCK + i;
+            let used = match bit.checked_sub(inode_bit_map.len()) {
+                None => inode_bit_map[bit],
+                Some(block) => data_bit_map.get(block).copied().unwrap_or(false)
+            };
+            if used {
+                data[i / 8] |= 1 << (i % 8);
+            }
         }
+        data
     }
 
-    fn save_inode_table(&mut self) -> bool {
-        match &mut self.metaData {
-            Some(metaData) => {
-                match &mut self.disk {
-                    Some(disk) => {
-                        let mut block = Block::new();
+    fn read_bitmaps(disk: &mut D, superBlock: &Superblock) -> Result<(Vec<bool>, Vec<bool>)> {
+        let inodes = superBlock.inode_count();
+        let bits = inodes + superBlock.data_end() - superBlock.data_start();
+
+        let mut bit_map = Vec::with_capacity(bits);
+        let mut data = [0; Disk::BLOCK_SIZE];
+        for i in 0..superBlock.BitmapBlocks as usize {
+            disk.read_block(1 + superBlock.InodeBlocks as usize + i, &mut data)?;
+            for bit in 0..BITS_PER_BLOCK.min(bits - bit_map.len()) {
+                bit_map.push(data[bit / 8] & (1 << (bit % 8)) != 0);
+            }
+        }
+        if bit_map.len() < bits {
+            return Err(Error::CorruptMetadata(
+                format!("{} bitmap blocks cannot hold {} bits", superBlock.BitmapBlocks, bits)
+            ));
+        }
 
-                        // write in memory inodeTable to disk
-                        let mut i = 1;
-                        for inode_blk in metaData.inodeTable.iter() {
-                            block.set_inodes(*inode_blk);
-                            disk.write(i, &mut block.data());
-                            i += 1;
-                        }
+        let data_bit_map = bit_map.split_off(inodes);
+        Ok((bit_map, data_bit_map))
+    }
 
-                        true
-                    },
-                    _ => false
-                }
-            },
-            None => false
+    fn write_bitmaps(
+        disk: &mut D, superBlock: &Superblock,
+        inode_bit_map: &[bool], data_bit_map: &[bool]
+    ) -> Result<()> {
+        for i in 0..superBlock.BitmapBlocks as usize {
+            let data = Self::bitmap_block(i, inode_bit_map, data_bit_map);
+            disk.write_block(1 + superBlock.InodeBlocks as usize + i, &data)?;
         }
+        Ok(())
     }
 
-    fn load_inode(&mut self, inumber: usize, inode: &mut Inode) -> bool {
-        let mut blk = ((inumber / INODES_PER_BLOCK) as f64).ceil() as usize;
-        if blk == 0 {
-            blk = 1;
+    /// Writes the bitmap block holding `bit` (numbered as in `bitmap_block`)
+    /// after it changed in memory; a no-op on images without bitmaps.
+    fn save_bitmap_bit(&mut self, bit: usize) -> Result<()> {
+        let (metaData, inodeBitMap, dataBitMap) = match (&self.metaData, &self.inodeBitMap, &self.dataBitMap) {
+            (Some(metaData), Some(inodeBitMap), Some(dataBitMap)) => (metaData, inodeBitMap, dataBitMap),
+            _ => return Err(Error::NotMounted)
+        };
+        if metaData.superBlock.BitmapBlocks == 0 {
+            return Ok(());
         }
 
+        let index = bit / BITS_PER_BLOCK;
+        let data = Self::bitmap_block(index, inodeBitMap, dataBitMap);
+        let block_num = 1 + metaData.superBlock.InodeBlocks as usize + index;
+        self.write_metadata_block(block_num, &data)
+    }
+
+    fn save_super_block(&mut self) -> Result<()> {
+        let superBlock = match &self.metaData {
+            Some(metaData) => metaData.superBlock,
+            None => return Err(Error::NotMounted)
+        };
+
+        let mut block = Block::new();
+        block.set_superblock(superBlock);
+        self.write_metadata_block(0, &block.data())
+    }
+
+    /// Locates the inode block (and the row within it) that holds `inumber`.
+    fn inode_location(&self, inumber: usize) -> Result<(usize, usize)> {
+        let inodeBlocks = match &self.metaData {
+            Some(metaData) => metaData.superBlock.InodeBlocks as usize,
+            None => return Err(Error::NotMounted)
+        };
+
+        if inumber >= inodeBlocks * INODES_PER_BLOCK {
+            return Err(Error::InvalidInode(inumber));
+        }
+
+        Ok((1 + inumber / INODES_PER_BLOCK, inumber % INODES_PER_BLOCK))
+    }
+
+    fn load_inode(&mut self, inumber: usize) -> Result<Inode> {
+        let (blk, row_blk) = self.inode_location(inumber)?;
+
         // read the block
         let mut block = Block::new();
         let mut data = block.data();
-        match &mut self.disk {
-            Some(disk) => {
-                disk.read(blk, &mut data);
-                block.set_data(data);
-            },
-            _ => {
-                return false
-            }
-        };
+        self.read_block(blk, &mut data)?;
+        block.set_data(data);
 
         // interpret block as inodes and load into inode
-        let inodes = block.inodes();
-        let inode_r = inodes[inumber % INODES_PER_BLOCK];
-        inode.Valid = inode_r.Valid;
-        inode.Size = inode_r.Size;
-        inode.Direct = inode_r.Direct;
-        inode.Indirect = inode_r.Indirect;
-        true
+        Ok(block.inodes()[row_blk])
     }
 
-    fn save_inode(&mut self, inumber: usize, inode: &mut Inode) -> bool {
-        let mut blk = ((inumber / INODES_PER_BLOCK) as f64).ceil() as usize;
+    fn load_valid_inode(&mut self, inumber: usize) -> Result<Inode> {
+        let inode = self.load_inode(inumber)?;
+        if inode.Valid == FREE_INODE {
+            return Err(Error::InvalidInode(inumber));
+        }
+        Ok(inode)
+    }
 
-        if blk == 0 {
-            blk = 1;
+    /// Loads an inode whose contents may be changed directly, i.e. anything
+    /// but a directory.
+    fn load_file_inode(&mut self, inumber: usize) -> Result<Inode> {
+        let inode = self.load_valid_inode(inumber)?;
+        if inode.is_directory() {
+            return Err(Error::IsADirectory(format!("inode {}", inumber)));
         }
+        Ok(inode)
+    }
 
-        let row_blk = inumber % INODES_PER_BLOCK;
+    fn save_inode(&mut self, inumber: usize, inode: &Inode) -> Result<()> {
+        let (blk, row_blk) = self.inode_location(inumber)?;
 
         // read the block
         let mut block = Block::new();
         let mut data = block.data();
-        match &mut self.disk {
-            Some(disk) => {
-                disk.read(blk, &mut data);
-                block.set_data(data);
-
-                // interpret block as inodes and set inodes field
-                let mut inodes = block.inodes();
-
-                inodes[row_blk] = *inode;
-                block.set_inodes(inodes);
-                disk.write(blk, &mut block.data());
-
-                // update in memory inodeTable
-                match &mut self.metaData {
-                    Some(metaData) => {
-                        if metaData.inodeTable.len() < blk { // add inodes for block blk
-                            println!(" I don't think this section will ever execute");
-                            metaData.inodeTable.push(block.inodes());
-                        } else {
-                            metaData.inodeTable[blk - 1][row_blk] = *inode;
-                        }
-                    },
-                    _ => {}
-                };
-            },
-            _ => {
-                return false
+        self.read_block(blk, &mut data)?;
+        block.set_data(data);
+
+        // interpret block as inodes and set inodes field
+        let mut inodes = block.inodes();
+        inodes[row_blk] = *inode;
+        block.set_inodes(inodes);
+        self.write_metadata_block(blk, &block.data())?;
+
+        // update in memory inodeTable
+        if let Some(metaData) = &mut self.metaData {
+            metaData.inodeTable[blk - 1][row_blk] = *inode;
+        }
+
+        Ok(())
+    }
+
+    fn read_pointers(&mut self, block_num: usize) -> Result<[u32; POINTERS_PER_BLOCK]> {
+        let mut block = Block::new();
+        let mut block_data = block.data();
+        self.read_block(block_num, &mut block_data)?;
+        block.set_data(block_data);
+        Ok(block.pointers())
+    }
+
+    fn write_pointers(&mut self, block_num: usize, pointers: [u32; POINTERS_PER_BLOCK]) -> Result<()> {
+        let mut block = Block::new();
+        block.set_pointers(pointers);
+        self.write_metadata_block(block_num, &block.data())
+    }
+
+    /// Marks `ptr` and, for a pointer block `level` blocks above the data,
+    /// everything reachable from it as used.
+    fn mark_tree(
+        disk: &mut D, ptr: u32, level: u32,
+        mark_used: &mut dyn FnMut(u32) -> Result<()>
+    ) -> Result<()> {
+        if ptr == 0 {
+            return Ok(());
+        }
+        mark_used(ptr)?;
+        if level == 0 {
+            return Ok(());
+        }
+
+        let mut block = Block::new();
+        let mut data = block.data();
+        disk.read_block(ptr as usize, &mut data)?;
+        block.set_data(data);
+        for child in block.pointers().iter() {
+            Self::mark_tree(disk, *child, level - 1, mark_used)?;
+        }
+        Ok(())
+    }
+
+    /// Splits the `block_index`-th block of a file (past the direct blocks)
+    /// into the depth of the indirect tree holding it and the index within
+    /// that tree, or `None` if no inode can address it.
+    fn tree_position(block_index: usize) -> Option<(u32, usize)> {
+        let mut index = block_index - POINTERS_PER_INODE;
+        let mut span = 1;
+        for level in 1..=3 {
+            span *= POINTERS_PER_BLOCK;
+            if index < span {
+                return Some((level, index));
             }
+            index -= span;
+        }
+        None
+    }
+
+    /// The slot, within a pointer block `depth` levels above the data, that
+    /// leads to the `index`-th block of the tree.
+    fn tree_slot(index: usize, depth: u32) -> usize {
+        (index / POINTERS_PER_BLOCK.pow(depth)) % POINTERS_PER_BLOCK
+    }
+
+    /// Returns the data block holding the `block_index`-th block of the file,
+    /// or 0 if that part of the file has no block yet.
+    fn inode_block(&mut self, inode: &Inode, block_index: usize) -> Result<usize> {
+        if block_index < POINTERS_PER_INODE {
+            return Ok(inode.Direct[block_index] as usize);
+        }
+
+        let (level, index) = match Self::tree_position(block_index) {
+            Some(position) => position,
+            None => return Ok(0)
         };
 
-        true
+        // walk down the tree, one pointer block per level
+        let mut block_num = inode.tree_root(level) as usize;
+        for depth in (0..level).rev() {
+            if block_num == 0 {
+                return Ok(0);
+            }
+            block_num = self.read_pointers(block_num)?[Self::tree_slot(index, depth)] as usize;
+        }
+        Ok(block_num)
     }
 
-    fn allocate_free_block(&mut self, inumber: usize) -> i64 {
-        // load inode
-        let mut inode = Inode::blank();
-        if !self.load_inode(inumber, &mut inode) {
-            return -1;
+    /// The index of the last block of the file before `block_index` that
+    /// has a data block, skipping holes a pointer block at a time.
+    fn last_block_before(&mut self, inode: &Inode, block_index: usize) -> Result<Option<usize>> {
+        let mut bases = [0; 3];  // the first block index each tree holds
+        let mut base = POINTERS_PER_INODE;
+        for (level, tree_base) in bases.iter_mut().enumerate() {
+            *tree_base = base;
+            base += POINTERS_PER_BLOCK.pow(level as u32 + 1);
         }
 
-        let mut disk = match &mut self.disk {
-            Some(disk) => {
-                disk
-            },
-            _ => {
-                return -1;
+        for level in (1..=3).rev() {
+            let base = bases[level as usize - 1];
+            if block_index > base {
+                if let Some(index) = self.last_in_tree(inode.tree_root(level), level, block_index - base)? {
+                    return Ok(Some(base + index));
+                }
             }
-        };
+        }
+        Ok((0..block_index.min(POINTERS_PER_INODE)).rev().find(|i| inode.Direct[*i] != 0))
+    }
 
-        let get_free_block = |dataBitMap: Option<&Vec<bool>>| {
-            match dataBitMap {
-                Some(dataBitMap) => {
-                    // println!("DATABITMAP: {:?}", dataBitMap);
-                    let mut i = 0;
-                    loop {
-                        if i < dataBitMap.len() {
-                            if !dataBitMap[i] {
-                                break (i + 1) as i64;
-                            }
-                        } else {
-                            break -1;
-                        }
-                        i += 1;
-                    }
-                },
-                _ => -1
+    // `last_block_before` within the tree rooted at `root`, `level` pointer
+    // blocks deep, for tree indices before `end`
+    fn last_in_tree(&mut self, root: u32, level: u32, end: usize) -> Result<Option<usize>> {
+        if root == 0 {
+            return Ok(None);
+        }
+
+        let span = POINTERS_PER_BLOCK.pow(level - 1);
+        let ptrs = self.read_pointers(root as usize)?;
+        for slot in (0..end.div_ceil(span).min(POINTERS_PER_BLOCK)).rev() {
+            if ptrs[slot] == 0 {
+                continue;
             }
-        };
+            if level == 1 {
+                return Ok(Some(slot));
+            }
+            if let Some(index) = self.last_in_tree(ptrs[slot], level - 1, (end - slot * span).min(span))? {
+                return Ok(Some(slot * span + index));
+            }
+        }
+        Ok(None)
+    }
 
-    
-        let free_block = get_free_block(self.dataBitMap.as_ref());
+    /// `inode_block` for `count` consecutive blocks of the file, reading
+    /// each pointer block on the way only once.
+    fn inode_blocks(&mut self, inode: &Inode, first: usize, count: usize) -> Result<Vec<usize>> {
+        let mut blocks = Vec::with_capacity(count);
+        // the pointer block last read at each depth, by block number
+        let mut pointers: [Option<(usize, [u32; POINTERS_PER_BLOCK])>; 3] = [None; 3];
+        for block_index in first..first + count {
+            if block_index < POINTERS_PER_INODE {
+                blocks.push(inode.Direct[block_index] as usize);
+                continue;
+            }
 
-        if free_block > -1 {
-            match &mut self.dataBitMap {
-                Some(dataBitMap) => {
-                    dataBitMap[free_block as usize - 1] = true;
-                },
-                _ => { return -1; }
+            let (level, index) = match Self::tree_position(block_index) {
+                Some(position) => position,
+                None => {
+                    blocks.push(0);
+                    continue;
+                }
+            };
+            let mut block_num = inode.tree_root(level) as usize;
+            for depth in (0..level).rev() {
+                if block_num == 0 {
+                    break;
+                }
+                let memo = &mut pointers[depth as usize];
+                if memo.map(|(cached, _)| cached) != Some(block_num) {
+                    *memo = Some((block_num, self.read_pointers(block_num)?));
+                }
+                block_num = memo.unwrap().1[Self::tree_slot(index, depth)] as usize;
             }
+            blocks.push(block_num);
+        }
+        Ok(blocks)
+    }
 
-            match &mut self.metaData {
-                Some(metaData) => {
-                    let offset = metaData.superBlock.InodeBlocks;
-                    let free_block = free_block + offset as i64;
-                    // compute the inode number this free block belongs to
-                    let mut i = 0;
-                    loop {
-                        if i == POINTERS_PER_INODE {
-                            break;
-                        }
-                        if inode.Direct[i] == 0 {
-                            inode.Direct[i] = free_block as u32;
-                            self.save_inode(inumber, &mut inode);
-                            return free_block;
-                        }
-                        i += 1;
-                    }
+    /// Like `inode_block`, but allocates the data block (and any pointer
+    /// blocks on the way to it) if the file does not have one yet. The flag
+    /// tells whether the returned block was just allocated.
+    fn allocate_inode_block(&mut self, inode: &mut Inode, block_index: usize) -> Result<(usize, bool)> {
+        self.place_inode_block(inode, block_index, None)
+    }
 
-                    if inode.Indirect == 0 {
-                        // allocate new indirect block for more storage
-                        let free_indirect = get_free_block(self.dataBitMap.as_ref());
-                        if free_indirect < 0 {
-                            return -1;
-                        }
-                        inode.Indirect = free_indirect as u32;
-                        // self.save_inode(inumber, &mut inode);
-                    }
+    /// `allocate_inode_block`, except that a missing data block is `leaf`
+    /// (one of the `reservedBlocks`) when given, instead of a free one.
+    fn place_inode_block(&mut self, inode: &mut Inode, block_index: usize, leaf: Option<usize>) -> Result<(usize, bool)> {
+        if block_index < POINTERS_PER_INODE {
+            if inode.Direct[block_index] == 0 {
+                inode.Direct[block_index] = self.claim_block(leaf)? as u32;
+                return Ok((inode.Direct[block_index] as usize, true));
+            }
+            return Ok((inode.Direct[block_index] as usize, false));
+        }
 
-                    let mut block = Block::new();
-                    let mut block_data = block.data();
-                    disk.read(inode.Indirect as usize, &mut block_data);
-                    block.set_data(block_data);
-                    let mut indirect_pointers = block.pointers();
-
-                    let mut i = 0;
-                    loop {
-                        if i == POINTERS_PER_BLOCK {
-                            break;
-                        }
-
-                        if indirect_pointers[i] == 0 {
-                            indirect_pointers[i] = free_block as u32;
-                            block.set_pointers(indirect_pointers);
-                            disk.write(inode.Indirect as usize, &mut block.data());
-                            self.save_inode(inumber, &mut inode);
-                            return free_block;
-                        }
-                        i += 1;
-                    }
-                    return -1;
-                },
-                _ => {}
+        let (level, index) = Self::tree_position(block_index).ok_or(Error::FileTooLarge)?;
+
+        // make sure the root of the tree has been allocated
+        let root = inode.tree_root_mut(level);
+        if *root == 0 {
+            let blk = self.allocate_free_block()?;
+            self.write_pointers(blk, [0; POINTERS_PER_BLOCK])?;
+            *root = blk as u32;
+        }
+
+        let mut block_num = *root as usize;
+        for depth in (0..level).rev() {
+            let slot = Self::tree_slot(index, depth);
+            let mut ptrs = self.read_pointers(block_num)?;
+            if ptrs[slot] == 0 {
+                let blk = if depth == 0 { self.claim_block(leaf)? } else { self.allocate_free_block()? };
+                if depth > 0 {
+                    self.write_pointers(blk, [0; POINTERS_PER_BLOCK])?;
+                }
+                ptrs[slot] = blk as u32;
+                self.write_pointers(block_num, ptrs)?;
+                if depth == 0 {
+                    return Ok((blk, true));
+                }
+            }
+            block_num = ptrs[slot] as usize;
+        }
+        Ok((block_num, false))
+    }
+
+    /// Frees the blocks holding the `from`-th block of the file onwards and
+    /// clears their pointers in `inode`, releasing pointer blocks too once
+    /// nothing in them is in use.
+    fn release_blocks(&mut self, inode: &mut Inode, from: usize) -> Result<()> {
+        for ptr in inode.Direct.iter_mut().skip(from) {
+            if *ptr != 0 {
+                self.free_block(*ptr as usize)?;
+                *ptr = 0;
+            }
+        }
+
+        let mut base = POINTERS_PER_INODE;
+        let mut span = 1;
+        for level in 1..=3 {
+            span *= POINTERS_PER_BLOCK;
+            if from < base + span {
+                self.release_tree(inode.tree_root_mut(level), level, from.saturating_sub(base))?;
+            }
+            base += span;
+        }
+
+        Ok(())
+    }
+
+    /// Frees the data blocks from the `first`-th one onwards in the tree
+    /// rooted at `root`, `level` pointer blocks deep. The root itself is
+    /// freed (and cleared) when the whole tree goes.
+    fn release_tree(&mut self, root: &mut u32, level: u32, first: usize) -> Result<()> {
+        if *root == 0 {
+            return Ok(());
+        }
+
+        // data blocks below each pointer of this block
+        let span = POINTERS_PER_BLOCK.pow(level - 1);
+        let mut ptrs = self.read_pointers(*root as usize)?;
+        let mut changed = false;
+        for (i, ptr) in ptrs.iter_mut().enumerate() {
+            if *ptr == 0 || (i + 1) * span <= first {
+                continue;
+            }
+            if level == 1 {
+                self.free_block(*ptr as usize)?;
+                *ptr = 0;
+            } else {
+                self.release_tree(ptr, level - 1, first.saturating_sub(i * span))?;
             }
+            changed = true;
         }
 
-        -1
+        if first == 0 {
+            self.free_block(*root as usize)?;
+            *root = 0;
+        } else if changed {
+            self.write_pointers(*root as usize, ptrs)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a data block to the free pool.
+    fn free_block(&mut self, block_num: usize) -> Result<()> {
+        let (offset, inodes) = match &self.metaData {
+            Some(metaData) => (metaData.superBlock.data_start(), metaData.superBlock.inode_count()),
+            None => return Err(Error::NotMounted)
+        };
+
+        match &mut self.dataBitMap {
+            Some(dataBitMap) => {
+                if block_num < offset || block_num - offset >= dataBitMap.len() {
+                    return Err(Error::CorruptMetadata(
+                        format!("block pointer {} is outside the data region", block_num)
+                    ));
+                }
+                dataBitMap[block_num - offset] = false;
+            },
+            None => return Err(Error::NotMounted)
+        }
+        self.save_bitmap_bit(inodes + block_num - offset)
+    }
+
+    /// Claims `reserved` for good if given, the first free data block
+    /// otherwise, and returns its block number.
+    fn claim_block(&mut self, reserved: Option<usize>) -> Result<usize> {
+        let block_num = match reserved {
+            Some(block_num) => block_num,
+            None => return self.allocate_free_block()
+        };
+        let (offset, inodes) = match &self.metaData {
+            Some(metaData) => (metaData.superBlock.data_start(), metaData.superBlock.inode_count()),
+            None => return Err(Error::NotMounted)
+        };
+        if !self.reservedBlocks.remove(&block_num) {
+            return Err(Error::CorruptMetadata(format!("block {} was not reserved", block_num)));
+        }
+        // its bit has been set in memory since it was reserved
+        self.save_bitmap_bit(inodes + block_num - offset)?;
+        Ok(block_num)
+    }
+
+    /// Claims the first free data block and returns its block number.
+    fn allocate_free_block(&mut self) -> Result<usize> {
+        let (offset, inodes) = match &self.metaData {
+            Some(metaData) => (metaData.superBlock.data_start(), metaData.superBlock.inode_count()),
+            None => return Err(Error::NotMounted)
+        };
+
+        let free_block = match &mut self.dataBitMap {
+            Some(dataBitMap) => {
+                let free_block = dataBitMap.iter().position(|used| !used).ok_or(Error::NoSpace)?;
+                dataBitMap[free_block] = true;
+                free_block
+            },
+            None => return Err(Error::NotMounted)
+        };
+        self.save_bitmap_bit(inodes + free_block)?;
+        Ok(free_block + offset)
     }
 }
 
 pub mod prelude {
+    pub use super::build::*;
+    pub use super::cache::*;
+    pub use super::device::*;
     pub use super::disk::*;
+    pub use super::error::*;
+    pub use super::file::*;
+    pub use super::fsck::*;
+    pub use super::handle::*;
+    pub use super::memdisk::*;
+    pub use super::options::*;
+    pub use super::sfs::*;
+    pub use super::shared::*;
     pub use super::types::*;
 }
 
@@ -790,83 +1220,540 @@ pub mod prelude {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::crash::FailingDisk;
 
-    // #[test]
+    #[test]
     fn test_debug() {
-        let mut disk = Disk::new();
-        disk.open("./data/image.5", 5);
-        let mut data = [0; Disk::BLOCK_SIZE];
-        disk.write(0,&mut data);
+        let mut disk = MemDisk::new(5);
+        match FileSystem::debug(&mut disk) {
+            Err(Error::CorruptMetadata(_)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
 
-        assert_eq!(FileSystem::debug(&mut disk), ());
+        FileSystem::format(&mut disk).unwrap();
+        assert!(FileSystem::debug(&mut disk).is_ok());
     }
 
-    // #[test]
+    #[test]
     fn test_format() {
-        let mut disk = Disk::new();
-        disk.open("./data/image.20", 20);
-        assert_eq!(FileSystem::format(&mut disk), true);
-        FileSystem::debug(&mut disk);
+        let mut disk = MemDisk::new(20);
+        assert!(FileSystem::format(&mut disk).is_ok());
+
+        let superblock = FileSystem::read_super_block(&mut disk).unwrap();
+        assert_eq!(superblock.MagicNumber, MAGIC_NUMBER as u32);
+        assert_eq!(superblock.Blocks, 20);
+        assert_eq!(superblock.InodeBlocks, 2);
+        assert_eq!(superblock.Inodes, 1);
     }
 
-    // #[test]
+    #[test]
+    fn test_format_with_reserved_blocks() {
+        let mut disk = MemDisk::new(20);
+        let options = FormatOptions::new().inodes(64).reserved_blocks(4).label("scratch");
+        FileSystem::format_with(&mut disk, &options).unwrap();
+        for block_num in 16..20 {
+            disk.write(block_num, &[0xaa; Disk::BLOCK_SIZE]).unwrap();
+        }
+
+        // 13 data blocks: the root directory, an indirect block and 11 of the file
+        let mut fs = FileSystem::new(&mut disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.dataBitMap.as_ref().unwrap().len(), 13);
+        let inumber = fs.create().unwrap();
+        let length = 17 * Disk::BLOCK_SIZE;
+        assert_eq!(fs.write(inumber, &vec![1; length], length, 0).unwrap(), 11 * Disk::BLOCK_SIZE);
+        fs.unmount().unwrap();
+
+        let mut data = [0; Disk::BLOCK_SIZE];
+        for block_num in 16..20 {
+            disk.read(block_num, &mut data).unwrap();
+            assert!(data.iter().all(|b| *b == 0xaa));
+        }
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+        assert_eq!(FileSystem::read_super_block(&mut disk).unwrap().label(), "scratch");
+    }
+
+    #[test]
     fn test_mount() {
-        let mut disk = Disk::new();
-        disk.open("./data/image.20", 20);
-        let mut fs = FileSystem::new();
-        assert_eq!(fs.mount(&mut disk), true);
+        let mut disk = MemDisk::new(20);
+        let mut fs = FileSystem::new(MemDisk::new(20));
+        match fs.mount() {
+            Err(Error::BadMagic(0)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(disk);
+        assert!(fs.mount().is_ok());
+        assert_eq!(fs.inodeBitMap.as_ref().unwrap().len(), 2 * INODES_PER_BLOCK);
+        assert_eq!(fs.dataBitMap.as_ref().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn test_bitmaps_persist() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(200)).unwrap();
+        let inumber = fs.create().unwrap();
+        let length = (POINTERS_PER_INODE + 3) * Disk::BLOCK_SIZE;
+        fs.write(inumber, &vec![1; length], length, 0).unwrap();
+        fs.create().unwrap();
+        let inode_bit_map = fs.inodeBitMap.clone().unwrap();
+        let data_bit_map = fs.dataBitMap.clone().unwrap();
+
+        // a mounted filesystem is marked dirty on disk until it is unmounted
+        let superblock = FileSystem::read_super_block(&mut fs.disk).unwrap();
+        assert_eq!(superblock.State, STATE_DIRTY);
+        let mut disk = fs.into_disk().unwrap();
+        let superblock = FileSystem::read_super_block(&mut disk).unwrap();
+        assert_eq!(superblock.State, STATE_CLEAN);
+        assert_eq!(FileSystem::read_bitmaps(&mut disk, &superblock).unwrap(), (inode_bit_map.clone(), data_bit_map.clone()));
+
+        // after a clean unmount, mounting only reads the superblock, the
+        // inode table and the bitmaps, not the indirect block
+        let reads = disk.reads();
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.disk.reads() - reads, 1 + 1 + superblock.InodeBlocks as usize + 1);
+        assert_eq!(fs.inodeBitMap.as_ref().unwrap(), &inode_bit_map);
+        assert_eq!(fs.dataBitMap.as_ref().unwrap(), &data_bit_map);
     }
 
-    // #[test]
+    #[test]
+    fn test_unclean_mount_rescans() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        fs.write(inumber, &[1; 3 * Disk::BLOCK_SIZE], 3 * Disk::BLOCK_SIZE, 0).unwrap();
+        let data_bit_map = fs.dataBitMap.clone().unwrap();
+
+        // crash without unmounting, with the bitmap block lost as well
+        let mut disk = fs.disk;
+        let superblock = FileSystem::read_super_block(&mut disk).unwrap();
+        disk.write(1 + superblock.InodeBlocks as usize, &[0; Disk::BLOCK_SIZE]).unwrap();
+
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.dataBitMap.as_ref().unwrap(), &data_bit_map);
+
+        // and the rebuilt bitmaps are written back
+        let (_, on_disk) = FileSystem::read_bitmaps(&mut fs.disk, &superblock).unwrap();
+        assert_eq!(on_disk, data_bit_map);
+    }
+
+    #[test]
+    fn test_mount_without_bitmaps() {
+        // images from before the bitmaps were persisted have no bitmap blocks
+        let mut disk = MemDisk::new(20);
+        FileSystem::format(&mut disk).unwrap();
+        let mut superblock = FileSystem::read_super_block(&mut disk).unwrap();
+        superblock.BitmapBlocks = 0;
+        superblock.State = STATE_DIRTY;
+        let mut block = Block::new();
+        block.set_superblock(superblock);
+        disk.write(0, &block.data()).unwrap();
+
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.dataBitMap.as_ref().unwrap().len(), 20 - 1 - 2);
+        let inumber = fs.create().unwrap();
+        fs.write(inumber, b"data", 4, 0).unwrap();
+
+        // nothing is written where the bitmaps would be
+        let mut disk = fs.into_disk().unwrap();
+        let superblock = FileSystem::read_super_block(&mut disk).unwrap();
+        assert_eq!((superblock.BitmapBlocks, superblock.State), (0, STATE_DIRTY));
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_eq!(fs.stat(inumber).unwrap(), 4);
+    }
+
+    #[test]
     fn test_create_remove_inode() {
-        let mut disk = Disk::from_file("./data/image.100", 100);
-        let mut fs = FileSystem::from_disk(&mut disk);
-        let inode1 = fs.create();
-        let inode2 = fs.create();
-        let inode3 = fs.create();
-        let inode4 = fs.create();
-        println!("created INODES: {}, {}, {}, {}", inode1, inode2, inode3, inode4);
-        fs.info();
+        let mut fs = FileSystem::from_disk(MemDisk::new(100)).unwrap();
+        let inode1 = fs.create().unwrap();
+        let inode2 = fs.create().unwrap();
+        let inode3 = fs.create().unwrap();
+        // inode 0 is the root directory
+        assert_eq!((inode1, inode2, inode3), (1, 2, 3));
+
+        fs.remove(inode2).unwrap();
+        assert!(fs.stat(inode2).is_err());
+        assert_eq!(fs.create().unwrap(), inode2);
+        assert_eq!(fs.metaData.as_ref().unwrap().superBlock.Inodes, 4);
+    }
+
+    #[test]
+    fn test_remove_frees_blocks() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        let length = (POINTERS_PER_INODE + 2) * Disk::BLOCK_SIZE;
+        fs.write(inumber, &vec![1; length], length, 0).unwrap();
+
+        // 7 data blocks plus the indirect block, besides the root directory
+        let used = |fs: &FileSystem<MemDisk>| {
+            fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count()
+        };
+        assert_eq!(used(&fs), POINTERS_PER_INODE + 4);
+
+        fs.remove(inumber).unwrap();
+        assert_eq!(used(&fs), 1);
 
-        fs.remove(inode2);
-        println!("removed INODES: {}", inode2);
+        // the space is immediately reusable
+        let inumber = fs.create().unwrap();
+        let length = 17 * Disk::BLOCK_SIZE;
+        assert_eq!(fs.write(inumber, &vec![2; length], length, 0).unwrap(), 14 * Disk::BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_truncate_shrink() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        let length = (POINTERS_PER_INODE + 2) * Disk::BLOCK_SIZE;
+        let data: Vec<u8> = (0..length).map(|i| (i % 251) as u8 + 1).collect();
+        fs.write(inumber, &data, length, 0).unwrap();
+        let used = |fs: &FileSystem<MemDisk>| {
+            fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count()
+        };
+
+        // dropping into the indirect range keeps the indirect block
+        // (the root directory holds one more block throughout)
+        fs.truncate(inumber, (POINTERS_PER_INODE + 1) * Disk::BLOCK_SIZE - 1).unwrap();
+        assert_eq!(used(&fs), POINTERS_PER_INODE + 3);
+
+        // dropping below it releases the indirect block as well
+        fs.truncate(inumber, Disk::BLOCK_SIZE + 10).unwrap();
+        assert_eq!(fs.stat(inumber).unwrap(), Disk::BLOCK_SIZE + 10);
+        assert_eq!(used(&fs), 3);
+        assert_eq!(fs.load_inode(inumber).unwrap().Indirect, 0);
+
+        let mut data_r = vec![0; length];
+        assert_eq!(fs.read(inumber, &mut data_r, length, 0).unwrap(), Disk::BLOCK_SIZE + 10);
+        assert_eq!(&data_r[..Disk::BLOCK_SIZE + 10], &data[..Disk::BLOCK_SIZE + 10]);
 
-        fs.info()
+        fs.truncate(inumber, 0).unwrap();
+        assert_eq!(used(&fs), 1);
+        assert_eq!(fs.stat(inumber).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_truncate_grow() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        fs.write(inumber, b"0123456789", 10, 0).unwrap();
+
+        // shrinking then growing must not bring the old bytes back
+        fs.truncate(inumber, 4).unwrap();
+        fs.truncate(inumber, 2 * Disk::BLOCK_SIZE).unwrap();
+        assert_eq!(fs.stat(inumber).unwrap(), 2 * Disk::BLOCK_SIZE);
+
+        let mut data_r = vec![0xaa; 2 * Disk::BLOCK_SIZE];
+        fs.read(inumber, &mut data_r, 2 * Disk::BLOCK_SIZE, 0).unwrap();
+        assert_eq!(&data_r[..4], b"0123");
+        assert!(data_r[4..].iter().all(|b| *b == 0));
+
+        // growing beyond the free space fails and leaves the file untouched
+        match fs.truncate(inumber, 100 * Disk::BLOCK_SIZE) {
+            Err(Error::NoSpace) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        assert_eq!(fs.stat(inumber).unwrap(), 2 * Disk::BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_remove_invalid_inode() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        fs.remove(inumber).unwrap();
+
+        match fs.remove(inumber) {
+            Err(Error::InvalidInode(i)) => assert_eq!(i, inumber),
+            r => panic!("unexpected result {:?}", r)
+        }
+        assert_eq!(fs.metaData.as_ref().unwrap().superBlock.Inodes, 1);
+    }
+
+    #[test]
+    fn test_create_out_of_inodes() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(5)).unwrap();
+        for i in 1..INODES_PER_BLOCK {
+            assert_eq!(fs.create().unwrap(), i);
+        }
+        match fs.create() {
+            Err(Error::NoSpace) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
     }
 
     fn to_mut_data (txt: &str) -> [u8; 4096] {
         let data = txt.as_bytes();
         let mut d = [0; 4096];
-        let mut j = 0;
-        for i in data.iter() {
-            d[j] = *i;
-            j += 1;
-        }
+        d[..data.len()].copy_from_slice(data);
         d
     }
 
     #[test]
     fn test_fs_read_write() {
-        let mut disk = Disk::from_file("./data/image.100", 100);
-        let mut fs = FileSystem::from_disk(&mut disk);
-        let inode1 = fs.create();
-        let mut data = to_mut_data("Hello, World this is great string");
+        let mut fs = FileSystem::from_disk(MemDisk::new(100)).unwrap();
+        let inode1 = fs.create().unwrap();
+        let data = to_mut_data("Hello, World this is great string");
         let mut data_r = [0; 4096];
-        let mut i = 0;
-        
-        let b1 = fs.write(inode1, &mut data, 33, 0);
-        FileSystem::debug(&mut disk);
-        fs.info();
 
-        let b2 = fs.read(inode1, &mut data_r, 33, 0);
+        let b1 = fs.write(inode1, &data, 33, 0).unwrap();
+        let b2 = fs.read(inode1, &mut data_r, 33, 0).unwrap();
+
+        assert_eq!(b1, 33);
+        assert_eq!(b2, 33);
+        assert_eq!(&data_r[..], &data[..]);
+    }
+
+    #[test]
+    fn test_fs_read_write_indirect() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(100)).unwrap();
+        let inumber = fs.create().unwrap();
+        let data: Vec<u8> = (0..(POINTERS_PER_INODE + 3) * Disk::BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let length = data.len();
+
+        assert_eq!(fs.write(inumber, &data, length, 0).unwrap(), length);
+        assert_eq!(fs.stat(inumber).unwrap(), length);
+
+        let mut data_r = vec![0; length];
+        assert_eq!(fs.read(inumber, &mut data_r, length, 0).unwrap(), length);
+        assert_eq!(data_r, data);
+
+        // reads starting part way through a block
+        let mut tail = vec![0; 5000];
+        assert_eq!(fs.read(inumber, &mut tail, 5000, length - 4000).unwrap(), 4000);
+        assert_eq!(&tail[..4000], &data[length - 4000..]);
+    }
+
+    #[test]
+    fn test_fs_read_write_double_indirect() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(1400)).unwrap();
+        let inumber = fs.create().unwrap();
+        let blocks = POINTERS_PER_INODE + POINTERS_PER_BLOCK + 20;
+        let data: Vec<u8> = (0..blocks * Disk::BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let length = data.len();
+
+        assert_eq!(fs.write(inumber, &data, length, 0).unwrap(), length);
+        let mut data_r = vec![0; length];
+        assert_eq!(fs.read(inumber, &mut data_r, length, 0).unwrap(), length);
+        assert_eq!(data_r, data);
+
+        // data, the indirect block, the double indirect block and one block
+        // of pointers below it, besides the root directory
+        let used = |fs: &FileSystem<MemDisk>| {
+            fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count()
+        };
+        assert_eq!(used(&fs), blocks + 1 + 3 + 1);
+
+        // mounting again finds every one of them
+        let mut fs = FileSystem::new(fs.into_disk().unwrap());
+        fs.mount().unwrap();
+        assert_eq!(used(&fs), blocks + 1 + 3 + 1);
+
+        // shrinking back into the indirect range drops the whole double tree
+        fs.truncate(inumber, (POINTERS_PER_INODE + 1) * Disk::BLOCK_SIZE).unwrap();
+        assert_eq!(fs.load_inode(inumber).unwrap().DoubleIndirect, 0);
+        assert_eq!(used(&fs), POINTERS_PER_INODE + 1 + 1 + 1);
+
+        fs.remove(inumber).unwrap();
+        assert_eq!(used(&fs), 1);
+    }
+
+    #[test]
+    fn test_triple_indirect_blocks() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        let inumber = fs.create().unwrap();
+        let mut inode = fs.load_inode(inumber).unwrap();
+        let used = |fs: &FileSystem<MemDisk>| {
+            fs.dataBitMap.as_ref().unwrap().iter().filter(|used| **used).count()
+        };
+
+        // the first and last block reachable through the triple indirect
+        // pointer share its root, but nothing below it
+        let first = POINTERS_PER_INODE + POINTERS_PER_BLOCK + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK;
+        let last = MAX_FILE_BLOCKS - 1;
+        let (first_block, fresh) = fs.allocate_inode_block(&mut inode, first).unwrap();
+        assert!(fresh);
+        let (last_block, _) = fs.allocate_inode_block(&mut inode, last).unwrap();
+        assert_eq!(fs.allocate_inode_block(&mut inode, last).unwrap(), (last_block, false));
+        assert_eq!(fs.inode_block(&inode, first).unwrap(), first_block);
+        assert_eq!(fs.inode_block(&inode, last).unwrap(), last_block);
+        assert_eq!(fs.inode_block(&inode, last - 1).unwrap(), 0);
+        assert_eq!(used(&fs), 1 + 1 + 2 * 3);
+
+        match fs.allocate_inode_block(&mut inode, MAX_FILE_BLOCKS) {
+            Err(Error::FileTooLarge) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+
+        // the tree is found on mount as well
+        inode.Size = (MAX_FILE_BLOCKS * Disk::BLOCK_SIZE) as u64;
+        fs.save_inode(inumber, &inode).unwrap();
+        let mut fs = FileSystem::new(fs.into_disk().unwrap());
+        fs.mount().unwrap();
+        assert_eq!(used(&fs), 1 + 1 + 2 * 3);
+
+        // cutting the file just past the first block keeps only its path
+        fs.truncate(inumber, first * Disk::BLOCK_SIZE + 1).unwrap();
+        assert_eq!(used(&fs), 1 + 1 + 3);
+        fs.remove(inumber).unwrap();
+        assert_eq!(used(&fs), 1);
+    }
+
+    #[test]
+    fn test_fs_write_too_large() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        match fs.write(inumber, b"x", 1, MAX_FILE_BLOCKS * Disk::BLOCK_SIZE) {
+            Err(Error::FileTooLarge) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+
+        // an end of file past usize::MAX is just as large
+        match fs.write(inumber, b"x", 1, usize::MAX) {
+            Err(Error::FileTooLarge) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        assert_eq!(fs.stat(inumber).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_fs_overwrite_in_place() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        fs.write(inumber, b"Hello, World", 12, 0).unwrap();
+
+        assert_eq!(fs.write(inumber, b"there", 5, 7).unwrap(), 5);
+        assert_eq!(fs.stat(inumber).unwrap(), 12);
+
+        let mut data_r = [0; 12];
+        fs.read(inumber, &mut data_r, 12, 0).unwrap();
+        assert_eq!(&data_r, b"Hello, there");
+
+        // an overwrite running past the end extends the file
+        assert_eq!(fs.write(inumber, b"everyone", 8, 7).unwrap(), 8);
+        assert_eq!(fs.stat(inumber).unwrap(), 15);
+    }
+
+    #[test]
+    fn test_fs_write_past_end() {
+        // format only clears the inode table, so data blocks start out dirty
+        let disk = MemDisk::new(20);
+        for blocknum in 0..20 {
+            disk.write(blocknum, &[0xff; Disk::BLOCK_SIZE]).unwrap();
+        }
+        let mut fs = FileSystem::from_disk(disk).unwrap();
+
+        let inumber = fs.create().unwrap();
+        fs.write(inumber, b"head", 4, 0).unwrap();
+        let offset = 2 * Disk::BLOCK_SIZE + 10;
+        assert_eq!(fs.write(inumber, b"tail", 4, offset).unwrap(), 4);
+        assert_eq!(fs.stat(inumber).unwrap(), offset + 4);
+
+        let mut data_r = vec![0xaa; offset + 4];
+        assert_eq!(fs.read(inumber, &mut data_r, offset + 4, 0).unwrap(), offset + 4);
+        assert_eq!(&data_r[..4], b"head");
+        assert!(data_r[4..offset].iter().all(|b| *b == 0));
+        assert_eq!(&data_r[offset..], b"tail");
+    }
+
+    #[test]
+    fn test_fs_short_write_when_full() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(5)).unwrap();
+        let inumber = fs.create().unwrap();
+        let data = vec![1; 4 * Disk::BLOCK_SIZE];
+
+        // 5 blocks leave 2 data blocks after the superblock, inode table and
+        // bitmap, and the root directory takes one of them
+        assert_eq!(fs.write(inumber, &data, data.len(), 0).unwrap(), Disk::BLOCK_SIZE);
+        assert_eq!(fs.stat(inumber).unwrap(), Disk::BLOCK_SIZE);
+        match fs.write(inumber, &data, 1, Disk::BLOCK_SIZE) {
+            Err(Error::NoSpace) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
 
-        let str1 = std::str::from_utf8(&data_r).unwrap().to_string();
-        let str2 = std::str::from_utf8(&data).unwrap().to_string();
+    #[test]
+    fn test_fs_persists_across_mounts() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        let inumber = fs.create().unwrap();
+        let data = to_mut_data("persistent");
+        fs.write(inumber, &data, 10, 0).unwrap();
+
+        let mut fs = FileSystem::new(fs.into_disk().unwrap());
+        fs.mount().unwrap();
+        let mut data_r = [0; 10];
+        assert_eq!(fs.read(inumber, &mut data_r, 10, 0).unwrap(), 10);
+        assert_eq!(&data_r, b"persistent");
+    }
+
+    #[test]
+    fn test_not_mounted() {
+        let mut fs = FileSystem::new(MemDisk::new(5));
+        match fs.create() {
+            Err(Error::NotMounted) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match fs.stat(0) {
+            Err(Error::NotMounted) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
 
-        println!("STRING 1: {}, STRING 2: {}", str1, str2);
-        println!("bytes writen: {}, bytes read: {}", b1, b2);
-    
-        assert_eq!(str1, str2);
+    // a device with blocks the filesystem cannot be laid out on
+    struct SmallBlocks;
 
+    impl BlockDevice for SmallBlocks {
+        fn block_size(&self) -> usize { 512 }
+        fn block_count(&self) -> usize { 100 }
+        fn read_block(&mut self, _: usize, _: &mut [u8]) -> Result<()> { Ok(()) }
+        fn write_block(&mut self, _: usize, _: &[u8]) -> Result<()> { Ok(()) }
+        fn flush(&mut self) -> Result<()> { Ok(()) }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unsupported_block_size() {
+        match FileSystem::format(&mut SmallBlocks) {
+            Err(Error::UnsupportedBlockSize(512)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        match FileSystem::new(SmallBlocks).mount() {
+            Err(Error::UnsupportedBlockSize(512)) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn test_mount_twice() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(20)).unwrap();
+        match fs.mount() {
+            Err(Error::AlreadyMounted) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+        fs.unmount().unwrap();
+        assert!(!fs.is_mounted());
+    }
+
+    #[test]
+    fn test_unmount_reports_failures() {
+        let mut disk = MemDisk::new(20);
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(FailingDisk { disk, writes_left: usize::MAX });
+        fs.mount().unwrap();
+
+        // the superblock cannot be marked clean, but the filesystem is
+        // unmounted all the same
+        fs.disk.writes_left = 0;
+        assert!(matches!(fs.unmount(), Err(Error::Io(_))));
+        assert!(!fs.is_mounted());
+        assert!(fs.unmount().is_ok());
+
+        fs.disk.writes_left = usize::MAX;
+        fs.mount().unwrap();
+        fs.disk.writes_left = 0;
+        assert!(matches!(fs.into_disk(), Err(Error::Io(_))));
+    }
+}
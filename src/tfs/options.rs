@@ -0,0 +1,246 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InodeCount {
+    Default,               // 10% of the blocks, as SimpleFS does
+    Exactly(usize),        // rounded up to whole inode blocks
+    BytesPerInode(usize)   // one inode for every so many bytes of disk
+}
+
+/// How `FileSystem::format_with` lays out a new filesystem. What is not
+/// set keeps the layout of `FileSystem::format`: 10% of the blocks for
+/// inodes, no label, no reserved blocks and no UUID.
+///
+/// ```ignore
+/// let options = FormatOptions::new().bytes_per_inode(16384).label("photos");
+/// FileSystem::format_with(&mut disk, &options)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct FormatOptions {
+    inodes: InodeCount,
+    label: String,
+    reserved_blocks: usize,
+    uuid: [u8; 16]
+}
+
+impl FormatOptions {
+    pub fn new() -> FormatOptions {
+        FormatOptions {
+            inodes: InodeCount::Default,
+            label: String::new(),
+            reserved_blocks: 0,
+            uuid: [0; 16]
+        }
+    }
+
+    /// Room for at least `inodes` inodes, for many small files.
+    pub fn inodes(mut self, inodes: usize) -> Self {
+        self.inodes = InodeCount::Exactly(inodes);
+        self
+    }
+
+    /// One inode per `bytes` bytes of disk: larger values leave more room
+    /// for data, for fewer and larger files.
+    pub fn bytes_per_inode(mut self, bytes: usize) -> Self {
+        self.inodes = InodeCount::BytesPerInode(bytes);
+        self
+    }
+
+    /// A volume label of up to `LABEL_MAX` bytes.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    /// Blocks at the end of the disk to keep out of the filesystem.
+    pub fn reserved_blocks(mut self, blocks: usize) -> Self {
+        self.reserved_blocks = blocks;
+        self
+    }
+
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// The superblock of a fresh filesystem on a disk of `blocks` blocks.
+    pub(super) fn superblock(&self, blocks: usize) -> Result<Superblock> {
+        if self.label.len() > LABEL_MAX || self.label.contains('\0') {
+            return Err(Error::InvalidFormat(format!("label {:?} is not up to {} bytes of text", self.label, LABEL_MAX)));
+        }
+        let mut label = [0; LABEL_MAX];
+        label[..self.label.len()].copy_from_slice(self.label.as_bytes());
+        let too_large = || Error::InvalidFormat(format!("{} blocks do not fit in a superblock", blocks));
+        let blocks32 = u32::try_from(blocks).map_err(|_| too_large())?;
+
+        let inode_blocks = match self.inodes {
+            InodeCount::Default => (blocks as f64 * 0.1).ceil() as usize,
+            InodeCount::Exactly(0) | InodeCount::BytesPerInode(0) => {
+                return Err(Error::InvalidFormat("a filesystem needs at least one inode".to_string()));
+            }
+            InodeCount::Exactly(inodes) => inodes.div_ceil(INODES_PER_BLOCK),
+            InodeCount::BytesPerInode(bytes) => {
+                let disk_bytes = blocks.checked_mul(Disk::BLOCK_SIZE).ok_or_else(too_large)?;
+                disk_bytes.div_ceil(bytes).div_ceil(INODES_PER_BLOCK)
+            }
+        };
+
+        // enough bitmap blocks for every inode and block, and a journal on
+        // disks big enough to afford one; an inode table that outgrows the
+        // counts cannot fit on the disk either
+        let bitmap_blocks = inode_blocks.checked_mul(INODES_PER_BLOCK)
+            .and_then(|inodes| inodes.checked_add(blocks))
+            .ok_or(Error::NoSpace)?
+            .div_ceil(BITS_PER_BLOCK);
+        let journal_blocks = if blocks >= JOURNAL_MIN_BLOCKS { JOURNAL_BLOCKS } else { 0 };
+        let superblock = Superblock {
+            MagicNumber: MAGIC_NUMBER as u32,
+            Blocks: blocks32,
+            InodeBlocks: u32::try_from(inode_blocks).map_err(|_| Error::NoSpace)?,
+            Inodes: 1,
+            BitmapBlocks: u32::try_from(bitmap_blocks).map_err(|_| Error::NoSpace)?,
+            State: STATE_CLEAN,
+            JournalBlocks: journal_blocks as u32,
+            ReservedBlocks: self.reserved_blocks.min(blocks) as u32,
+            Uuid: self.uuid,
+            Label: label
+        };
+
+        // the root directory needs a data block
+        if superblock.data_start() >= superblock.data_end() {
+            return Err(Error::NoSpace);
+        }
+        Ok(superblock)
+    }
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads a size for a command line: a number of blocks, or of bytes when
+/// it ends with K, M or G.
+pub fn parse_size(text: &str) -> Option<usize> {
+    let (digits, unit) = match text.chars().last()? {
+        'K' | 'k' => (&text[..text.len() - 1], 1 << 10),
+        'M' | 'm' => (&text[..text.len() - 1], 1 << 20),
+        'G' | 'g' => (&text[..text.len() - 1], 1 << 30),
+        _ => return text.parse().ok()
+    };
+    let bytes = digits.parse::<usize>().ok()?.checked_mul(unit)?;
+    Some(bytes / Disk::BLOCK_SIZE)
+}
+
+/// A random (version 4) UUID, without pulling in a crate for it: the
+/// randomness comes from the keys std seeds its hash maps with.
+pub fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0; 16];
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    for (i, half) in uuid.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now);
+        hasher.write_usize(i);
+        half.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// Writes a UUID the usual way, as 8-4-4-4-12 hex digits.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut text = String::with_capacity(36);
+    for (i, byte) in uuid.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            text.push('-');
+        }
+        let _ = write!(text, "{:02x}", byte);
+    }
+    text
+}
+
+/// Reads a UUID written as by `format_uuid` (dashes are optional).
+pub fn parse_uuid(text: &str) -> Result<[u8; 16]> {
+    let digits: Vec<u8> = text.bytes().filter(|c| *c != b'-').collect();
+    let invalid = || Error::InvalidFormat(format!("invalid UUID {:?}", text));
+    if digits.len() != 32 {
+        return Err(invalid());
+    }
+
+    let mut uuid = [0; 16];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inode_counts() {
+        let layout = |options: FormatOptions| options.superblock(1000).unwrap();
+        assert_eq!(layout(FormatOptions::new()).InodeBlocks, 100);
+        assert_eq!(layout(FormatOptions::new().inodes(64)).InodeBlocks, 1);
+        assert_eq!(layout(FormatOptions::new().inodes(65)).InodeBlocks, 2);
+
+        // 4 MB at one inode per 64 KB is 63 inodes, one block of them
+        assert_eq!(layout(FormatOptions::new().bytes_per_inode(65536)).InodeBlocks, 1);
+        assert_eq!(layout(FormatOptions::new().bytes_per_inode(4096)).InodeBlocks, 16);
+
+        assert!(matches!(FormatOptions::new().inodes(0).superblock(1000), Err(Error::InvalidFormat(_))));
+        assert!(matches!(FormatOptions::new().inodes(64 * 1000).superblock(1000), Err(Error::NoSpace)));
+        assert!(matches!(FormatOptions::new().inodes(usize::MAX).superblock(1000), Err(Error::NoSpace)));
+        assert!(matches!(FormatOptions::new().inodes(1 << 40).superblock(1000), Err(Error::NoSpace)));
+        assert!(matches!(FormatOptions::new().superblock(1 << 32), Err(Error::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_label_and_reserved_blocks() {
+        let superblock = FormatOptions::new().label("backup").reserved_blocks(50).superblock(1000).unwrap();
+        assert_eq!(superblock.label(), "backup");
+        assert_eq!(superblock.data_end(), 950);
+
+        let long = "x".repeat(LABEL_MAX + 1);
+        assert!(matches!(FormatOptions::new().label(&long).superblock(1000), Err(Error::InvalidFormat(_))));
+        assert!(matches!(FormatOptions::new().reserved_blocks(900).superblock(1000), Err(Error::NoSpace)));
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(parse_size("300"), Some(300));
+        assert_eq!(parse_size("8M"), Some(2048));
+        assert_eq!(parse_size("1g"), Some(262144));
+        assert_eq!(parse_size("6K"), Some(1));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+    }
+
+    #[test]
+    fn test_uuid_text() {
+        let uuid = [
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0,
+            0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef
+        ];
+        let text = format_uuid(&uuid);
+        assert_eq!(text, "12345678-9abc-def0-0123-456789abcdef");
+        assert_eq!(parse_uuid(&text).unwrap(), uuid);
+        assert_eq!(parse_uuid("123456789ABCDEF00123456789ABCDEF").unwrap(), uuid);
+        assert!(parse_uuid("1234").is_err());
+        assert_ne!(random_uuid(), random_uuid());
+        assert_eq!(random_uuid()[6] >> 4, 4);
+        assert!(parse_uuid("12345678-9abc-def0-0123-456789abcdeg").is_err());
+    }
+}
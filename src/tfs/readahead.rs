@@ -0,0 +1,172 @@
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::Result;
+use super::types::*;
+use super::FileSystem;
+
+// blocks read ahead once reads look sequential, doubling on every
+// sequential read up to the maximum
+const READ_AHEAD_MIN: usize = 4;
+const READ_AHEAD_MAX: usize = 64;
+
+/// Tracks the last file read, to tell sequential reads from random ones.
+#[derive(Default)]
+pub struct ReadAhead {
+    inumber: usize,
+    next: usize,    // byte offset a sequential read would start at
+    window: usize,  // blocks to read ahead; 0 while reads look random
+    ahead: usize    // first block index of the file not prefetched yet
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Called after reading `offset..end` of `inumber`. If reads of the
+    /// file are sequential, asks the device to prefetch the blocks after
+    /// `end`. Finding them walks the pointer blocks, so an indirect block
+    /// is read before the data that needs it.
+    pub(super) fn read_ahead(&mut self, inumber: usize, inode: &Inode, offset: usize, end: usize) -> Result<()> {
+        let state = &mut self.readAhead;
+        let sequential = offset == 0 || (state.inumber == inumber && offset == state.next);
+        if !sequential {
+            *state = ReadAhead { inumber, next: end, window: 0, ahead: 0 };
+            return Ok(());
+        }
+        if state.inumber != inumber || offset == 0 {
+            state.ahead = 0;
+        }
+        state.inumber = inumber;
+        state.next = end;
+        state.window = (state.window * 2).clamp(READ_AHEAD_MIN, READ_AHEAD_MAX);
+
+        let file_blocks = (inode.Size as usize).div_ceil(Disk::BLOCK_SIZE);
+        let next_block = end.div_ceil(Disk::BLOCK_SIZE);
+        let first = state.ahead.max(next_block);
+        let last = (next_block + state.window).min(file_blocks);
+        if first >= last {
+            return Ok(());
+        }
+        state.ahead = last;
+
+        // one prefetch per run of blocks that are neighbours on disk
+        let blocks = self.inode_blocks(inode, first, last - first)?;
+        let mut start = 0;
+        while start < blocks.len() {
+            let mut run = 1;
+            while start + run < blocks.len() && blocks[start + run] == blocks[start] + run {
+                run += 1;
+            }
+            if blocks[start] != 0 {
+                self.disk.prefetch(blocks[start], run)?;
+            }
+            start += run;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cache::BlockCache;
+    use super::super::memdisk::MemDisk;
+
+    fn cached_file(size: usize) -> (FileSystem<BlockCache<MemDisk>>, usize, Vec<u8>) {
+        let mut disk = MemDisk::new(400);
+        FileSystem::format(&mut disk).unwrap();
+        let mut fs = FileSystem::new(BlockCache::new(disk));
+        fs.mount().unwrap();
+
+        let inumber = fs.create().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 241) as u8).collect();
+        fs.write(inumber, &data, size, 0).unwrap();
+        fs.disk.clear().unwrap();
+        (fs, inumber, data)
+    }
+
+    #[test]
+    fn test_sequential_reads_are_prefetched() {
+        // 100 blocks: the indirect block is needed after the fifth
+        let (mut fs, inumber, data) = cached_file(100 * Disk::BLOCK_SIZE);
+        let (reads, misses) = (fs.disk.get_ref().reads(), fs.disk.misses());
+        let mut read = vec![0; data.len()];
+        for offset in (0..data.len()).step_by(1000) {
+            let length = 1000.min(data.len() - offset);
+            fs.read(inumber, &mut read[offset..], length, offset).unwrap();
+        }
+        assert_eq!(read, data);
+
+        // all but the first handful of blocks were already cached when
+        // read, and they came in as a few large requests
+        assert!(fs.disk.misses() - misses < 10, "{} misses", fs.disk.misses() - misses);
+        assert_eq!(fs.disk.get_ref().reads() - reads, 102);
+        assert_eq!(fs.readAhead.window, READ_AHEAD_MAX);
+    }
+
+    #[test]
+    fn test_random_reads_are_not_prefetched() {
+        let (mut fs, inumber, _) = cached_file(100 * Disk::BLOCK_SIZE);
+        let reads = fs.disk.get_ref().reads();
+        let mut data = [0; 10];
+        for block in [50, 20, 80, 3, 60].iter() {
+            fs.read(inumber, &mut data, 10, block * Disk::BLOCK_SIZE + 7).unwrap();
+        }
+        assert_eq!(fs.readAhead.window, 0);
+        // the inode block, the indirect block and the five data blocks
+        assert_eq!(fs.disk.get_ref().reads() - reads, 7);
+    }
+
+    // counts the requests that reach the device
+    struct Requests {
+        disk: MemDisk,
+        requests: usize
+    }
+
+    impl BlockDevice for Requests {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> usize {
+            self.disk.block_count()
+        }
+
+        fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+            self.requests += 1;
+            self.disk.read_block(blocknum, data)
+        }
+
+        fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+            self.disk.write_block(blocknum, data)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_blocks(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+            self.requests += 1;
+            self.disk.read_blocks(blocknum, data)
+        }
+    }
+
+    #[test]
+    fn test_whole_blocks_read_as_runs() {
+        let (fs, inumber, data) = cached_file(20 * Disk::BLOCK_SIZE);
+        let disk = fs.into_disk().unwrap().into_inner().unwrap();
+        let mut fs = FileSystem::new(Requests { disk, requests: 0 });
+        fs.mount().unwrap();
+
+        // the inode, the indirect block and two runs: the indirect block
+        // sits between the direct blocks and the rest. An unaligned read gets
+        // its partial first and last blocks on their own
+        let requests = fs.disk.requests;
+        let mut read = vec![0; data.len()];
+        assert_eq!(fs.read(inumber, &mut read, data.len(), 0).unwrap(), data.len());
+        assert_eq!(read, data);
+        assert_eq!(fs.disk.requests - requests, 4);
+
+        let requests = fs.disk.requests;
+        fs.read(inumber, &mut read, 3 * Disk::BLOCK_SIZE, 100).unwrap();
+        assert_eq!(read[..3 * Disk::BLOCK_SIZE], data[100..100 + 3 * Disk::BLOCK_SIZE]);
+        assert_eq!(fs.disk.requests - requests, 4);
+    }
+}
@@ -0,0 +1,374 @@
+use std::ops::Range;
+
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::readahead::ReadAhead;
+use super::types::*;
+use super::{FileSystem, WRITE_CHUNK_BLOCKS};
+
+// Resizing keeps the inode table where it is. The bitmaps grow when they
+// cannot cover the new size, which moves the journal and the start of the
+// data region up; the data region ends where the reserved blocks start.
+// Any block in use outside the new data region is moved into it first,
+// one pointer block (or inode) at a time, each move a transaction of its
+// own. Only then is the new geometry written, and the device shrunk last.
+impl<D: BlockDevice> FileSystem<D> {
+    /// Grows or shrinks the mounted filesystem, and the device under it, to
+    /// `new_blocks` blocks. Shrinking fails with `NoSpace`, leaving the
+    /// filesystem as it was, unless what is in use fits in the smaller one.
+    ///
+    /// A crash part way leaves a consistent filesystem, though possibly on
+    /// a device larger than it; resizing again finishes the job.
+    pub fn resize(&mut self, new_blocks: usize) -> Result<()> {
+        let old = match &self.metaData {
+            Some(metaData) => metaData.superBlock,
+            None => return Err(Error::NotMounted)
+        };
+        let new = Self::resized(&old, new_blocks)?;
+        if new_blocks == old.Blocks as usize {
+            return self.fit_device(new_blocks);
+        }
+
+        // STEP 1: everything in use has to fit in what is left of the old
+        // data region, since the blocks past it are not tracked until the end
+        let keep = new.data_start()..new.data_end().min(old.data_end());
+        let used = self.dataBitMap.as_ref().map_or(0, |bitmap| bitmap.iter().filter(|used| **used).count());
+        if keep.start >= keep.end || used > keep.end - keep.start {
+            return Err(Error::NoSpace);
+        }
+
+        // the blocks must exist before anything is moved into them
+        let grown = new_blocks > self.disk.block_count();
+        if grown {
+            self.disk.resize(new_blocks)?;
+        }
+
+        // STEP 2: move what lies outside `keep` into it
+        if let Err(e) = self.relocate_all(&keep) {
+            // the bitmaps on disk may hold bits the moves set aside
+            if let (Some(inodeBitMap), Some(dataBitMap)) = (&self.inodeBitMap, &self.dataBitMap) {
+                let _ = Self::write_bitmaps(&mut self.disk, &old, inodeBitMap, dataBitMap);
+            }
+            if grown {
+                let _ = self.disk.resize(old.Blocks as usize);
+            }
+            return Err(e);
+        }
+
+        // STEP 3: switch to the new geometry, then let the device follow
+        self.switch_geometry(&old, new)?;
+        self.readAhead = ReadAhead::default();
+        self.fit_device(new_blocks)
+    }
+
+    /// The superblock of `old` resized to `new_blocks` blocks.
+    fn resized(old: &Superblock, new_blocks: usize) -> Result<Superblock> {
+        if new_blocks > u32::MAX as usize {
+            return Err(Error::InvalidFormat(format!("{} blocks do not fit in a superblock", new_blocks)));
+        }
+
+        let mut new = *old;
+        new.Blocks = new_blocks as u32;
+        if old.BitmapBlocks > 0 {
+            let needed = (old.inode_count() + new_blocks).div_ceil(BITS_PER_BLOCK);
+            new.BitmapBlocks = old.BitmapBlocks.max(needed as u32);
+        }
+        if new.data_start() >= new.data_end() {
+            return Err(Error::NoSpace);
+        }
+        Ok(new)
+    }
+
+    fn fit_device(&mut self, blocks: usize) -> Result<()> {
+        if self.disk.block_count() != blocks {
+            self.disk.resize(blocks)?;
+        }
+        self.disk.flush()
+    }
+
+    /// Moves every block in use outside `keep` into it. While this runs,
+    /// the free blocks outside `keep` and the blocks moved away from are
+    /// marked used, so that nothing is allocated there; they are cleared
+    /// when it is over, whether it succeeded or not.
+    fn relocate_all(&mut self, keep: &Range<usize>) -> Result<()> {
+        let (dataStart, inumbers) = match (&self.metaData, &self.inodeBitMap) {
+            (Some(metaData), Some(inodeBitMap)) => (
+                metaData.superBlock.data_start(),
+                inodeBitMap.iter().enumerate().filter(|(_, used)| **used).map(|(i, _)| i).collect::<Vec<usize>>()
+            ),
+            _ => return Err(Error::NotMounted)
+        };
+
+        let mut fenced = Vec::new();
+        if let Some(dataBitMap) = &mut self.dataBitMap {
+            for (i, used) in dataBitMap.iter_mut().enumerate() {
+                if !*used && !keep.contains(&(dataStart + i)) {
+                    *used = true;
+                    fenced.push(dataStart + i);
+                }
+            }
+        }
+
+        let result = inumbers.into_iter().try_for_each(|inumber| self.relocate_inode(inumber, keep, &mut fenced));
+
+        if let Some(dataBitMap) = &mut self.dataBitMap {
+            for block_num in fenced {
+                dataBitMap[block_num - dataStart] = false;
+            }
+        }
+        result
+    }
+
+    fn relocate_inode(&mut self, inumber: usize, keep: &Range<usize>, moved: &mut Vec<usize>) -> Result<()> {
+        let mut inode = self.load_valid_inode(inumber)?;
+        self.transaction(|fs| {
+            let mut changed = false;
+            for ptr in inode.Direct.iter_mut() {
+                changed |= fs.move_block(ptr, keep, moved)?;
+            }
+            for level in 1..=3 {
+                changed |= fs.move_block(inode.tree_root_mut(level), keep, moved)?;
+            }
+            if changed {
+                fs.save_inode(inumber, &inode)?;
+            }
+            Ok(())
+        })?;
+
+        for level in 1..=3 {
+            self.relocate_tree(inode.tree_root(level) as usize, level, keep, moved)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the blocks the pointer block `block_num` points at, then the
+    /// ones below them, `level` pointer blocks deep.
+    fn relocate_tree(&mut self, block_num: usize, level: u32, keep: &Range<usize>, moved: &mut Vec<usize>) -> Result<()> {
+        if block_num == 0 {
+            return Ok(());
+        }
+
+        // a transaction per WRITE_CHUNK_BLOCKS pointers keeps the bitmap
+        // blocks each one dirties within what the journal holds
+        let mut ptrs = self.read_pointers(block_num)?;
+        for first in (0..ptrs.len()).step_by(WRITE_CHUNK_BLOCKS) {
+            self.transaction(|fs| {
+                let mut changed = false;
+                for ptr in ptrs.iter_mut().skip(first).take(WRITE_CHUNK_BLOCKS) {
+                    changed |= fs.move_block(ptr, keep, moved)?;
+                }
+                if changed {
+                    fs.write_pointers(block_num, ptrs)?;
+                }
+                Ok(())
+            })?;
+        }
+
+        if level > 1 {
+            for ptr in ptrs.iter() {
+                self.relocate_tree(*ptr as usize, level - 1, keep, moved)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the block `ptr` points at into a free block in `keep` and
+    /// points `ptr` there, unless it is already in `keep`. The copy is
+    /// written in place before the transaction pointing at it commits, as
+    /// file data is. Returns whether `ptr` changed.
+    fn move_block(&mut self, ptr: &mut u32, keep: &Range<usize>, moved: &mut Vec<usize>) -> Result<bool> {
+        let old = *ptr as usize;
+        if old == 0 || keep.contains(&old) {
+            return Ok(false);
+        }
+
+        let new = self.allocate_free_block()?;
+        let mut data = [0; Disk::BLOCK_SIZE];
+        self.read_block(old, &mut data)?;
+        self.write_data_block(new, &data)?;
+
+        // the old block stays marked used until every move is done
+        moved.push(old);
+        *ptr = new as u32;
+        Ok(true)
+    }
+
+    /// Writes the geometry of `new` over that of `old`, once nothing in use
+    /// lies outside the new data region.
+    fn switch_geometry(&mut self, old: &Superblock, new: Superblock) -> Result<()> {
+        let (inode_bit_map, old_bit_map) = match (&self.inodeBitMap, &self.dataBitMap) {
+            (Some(inodeBitMap), Some(dataBitMap)) => (inodeBitMap.clone(), dataBitMap),
+            _ => return Err(Error::NotMounted)
+        };
+        let data_bit_map: Vec<bool> = (new.data_start()..new.data_end())
+            .map(|block_num| block_num
+                .checked_sub(old.data_start())
+                .and_then(|i| old_bit_map.get(i).copied())
+                .unwrap_or(false))
+            .collect();
+
+        // STEP 1: the reserved blocks move to the new end of the device;
+        // growing copies from the top down, shrinking from the bottom up,
+        // so that overlapping ranges are not overwritten before being read
+        let reserved = old.ReservedBlocks as usize;
+        let (from, to) = (old.Blocks as usize - reserved, new.Blocks as usize - reserved);
+        let order: Vec<usize> = if to > from { (0..reserved).rev().collect() } else { (0..reserved).collect() };
+        let mut data = [0; Disk::BLOCK_SIZE];
+        if from != to {
+            for i in order {
+                self.disk.read_block(from + i, &mut data)?;
+                self.disk.write_block(to + i, &data)?;
+            }
+        }
+
+        // STEP 2: an empty journal where the new superblock will look for it
+        if new.JournalBlocks > 0 && new.journal_start() != old.journal_start() {
+            self.disk.write_block(new.journal_start(), &[0; Disk::BLOCK_SIZE])?;
+        }
+        self.disk.flush()?;
+
+        // STEP 3: the new superblock is the switch. It is still marked
+        // dirty, so a crash before the bitmaps are written rebuilds them.
+        let mut block = Block::new();
+        block.set_superblock(new);
+        self.disk.write_block(0, &block.data())?;
+        self.disk.flush()?;
+        Self::write_bitmaps(&mut self.disk, &new, &inode_bit_map, &data_bit_map)?;
+        self.disk.flush()?;
+
+        if let Some(metaData) = &mut self.metaData {
+            metaData.superBlock = new;
+        }
+        self.dataBitMap = Some(data_bit_map);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::crash::{crash_points, epochs, RecordingDisk};
+    use super::super::memdisk::MemDisk;
+    use super::super::options::FormatOptions;
+    use super::super::testutil::{assert_file, pattern, write_file};
+
+    // a filesystem whose files sit at the top of the data region: the
+    // first file filled the bottom and was removed again
+    fn filled(blocks: usize) -> FileSystem<MemDisk> {
+        let mut fs = FileSystem::from_disk(MemDisk::new(blocks)).unwrap();
+        write_file(&mut fs, "/filler", &pattern(100 * Disk::BLOCK_SIZE, 0));
+        fs.mkdir("/dir").unwrap();
+        write_file(&mut fs, "/dir/large", &pattern(1000 * Disk::BLOCK_SIZE, 1));
+        write_file(&mut fs, "/small", &pattern(2 * Disk::BLOCK_SIZE, 2));
+        fs.unlink("/filler").unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(200)).unwrap();
+        write_file(&mut fs, "/a", &pattern(150 * Disk::BLOCK_SIZE, 1));
+
+        // /b only fits once the filesystem has grown
+        fs.resize(400).unwrap();
+        assert_eq!(fs.disk.size(), 400);
+        write_file(&mut fs, "/b", &pattern(150 * Disk::BLOCK_SIZE, 2));
+        assert_file(&mut fs, "/a", &pattern(150 * Disk::BLOCK_SIZE, 1));
+
+        let mut disk = fs.into_disk().unwrap();
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        assert_file(&mut fs, "/b", &pattern(150 * Disk::BLOCK_SIZE, 2));
+    }
+
+    #[test]
+    fn test_shrink_moves_blocks() {
+        let mut fs = filled(1500);
+        let data_start = fs.metaData.as_ref().unwrap().superBlock.data_start();
+
+        fs.resize(data_start + 1050).unwrap();
+        assert_file(&mut fs, "/dir/large", &pattern(1000 * Disk::BLOCK_SIZE, 1));
+        assert_file(&mut fs, "/small", &pattern(2 * Disk::BLOCK_SIZE, 2));
+
+        let mut disk = fs.into_disk().unwrap();
+        assert_eq!(disk.size(), data_start + 1050);
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_shrink_too_far() {
+        let mut fs = filled(1500);
+        let data_start = fs.metaData.as_ref().unwrap().superBlock.data_start();
+        match fs.resize(data_start + 1000) {
+            Err(Error::NoSpace) => {},
+            r => panic!("unexpected result {:?}", r)
+        }
+
+        // nothing moved, and the space is still there to use
+        assert_eq!(fs.disk.size(), 1500);
+        write_file(&mut fs, "/more", &pattern(100 * Disk::BLOCK_SIZE, 3));
+        assert_file(&mut fs, "/dir/large", &pattern(1000 * Disk::BLOCK_SIZE, 1));
+        let mut disk = fs.into_disk().unwrap();
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_grow_bitmaps() {
+        // one bitmap block is just enough for 31680 inodes and 1024 blocks
+        let mut disk = MemDisk::new(1024);
+        FileSystem::format_with(&mut disk, &FormatOptions::new().inodes(31680).reserved_blocks(2)).unwrap();
+        disk.write(1022, &[7; Disk::BLOCK_SIZE]).unwrap();
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        write_file(&mut fs, "/file", &pattern(20 * Disk::BLOCK_SIZE, 4));
+        let old = fs.metaData.as_ref().unwrap().superBlock;
+
+        fs.resize(1100).unwrap();
+        let new = fs.metaData.as_ref().unwrap().superBlock;
+        assert_eq!((old.BitmapBlocks, new.BitmapBlocks), (1, 2));
+        assert_eq!(new.data_start(), old.data_start() + 1);
+        assert_file(&mut fs, "/file", &pattern(20 * Disk::BLOCK_SIZE, 4));
+        assert!(fs.readdir("/").unwrap().iter().any(|(name, _)| name == "file"));
+
+        let mut disk = fs.into_disk().unwrap();
+        let mut data = [0; Disk::BLOCK_SIZE];
+        disk.read(1098, &mut data).unwrap();
+        assert_eq!(data, [7; Disk::BLOCK_SIZE]);
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_crash_while_shrinking() {
+        let mut base = filled(1500).into_disk().unwrap();
+        let new_blocks = FileSystem::read_super_block(&mut base).unwrap().data_start() + 1050;
+
+        let mut recorder = RecordingDisk::new(base.clone());
+        let mut fs = FileSystem::new(&mut recorder);
+        fs.mount().unwrap();
+        fs.resize(new_blocks).unwrap();
+        fs.unmount().unwrap();
+
+        let epochs = epochs(&recorder.log);
+        for point in crash_points(&epochs).iter().step_by(7) {
+            let context = format!("crash in epoch {} keeping writes {:?}", point.epoch, point.kept);
+            let mut disk = point.image(&base);
+
+            // what resizing again would do first: fit the device to the filesystem
+            let blocks = FileSystem::read_super_block(&mut disk).unwrap().Blocks as usize;
+            disk.set_size(blocks);
+
+            let mut fs = FileSystem::new(&mut disk);
+            if let Err(e) = fs.mount() {
+                panic!("{}: mount failed: {}", context, e);
+            }
+            assert_file(&mut fs, "/dir/large", &pattern(1000 * Disk::BLOCK_SIZE, 1));
+            assert_file(&mut fs, "/small", &pattern(2 * Disk::BLOCK_SIZE, 2));
+            fs.unmount().unwrap();
+
+            let report = FileSystem::check(&mut disk, false).unwrap();
+            assert!(report.is_clean(), "{}: {:?}", context, report.problems);
+        }
+    }
+}
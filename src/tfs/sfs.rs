@@ -0,0 +1,567 @@
+use std::convert::TryFrom;
+
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::{get_u32, put_u32, Block, Superblock, LABEL_MAX, POINTERS_PER_BLOCK, POINTERS_PER_INODE};
+
+// SIMPLEFS IMAGES
+// The format tfs grew out of, kept so that images made by (and for) sfssh
+// can still be used byte for byte:
+//
+//   Superblock (block 0): MagicNumber, Blocks, InodeBlocks, Inodes, where
+//   Inodes is the capacity of the inode table, not the number in use
+//
+//   Inode (32 bytes, SFS_INODES_PER_BLOCK per inode block)
+//      0  Valid          u32
+//      4  Size           u32
+//      8  Direct         5 x u32
+//     28  Indirect       u32
+//
+// There are no directories, bitmaps or double/triple indirect blocks.
+//
+// The I/O pattern of every operation (which blocks are read and written,
+// and how often) follows the reference sfssh, since its test transcripts
+// include the disk's read and write counters.
+pub const SFS_MAGIC_NUMBER: u32 = 0xf0f03410;
+pub const SFS_INODES_PER_BLOCK: usize = 128;
+pub const SFS_INODE_SIZE: usize = 32;
+pub const SFS_MAX_FILE_BLOCKS: usize = POINTERS_PER_INODE + POINTERS_PER_BLOCK;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SfsInode {
+    pub Valid: u32, // whether or not inode is valid
+    pub Size: u32,  // size of file
+    pub Direct: [u32; POINTERS_PER_INODE],
+    pub Indirect: u32
+}
+
+impl SfsInode {
+    pub fn blank() -> Self {
+        SfsInode {
+            Valid: 0,
+            Size: 0,
+            Direct: [0; POINTERS_PER_INODE],
+            Indirect: 0
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut inode = Self::blank();
+        inode.Valid = get_u32(bytes, 0);
+        inode.Size = get_u32(bytes, 4);
+        for (i, pointer) in inode.Direct.iter_mut().enumerate() {
+            *pointer = get_u32(bytes, 8 + 4 * i);
+        }
+        inode.Indirect = get_u32(bytes, 28);
+        inode
+    }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        put_u32(bytes, 0, self.Valid);
+        put_u32(bytes, 4, self.Size);
+        for (i, pointer) in self.Direct.iter().enumerate() {
+            put_u32(bytes, 8 + 4 * i, *pointer);
+        }
+        put_u32(bytes, 28, self.Indirect);
+    }
+}
+
+/// A filesystem on a SimpleFS image, as used by the reference `sfssh`.
+pub struct SimpleFs<D: BlockDevice> {
+    pub superBlock: Option<Superblock>,
+    pub blockBitMap: Option<Vec<bool>>,  // in-use flag of every block on the disk
+    pub disk: D
+}
+
+impl<D: BlockDevice> SimpleFs<D> {
+    /// Wraps `disk` in an unmounted filesystem; call `mount` before using it.
+    pub fn new(disk: D) -> Self {
+        SimpleFs {
+            superBlock: None,
+            blockBitMap: None,
+            disk
+        }
+    }
+
+    /// Prints the superblock and every valid inode, in sfssh's format.
+    pub fn debug(disk: &mut D) -> Result<()> {
+        let superblock = Self::read_super_block(disk)?;
+
+        println!("SuperBlock:");
+        if superblock.MagicNumber != SFS_MAGIC_NUMBER {
+            println!("    magic number is invalid");
+            return Ok(());
+        }
+        println!("    magic number is valid");
+        println!("    {} blocks", superblock.Blocks);
+        println!("    {} inode blocks", superblock.InodeBlocks);
+        println!("    {} inodes", superblock.Inodes);
+
+        for i in 0..superblock.InodeBlocks as usize {
+            let inodes = Self::read_inode_block(disk, 1 + i)?;
+            for (row, inode) in inodes.iter().enumerate() {
+                if inode.Valid == 0 {
+                    continue;
+                }
+
+                println!("Inode {}:", i * SFS_INODES_PER_BLOCK + row);
+                println!("    size: {} bytes", inode.Size);
+                println!("    direct blocks:{}", Self::block_list(&inode.Direct));
+                if inode.Indirect != 0 {
+                    println!("    indirect block: {}", inode.Indirect);
+                    let pointers = Self::read_pointers(disk, inode.Indirect as usize)?;
+                    println!("    indirect data blocks:{}", Self::block_list(&pointers));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes an empty SimpleFS over the whole disk, 10% of it inodes.
+    pub fn format(&mut self) -> Result<()> {
+        if self.is_mounted() {
+            return Err(Error::AlreadyMounted);
+        }
+
+        let blocks = self.disk.block_count();
+        let inode_blocks = Self::inode_blocks_for(blocks);
+        let superblock = Superblock {
+            MagicNumber: SFS_MAGIC_NUMBER,
+            Blocks: blocks as u32,
+            InodeBlocks: inode_blocks as u32,
+            Inodes: (inode_blocks * SFS_INODES_PER_BLOCK) as u32,
+            BitmapBlocks: 0,
+            State: 0,
+            JournalBlocks: 0,
+            ReservedBlocks: 0,
+            Uuid: [0; 16],
+            Label: [0; LABEL_MAX]
+        };
+
+        let mut block = Block::new();
+        block.set_superblock(superblock);
+        self.disk.write_block(0, &block.data())?;
+        for i in 1..blocks {
+            self.disk.write_block(i, &[0; Disk::BLOCK_SIZE])?;
+        }
+        self.disk.flush()
+    }
+
+    pub fn mount(&mut self) -> Result<()> {
+        if self.is_mounted() {
+            return Err(Error::AlreadyMounted);
+        }
+
+        let superblock = Self::read_super_block(&mut self.disk)?;
+        if superblock.MagicNumber != SFS_MAGIC_NUMBER {
+            return Err(Error::BadMagic(superblock.MagicNumber));
+        }
+        let blocks = superblock.Blocks as usize;
+        let inode_blocks = superblock.InodeBlocks as usize;
+        if blocks != self.disk.block_count()
+            || inode_blocks != Self::inode_blocks_for(blocks)
+            || superblock.Inodes as usize != inode_blocks * SFS_INODES_PER_BLOCK
+        {
+            return Err(Error::CorruptMetadata(format!(
+                "superblock describes {} blocks, {} inode blocks and {} inodes on a {} block disk",
+                blocks, inode_blocks, superblock.Inodes, self.disk.block_count()
+            )));
+        }
+
+        // the super block and inode table are always in use; mark every
+        // block the inodes point at
+        let mut bit_map = vec![false; blocks];
+        for used in bit_map.iter_mut().take(1 + inode_blocks) {
+            *used = true;
+        }
+        for i in 0..inode_blocks {
+            let inodes = Self::read_inode_block(&mut self.disk, 1 + i)?;
+            for inode in inodes.iter().filter(|inode| inode.Valid != 0) {
+                Self::mark(&mut bit_map, &inode.Direct)?;
+                if inode.Indirect != 0 {
+                    Self::mark(&mut bit_map, &[inode.Indirect])?;
+                    let pointers = Self::read_pointers(&mut self.disk, inode.Indirect as usize)?;
+                    Self::mark(&mut bit_map, &pointers)?;
+                }
+            }
+        }
+
+        self.superBlock = Some(superblock);
+        self.blockBitMap = Some(bit_map);
+        Ok(())
+    }
+
+    pub fn unmount(&mut self) {
+        if self.is_mounted() {
+            let _ = self.disk.flush();
+        }
+        self.superBlock = None;
+        self.blockBitMap = None;
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.superBlock.is_some()
+    }
+
+    /// Allocates the lowest free inode and returns its number.
+    pub fn create(&mut self) -> Result<usize> {
+        let inode_blocks = self.super_block()?.InodeBlocks as usize;
+
+        for i in 0..inode_blocks {
+            let inodes = Self::read_inode_block(&mut self.disk, 1 + i)?;
+            if let Some(row) = inodes.iter().position(|inode| inode.Valid == 0) {
+                let inumber = i * SFS_INODES_PER_BLOCK + row;
+                let mut inode = SfsInode::blank();
+                inode.Valid = 1;
+                self.save_inode(inumber, &inode)?;
+                return Ok(inumber);
+            }
+        }
+
+        Err(Error::NoSpace)
+    }
+
+    /// Frees an inode along with all of its blocks.
+    pub fn remove(&mut self, inumber: usize) -> Result<()> {
+        let inode = self.load_valid_inode(inumber)?;
+
+        self.free_blocks(&inode.Direct);
+        if inode.Indirect != 0 {
+            let pointers = Self::read_pointers(&mut self.disk, inode.Indirect as usize)?;
+            self.free_blocks(&pointers);
+            self.free_blocks(&[inode.Indirect]);
+        }
+
+        self.save_inode(inumber, &SfsInode::blank())
+    }
+
+    pub fn stat(&mut self, inumber: usize) -> Result<usize> {
+        Ok(self.load_valid_inode(inumber)?.Size as usize)
+    }
+
+    /// Reads up to `length` bytes at `offset`; returns 0 at end of file.
+    pub fn read(&mut self, inumber: usize, data: &mut [u8], length: usize, offset: usize) -> Result<usize> {
+        let inode = self.load_valid_inode(inumber)?;
+        let size = inode.Size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let length = length.min(size - offset).min(data.len());
+        let mut block = [0; Disk::BLOCK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done;
+            let index = position / Disk::BLOCK_SIZE;
+            let start = position % Disk::BLOCK_SIZE;
+            let count = (Disk::BLOCK_SIZE - start).min(length - done);
+
+            let block_num = if index < POINTERS_PER_INODE {
+                inode.Direct[index]
+            } else if index < SFS_MAX_FILE_BLOCKS {
+                Self::read_pointers(&mut self.disk, inode.Indirect as usize)?[index - POINTERS_PER_INODE]
+            } else {
+                return Err(Error::CorruptMetadata(format!(
+                    "inode {} is {} bytes, more than its blocks can hold", inumber, size
+                )));
+            };
+            self.check_pointer(block_num)?;
+            self.disk.read_block(block_num as usize, &mut block)?;
+            data[done..done + count].copy_from_slice(&block[start..start + count]);
+            done += count;
+        }
+
+        Ok(done)
+    }
+
+    /// Writes `length` bytes at `offset`, allocating blocks as it goes.
+    /// Returns fewer bytes than asked for once the disk or the inode is full.
+    pub fn write(&mut self, inumber: usize, data: &[u8], length: usize, offset: usize) -> Result<usize> {
+        let mut inode = self.load_valid_inode(inumber)?;
+        let size = inode.Size as usize;
+        if offset > size {
+            // SimpleFS files cannot have holes
+            return Err(Error::InvalidSeek(i64::try_from(offset).unwrap_or(i64::MAX)));
+        }
+
+        let length = length.min(data.len());
+        let mut block = [0; Disk::BLOCK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done;
+            let index = position / Disk::BLOCK_SIZE;
+            let start = position % Disk::BLOCK_SIZE;
+            let count = (Disk::BLOCK_SIZE - start).min(length - done);
+            if index >= SFS_MAX_FILE_BLOCKS {
+                break;
+            }
+
+            // find (or allocate) the data block
+            let (block_num, fresh) = if index < POINTERS_PER_INODE {
+                if inode.Direct[index] == 0 {
+                    match self.allocate_block() {
+                        Some(block_num) => inode.Direct[index] = block_num,
+                        None => break
+                    }
+                    (inode.Direct[index], true)
+                } else {
+                    (inode.Direct[index], false)
+                }
+            } else {
+                let slot = index - POINTERS_PER_INODE;
+                let mut pointers = [0; POINTERS_PER_BLOCK];
+                if inode.Indirect == 0 {
+                    match self.allocate_block() {
+                        Some(block_num) => inode.Indirect = block_num,
+                        None => break
+                    }
+                } else {
+                    pointers = Self::read_pointers(&mut self.disk, inode.Indirect as usize)?;
+                }
+
+                if pointers[slot] == 0 {
+                    match self.allocate_block() {
+                        Some(block_num) => pointers[slot] = block_num,
+                        None => break
+                    }
+                    let mut indirect = Block::new();
+                    indirect.set_pointers(pointers);
+                    self.disk.write_block(inode.Indirect as usize, &indirect.data())?;
+                    (pointers[slot], true)
+                } else {
+                    (pointers[slot], false)
+                }
+            };
+            self.check_pointer(block_num)?;
+
+            // only a partly overwritten block needs its old contents
+            if fresh {
+                block = [0; Disk::BLOCK_SIZE];
+            } else if count < Disk::BLOCK_SIZE {
+                self.disk.read_block(block_num as usize, &mut block)?;
+            }
+            block[start..start + count].copy_from_slice(&data[done..done + count]);
+            self.disk.write_block(block_num as usize, &block)?;
+
+            done += count;
+            inode.Size = inode.Size.max((offset + done) as u32);
+            self.save_inode(inumber, &inode)?;
+        }
+
+        Ok(done)
+    }
+
+    fn super_block(&self) -> Result<Superblock> {
+        self.superBlock.ok_or(Error::NotMounted)
+    }
+
+    fn inode_blocks_for(blocks: usize) -> usize {
+        (blocks as f64 * 0.1).ceil() as usize
+    }
+
+    fn block_list(pointers: &[u32]) -> String {
+        pointers
+            .iter()
+            .filter(|pointer| **pointer != 0)
+            .map(|pointer| format!(" {}", pointer))
+            .collect()
+    }
+
+    fn read_super_block(disk: &mut D) -> Result<Superblock> {
+        if disk.block_size() != Disk::BLOCK_SIZE {
+            return Err(Error::UnsupportedBlockSize(disk.block_size()));
+        }
+
+        let mut block = Block::new();
+        disk.read_block(0, &mut block.Data)?;
+        Ok(block.superblock())
+    }
+
+    fn read_inode_block(disk: &mut D, block_num: usize) -> Result<[SfsInode; SFS_INODES_PER_BLOCK]> {
+        let mut data = [0; Disk::BLOCK_SIZE];
+        disk.read_block(block_num, &mut data)?;
+
+        let mut inodes = [SfsInode::blank(); SFS_INODES_PER_BLOCK];
+        for (i, inode) in inodes.iter_mut().enumerate() {
+            *inode = SfsInode::decode(&data[i * SFS_INODE_SIZE..(i + 1) * SFS_INODE_SIZE]);
+        }
+        Ok(inodes)
+    }
+
+    fn read_pointers(disk: &mut D, block_num: usize) -> Result<[u32; POINTERS_PER_BLOCK]> {
+        let mut block = Block::new();
+        disk.read_block(block_num, &mut block.Data)?;
+        Ok(block.pointers())
+    }
+
+    /// Locates the inode block (and the row within it) that holds `inumber`.
+    fn inode_location(&self, inumber: usize) -> Result<(usize, usize)> {
+        let superblock = self.super_block()?;
+        if inumber >= superblock.Inodes as usize {
+            return Err(Error::InvalidInode(inumber));
+        }
+
+        Ok((1 + inumber / SFS_INODES_PER_BLOCK, inumber % SFS_INODES_PER_BLOCK))
+    }
+
+    fn load_valid_inode(&mut self, inumber: usize) -> Result<SfsInode> {
+        let (blk, row) = self.inode_location(inumber)?;
+        let inode = Self::read_inode_block(&mut self.disk, blk)?[row];
+        if inode.Valid == 0 {
+            return Err(Error::InvalidInode(inumber));
+        }
+        Ok(inode)
+    }
+
+    fn save_inode(&mut self, inumber: usize, inode: &SfsInode) -> Result<()> {
+        let (blk, row) = self.inode_location(inumber)?;
+
+        let mut data = [0; Disk::BLOCK_SIZE];
+        self.disk.read_block(blk, &mut data)?;
+        inode.encode(&mut data[row * SFS_INODE_SIZE..(row + 1) * SFS_INODE_SIZE]);
+        self.disk.write_block(blk, &data)
+    }
+
+    fn mark(bit_map: &mut [bool], pointers: &[u32]) -> Result<()> {
+        for pointer in pointers.iter().map(|pointer| *pointer as usize).filter(|pointer| *pointer != 0) {
+            match bit_map.get_mut(pointer) {
+                Some(used) => *used = true,
+                None => {
+                    return Err(Error::CorruptMetadata(format!("block pointer {} is out of range", pointer)))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_pointer(&self, block_num: u32) -> Result<()> {
+        let inode_blocks = self.super_block()?.InodeBlocks as usize;
+        let block_num = block_num as usize;
+        if block_num <= inode_blocks || block_num >= self.disk.block_count() {
+            return Err(Error::CorruptMetadata(format!("block pointer {} is out of range", block_num)));
+        }
+        Ok(())
+    }
+
+    fn allocate_block(&mut self) -> Option<u32> {
+        let bit_map = self.blockBitMap.as_mut()?;
+        let block_num = bit_map.iter().position(|used| !used)?;
+        bit_map[block_num] = true;
+        Some(block_num as u32)
+    }
+
+    fn free_blocks(&mut self, pointers: &[u32]) {
+        if let Some(bit_map) = &mut self.blockBitMap {
+            for pointer in pointers.iter().map(|pointer| *pointer as usize) {
+                if pointer != 0 && pointer < bit_map.len() {
+                    bit_map[pointer] = false;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memdisk::MemDisk;
+
+    fn image(name: &str) -> MemDisk {
+        MemDisk::from_image(format!("data/{}", name)).unwrap()
+    }
+
+    #[test]
+    fn test_mount_images() {
+        for (name, reads) in &[("image.5", 2), ("image.20", 4), ("image.200", 23)] {
+            let mut fs = SimpleFs::new(image(name));
+            fs.mount().unwrap();
+            assert_eq!(fs.disk.reads(), *reads);
+            assert!(fs.mount().is_err());
+        }
+    }
+
+    #[test]
+    fn test_stat_and_read() {
+        let mut fs = SimpleFs::new(image("image.20"));
+        fs.mount().unwrap();
+        assert_eq!(fs.stat(2).unwrap(), 27160);
+        assert_eq!(fs.stat(3).unwrap(), 9546);
+        assert!(fs.stat(1).is_err());
+
+        let mut data = vec![0; 32768];
+        assert_eq!(fs.read(2, &mut data, 32768, 0).unwrap(), 27160);
+        assert_eq!(fs.read(2, &mut data, 32768, 27160).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_format_and_write() {
+        let mut fs = SimpleFs::new(MemDisk::new(20));
+        fs.format().unwrap();
+        assert_eq!(fs.disk.writes(), 20);
+        fs.mount().unwrap();
+        assert!(fs.format().is_err());
+
+        let data: Vec<u8> = (0..7 * Disk::BLOCK_SIZE - 100).map(|i| (i % 251) as u8).collect();
+        let inumber = fs.create().unwrap();
+        assert_eq!(inumber, 0);
+        assert_eq!(fs.write(inumber, &data, data.len(), 0).unwrap(), data.len());
+
+        // blocks 0-2 are the super block and inode table; the indirect
+        // block is allocated ahead of the data it points at
+        let mut block = Block::new();
+        fs.disk.read_block(1, &mut block.Data).unwrap();
+        let inode = SfsInode::decode(&block.Data[..SFS_INODE_SIZE]);
+        assert_eq!(inode.Size as usize, data.len());
+        assert_eq!(inode.Direct, [3, 4, 5, 6, 7]);
+        assert_eq!(inode.Indirect, 8);
+
+        let mut read = vec![0; data.len()];
+        assert_eq!(fs.read(inumber, &mut read, data.len(), 0).unwrap(), data.len());
+        assert_eq!(read, data);
+
+        fs.remove(inumber).unwrap();
+        assert!(fs.stat(inumber).is_err());
+        assert_eq!(fs.blockBitMap.as_ref().unwrap().iter().filter(|used| **used).count(), 3);
+    }
+
+    #[test]
+    fn test_write_io_pattern() {
+        // sfssh reads the inode and writes it back after every block
+        let mut fs = SimpleFs::new(image("image.5"));
+        fs.mount().unwrap();
+        let inumber = fs.create().unwrap();
+        let (reads, writes) = (fs.disk.reads(), fs.disk.writes());
+        assert_eq!(fs.write(inumber, &[7; 965], 965, 0).unwrap(), 965);
+        assert_eq!(fs.disk.reads() - reads, 2);
+        assert_eq!(fs.disk.writes() - writes, 2);
+    }
+
+    #[test]
+    fn test_full_disk_short_write() {
+        let mut fs = SimpleFs::new(image("image.5"));
+        fs.mount().unwrap();
+        let inumber = fs.create().unwrap();
+        let data = vec![1; 4 * Disk::BLOCK_SIZE];
+        // only blocks 3 and 4 are free
+        assert_eq!(fs.write(inumber, &data, data.len(), 0).unwrap(), 2 * Disk::BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_bad_offsets() {
+        let mut fs = SimpleFs::new(MemDisk::new(20));
+        fs.format().unwrap();
+        fs.mount().unwrap();
+        let inumber = fs.create().unwrap();
+        assert_eq!(fs.write(inumber, &[1; 10], 10, 0).unwrap(), 10);
+        assert!(matches!(fs.write(inumber, &[1; 10], 10, 11), Err(Error::InvalidSeek(11))));
+
+        // a size larger than the inode can address is refused, not followed
+        let mut inode = fs.load_valid_inode(inumber).unwrap();
+        inode.Size = (SFS_MAX_FILE_BLOCKS * Disk::BLOCK_SIZE + 1) as u32;
+        fs.save_inode(inumber, &inode).unwrap();
+        let offset = SFS_MAX_FILE_BLOCKS * Disk::BLOCK_SIZE;
+        assert!(matches!(fs.read(inumber, &mut [0; 1], 1, offset), Err(Error::CorruptMetadata(_))));
+    }
+}
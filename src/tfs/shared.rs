@@ -0,0 +1,686 @@
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+
+use super::device::{BlockDevice, SharedDevice};
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::MAX_FILE_BLOCKS;
+use super::FileSystem;
+
+// reads and writes look up (or set aside) this many blocks at a time under
+// the filesystem lock, then move their data with the lock released
+const CHUNK_BLOCKS: usize = 16;
+
+/// A mounted `FileSystem` that any number of threads can use at once.
+///
+/// Handles are cheap to clone and share one filesystem. Its metadata (the
+/// allocator, the journal, inodes and directories) sits behind a single
+/// lock, but file data does not: reads and writes take that lock only to
+/// find out which blocks to use, and move the data through the shared
+/// device without it. Transfers on different files, or several reads of
+/// one file, therefore run side by side as far as the device allows
+/// (`MemDisk` serves blocks in different stripes at once, while a `Mutex`
+/// around any other device takes requests one at a time).
+///
+/// - a write sets aside the new blocks it needs, fills them and only then
+///   links them into the file in a transaction, so the file never points
+///   at blocks that do not hold its data yet;
+/// - every inode has a reader/writer lock held for the whole operation,
+///   so a write (or truncate, or removal) spanning several chunks is never
+///   seen half done, and the blocks of a file stay where they are while
+///   data moves in and out of them;
+/// - `with` waits for every read and write in flight, since whatever it
+///   runs may move blocks around.
+///
+/// Locks are taken in one order: the layout lock that `with` holds, then
+/// an inode, then the filesystem.
+pub struct SharedFileSystem<D: SharedDevice> {
+    inner: Arc<Shared<D>>
+}
+
+struct Shared<D: SharedDevice> {
+    fs: Mutex<FileSystem<Arc<D>>>,
+    device: Arc<D>,          // the filesystem's own device, for file data
+    layout: RwLock<()>,      // shared by reads and writes, `with` has it alone
+    inodes: Vec<RwLock<()>>  // indexed by inode number
+}
+
+impl<D: SharedDevice> Clone for SharedFileSystem<D> {
+    fn clone(&self) -> Self {
+        SharedFileSystem { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<D: SharedDevice> SharedFileSystem<D> {
+    /// Shares the mounted filesystem `fs`.
+    pub fn new(fs: FileSystem<Arc<D>>) -> Result<Self> {
+        let inodes = match &fs.metaData {
+            Some(metaData) => metaData.superBlock.inode_count(),
+            None => return Err(Error::NotMounted)
+        };
+        Ok(SharedFileSystem {
+            inner: Arc::new(Shared {
+                device: Arc::clone(&fs.disk),
+                fs: Mutex::new(fs),
+                layout: RwLock::new(()),
+                inodes: (0..inodes).map(|_| RwLock::new(())).collect()
+            })
+        })
+    }
+
+    /// Gives the filesystem back once this is the last handle to it; its
+    /// device is then no longer shared either.
+    pub fn into_inner(self) -> std::result::Result<FileSystem<Arc<D>>, Self> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(shared) => Ok(shared.fs.into_inner().expect("filesystem lock poisoned")),
+            Err(inner) => Err(SharedFileSystem { inner })
+        }
+    }
+
+    /// Runs `op` with the whole filesystem to itself, for anything the
+    /// handle has no method for.
+    pub fn with<T>(&self, op: impl FnOnce(&mut FileSystem<Arc<D>>) -> T) -> T {
+        let _layout = self.inner.layout.write().expect("layout lock poisoned");
+        op(&mut self.fs())
+    }
+
+    pub fn lookup(&self, path: &str) -> Result<usize> {
+        self.fs().lookup(path)
+    }
+
+    pub fn open(&self, path: &str) -> Result<usize> {
+        self.fs().open(path)
+    }
+
+    pub fn create(&self) -> Result<usize> {
+        self.fs().create()
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<usize> {
+        self.fs().mkdir(path)
+    }
+
+    pub fn readdir(&self, path: &str) -> Result<Vec<(String, usize)>> {
+        self.fs().readdir(path)
+    }
+
+    pub fn stat(&self, inumber: usize) -> Result<usize> {
+        let _file = self.inode(inumber)?.read().expect("inode lock poisoned");
+        self.fs().stat(inumber)
+    }
+
+    /// `FileSystem::read`; other readers of the file may be part way through
+    /// theirs, but no writer.
+    pub fn read(&self, inumber: usize, data: &mut [u8], length: usize, offset: usize) -> Result<usize> {
+        let _layout = self.layout();
+        let _file = self.inode(inumber)?.read().expect("inode lock poisoned");
+        let length = length.min(data.len());
+
+        let mut bytes_read = 0;
+        while bytes_read < length {
+            let chunk = (CHUNK_BLOCKS * Disk::BLOCK_SIZE).min(length - bytes_read);
+            let bytes = self.read_chunk(inumber, &mut data[bytes_read..bytes_read + chunk], offset + bytes_read)?;
+            bytes_read += bytes;
+            if bytes < chunk {
+                break;
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    /// `FileSystem::write`; nothing else touches the file until it is done.
+    pub fn write(&self, inumber: usize, data: &[u8], length: usize, offset: usize) -> Result<usize> {
+        let _layout = self.layout();
+        let _file = self.inode(inumber)?.write().expect("inode lock poisoned");
+        let data = &data[..length.min(data.len())];
+        let end = offset.checked_add(data.len()).ok_or(Error::FileTooLarge)?;
+        if data.is_empty() || end > MAX_FILE_BLOCKS * Disk::BLOCK_SIZE {
+            // no data to move; the filesystem has the last word on the rest
+            return self.fs().write(inumber, data, data.len(), offset);
+        }
+
+        // like `FileSystem::write`, start at the old end of file when there
+        // is a gap to zero-fill
+        let mut position = offset.min(self.fs().load_file_inode(inumber)?.Size as usize);
+        let mut bytes_written = 0;
+        while position < end {
+            let chunk_end = end.min((position / Disk::BLOCK_SIZE + CHUNK_BLOCKS) * Disk::BLOCK_SIZE);
+            let reached = match self.write_chunk(inumber, data, offset, position, chunk_end) {
+                Ok(reached) => reached,
+                Err(Error::NoSpace) if bytes_written > 0 => break,
+                Err(e) => return Err(e)
+            };
+            bytes_written = reached.saturating_sub(offset);
+
+            // running out of space part way through is a short write
+            if reached < chunk_end {
+                if bytes_written == 0 {
+                    return Err(Error::NoSpace);
+                }
+                break;
+            }
+            position = chunk_end;
+        }
+        Ok(bytes_written)
+    }
+
+    pub fn truncate(&self, inumber: usize, size: usize) -> Result<()> {
+        let _file = self.inode(inumber)?.write().expect("inode lock poisoned");
+        self.fs().truncate(inumber, size)
+    }
+
+    pub fn remove(&self, inumber: usize) -> Result<()> {
+        let _file = self.inode(inumber)?.write().expect("inode lock poisoned");
+        self.fs().remove(inumber)
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<()> {
+        self.with_name_locked(path, |fs| fs.unlink(path))
+    }
+
+    pub fn rmdir(&self, path: &str) -> Result<()> {
+        self.with_name_locked(path, |fs| fs.rmdir(path))
+    }
+
+    /// `FileSystem::rename`; a file replaced at `to` is locked like one
+    /// being unlinked.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.with_name_locked(to, |fs| fs.rename(from, to))
+    }
+
+    /// Makes everything written so far durable.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.device.flush_shared()
+    }
+
+    // ****************** helper methods and functions *******************
+
+    /// The filesystem lock. A panic half way through an operation may have
+    /// left the in-memory state inconsistent, so the handle stops working.
+    fn fs(&self) -> MutexGuard<'_, FileSystem<Arc<D>>> {
+        self.inner.fs.lock().expect("filesystem lock poisoned")
+    }
+
+    fn layout(&self) -> RwLockReadGuard<'_, ()> {
+        self.inner.layout.read().expect("layout lock poisoned")
+    }
+
+    fn inode(&self, inumber: usize) -> Result<&RwLock<()>> {
+        self.inner.inodes.get(inumber).ok_or(Error::InvalidInode(inumber))
+    }
+
+    /// Reads what the file holds of the `data.len()` bytes at `offset`:
+    /// the blocks are looked up under the filesystem lock and read without
+    /// it.
+    fn read_chunk(&self, inumber: usize, data: &mut [u8], offset: usize) -> Result<usize> {
+        let (blocks, length) = {
+            let mut fs = self.fs();
+            let inode = fs.load_valid_inode(inumber)?;
+            let size = inode.Size as usize;
+            if offset >= size {
+                return Ok(0);
+            }
+            if inode.is_directory() {
+                // entries change under the filesystem lock alone, so they
+                // are read under it too
+                let length = data.len();
+                return fs.read(inumber, data, length, offset);
+            }
+
+            let length = data.len().min(size - offset);
+            let first = offset / Disk::BLOCK_SIZE;
+            (fs.inode_blocks(&inode, first, (offset + length - 1) / Disk::BLOCK_SIZE + 1 - first)?, length)
+        };
+
+        let mut bytes_read = 0;
+        for block_num in blocks {
+            let position = offset + bytes_read;
+            if block_num == 0 {
+                return Err(Error::CorruptMetadata(
+                    format!("inode {} has no block for byte {}", inumber, position)
+                ));
+            }
+
+            let block_offset = position % Disk::BLOCK_SIZE;
+            let chunk = (Disk::BLOCK_SIZE - block_offset).min(length - bytes_read);
+            let target = &mut data[bytes_read..bytes_read + chunk];
+            if chunk == Disk::BLOCK_SIZE {
+                self.inner.device.read_block_shared(block_num, target)?;
+            } else {
+                let mut blk_data = [0; Disk::BLOCK_SIZE];
+                self.inner.device.read_block_shared(block_num, &mut blk_data)?;
+                target.copy_from_slice(&blk_data[block_offset..block_offset + chunk]);
+            }
+            bytes_read += chunk;
+        }
+        Ok(bytes_read)
+    }
+
+    /// Writes the bytes of the file from `start` to `end`, which are `data`
+    /// from `offset` on and zeros before it, and returns how far it got
+    /// before the disk filled up.
+    fn write_chunk(&self, inumber: usize, data: &[u8], offset: usize, start: usize, end: usize) -> Result<usize> {
+        let first = start / Disk::BLOCK_SIZE;
+        let count = (end - 1) / Disk::BLOCK_SIZE + 1 - first;
+
+        // STEP 1: find the blocks the file has and set aside the rest
+        let (blocks, reserved) = {
+            let mut fs = self.fs();
+            let inode = fs.load_file_inode(inumber)?;
+            let mut blocks = fs.inode_blocks(&inode, first, count)?;
+            let reserved = fs.reserve_blocks(blocks.iter().filter(|block_num| **block_num == 0).count())?;
+
+            // the chunk stops short at the first block that could not be
+            // set aside
+            let mut new_blocks = reserved.iter();
+            for block_num in blocks.iter_mut().filter(|block_num| **block_num == 0) {
+                *block_num = new_blocks.next().copied().unwrap_or(0);
+            }
+            if let Some(missing) = blocks.iter().position(|block_num| *block_num == 0) {
+                blocks.truncate(missing);
+            }
+            (blocks, reserved)
+        };
+        let end = end.min((first + blocks.len()) * Disk::BLOCK_SIZE).max(start);
+
+        // STEP 2: fill them without the filesystem lock
+        if let Err(e) = self.fill_blocks(&blocks, &reserved, first, data, offset, start, end) {
+            self.fs().release_reserved(&reserved)?;
+            return Err(e);
+        }
+
+        // STEP 3: link the new blocks into the file
+        let mut fs = self.fs();
+        let reached = fs.transaction(|fs| {
+            let mut inode = fs.load_file_inode(inumber)?;
+            let mut reached = end;
+            for (i, block_num) in blocks.iter().enumerate().filter(|(_, block_num)| reserved.contains(block_num)) {
+                match fs.place_inode_block(&mut inode, first + i, Some(*block_num)) {
+                    Ok(_) => {},
+                    // no room for a pointer block: the file ends before this one
+                    Err(Error::NoSpace) => {
+                        reached = reached.min((first + i) * Disk::BLOCK_SIZE);
+                        break;
+                    }
+                    Err(e) => return Err(e)
+                }
+            }
+            if reached > inode.Size as usize {
+                inode.Size = reached as u64;
+            }
+            fs.save_inode(inumber, &inode)?;
+            Ok(reached)
+        });
+        let released = fs.release_reserved(&reserved);
+        let reached = reached?;
+        released?;
+        Ok(reached)
+    }
+
+    /// Writes the bytes from `start` to `end` into `blocks`, the blocks of
+    /// the file from the `first`-th on; those in `reserved` are new and
+    /// start out zeroed.
+    #[allow(clippy::too_many_arguments)]
+    fn fill_blocks(
+        &self, blocks: &[usize], reserved: &[usize], first: usize,
+        data: &[u8], offset: usize, start: usize, end: usize
+    ) -> Result<()> {
+        for (i, block_num) in blocks.iter().enumerate() {
+            let block_start = (first + i) * Disk::BLOCK_SIZE;
+            let from = start.max(block_start);
+            let to = end.min(block_start + Disk::BLOCK_SIZE);
+            if from >= to {
+                break;
+            }
+
+            // a whole-block overwrite does not need the old contents either
+            let mut blk_data = [0; Disk::BLOCK_SIZE];
+            if !reserved.contains(block_num) && to - from < Disk::BLOCK_SIZE {
+                self.inner.device.read_block_shared(*block_num, &mut blk_data)?;
+            }
+            let gap_end = offset.clamp(from, to);
+            blk_data[from - block_start..gap_end - block_start].fill(0);
+            blk_data[gap_end - block_start..to - block_start].copy_from_slice(&data[gap_end - offset..to - offset]);
+            self.inner.device.write_block_shared(*block_num, &blk_data)?;
+        }
+        Ok(())
+    }
+
+    /// Runs `op`, which may free whatever `path` names, with that inode
+    /// locked for writing so no read or write of it is in flight.
+    fn with_name_locked<T>(&self, path: &str, op: impl FnOnce(&mut FileSystem<Arc<D>>) -> Result<T>) -> Result<T> {
+        loop {
+            let inumber = match self.lookup(path) {
+                Ok(inumber) => inumber,
+                Err(Error::NotFound(_)) => {
+                    // nothing to free, as long as nothing appears meanwhile
+                    let mut fs = self.fs();
+                    if fs.lookup(path).is_ok() {
+                        continue;
+                    }
+                    return op(&mut fs);
+                }
+                Err(e) => return Err(e)
+            };
+
+            let _file = self.inode(inumber)?.write().expect("inode lock poisoned");
+            let mut fs = self.fs();
+            // the name may have moved on while we waited for the inode
+            if fs.lookup(path).ok() != Some(inumber) {
+                continue;
+            }
+            return op(&mut fs);
+        }
+    }
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Sets aside up to `count` free data blocks (fewer when the disk is
+    /// nearly full) for a write that fills them before linking them into
+    /// the file. The allocator leaves them alone until `place_inode_block`
+    /// claims them or `release_reserved` gives them back.
+    fn reserve_blocks(&mut self, count: usize) -> Result<Vec<usize>> {
+        let offset = match &self.metaData {
+            Some(metaData) => metaData.superBlock.data_start(),
+            None => return Err(Error::NotMounted)
+        };
+        let dataBitMap = self.dataBitMap.as_mut().ok_or(Error::NotMounted)?;
+
+        let mut blocks = Vec::with_capacity(count);
+        for (i, used) in dataBitMap.iter_mut().enumerate() {
+            if blocks.len() == count {
+                break;
+            }
+            if !*used {
+                *used = true;
+                blocks.push(offset + i);
+            }
+        }
+        self.reservedBlocks.extend(blocks.iter().copied());
+        Ok(blocks)
+    }
+
+    /// Returns those of `blocks` still set aside to the free pool.
+    fn release_reserved(&mut self, blocks: &[usize]) -> Result<()> {
+        self.transaction(|fs| {
+            for block_num in blocks {
+                if fs.reservedBlocks.remove(block_num) {
+                    fs.free_block(*block_num)?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cache::BlockCache;
+    use super::super::memdisk::MemDisk;
+    use super::super::testutil::pattern;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+
+    fn shared<D: SharedDevice>(mut device: D) -> SharedFileSystem<D> {
+        FileSystem::format(&mut device).unwrap();
+        let mut fs = FileSystem::new(Arc::new(device));
+        fs.mount().unwrap();
+        SharedFileSystem::new(fs).unwrap()
+    }
+
+    // unmounts the last handle and runs the checker over the image
+    fn check<D: SharedDevice>(fs: SharedFileSystem<D>) -> D {
+        let fs = fs.into_inner().ok().unwrap();
+        let mut device = Arc::try_unwrap(fs.into_disk().unwrap()).ok().unwrap();
+        let report = FileSystem::check(&mut device, false).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        device
+    }
+
+    // a device that holds up the first request for one block until the
+    // test lets it through
+    struct GatedDisk {
+        disk: MemDisk,
+        gate: AtomicUsize,           // the block, 0 when there is no gate
+        arrived: Mutex<Sender<()>>,  // told when a request reaches the gate
+        opened: Mutex<Receiver<()>>  // lets it through
+    }
+
+    impl GatedDisk {
+        fn pass(&self, blocknum: usize) {
+            if blocknum != 0 && self.gate.compare_exchange(blocknum, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                self.arrived.lock().unwrap().send(()).unwrap();
+                self.opened.lock().unwrap().recv().unwrap();
+            }
+        }
+    }
+
+    impl BlockDevice for GatedDisk {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn block_count(&self) -> usize {
+            self.disk.block_count()
+        }
+
+        fn read_block(&mut self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+            self.read_block_shared(blocknum, data)
+        }
+
+        fn write_block(&mut self, blocknum: usize, data: &[u8]) -> Result<()> {
+            self.write_block_shared(blocknum, data)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedDevice for GatedDisk {
+        fn read_block_shared(&self, blocknum: usize, data: &mut [u8]) -> Result<()> {
+            self.pass(blocknum);
+            self.disk.read(blocknum, data)
+        }
+
+        fn write_block_shared(&self, blocknum: usize, data: &[u8]) -> Result<()> {
+            self.pass(blocknum);
+            self.disk.write(blocknum, data)
+        }
+
+        fn flush_shared(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_and_sync() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<SharedFileSystem<MemDisk>>();
+        shareable::<SharedFileSystem<Disk>>();
+        shareable::<SharedFileSystem<Mutex<BlockCache<Disk>>>>();
+
+        let mut fs = FileSystem::new(Arc::new(MemDisk::new(20)));
+        assert!(matches!(SharedFileSystem::new(FileSystem::new(Arc::new(MemDisk::new(20)))), Err(Error::NotMounted)));
+        FileSystem::format(&mut fs.disk).unwrap();
+        fs.mount().unwrap();
+        let fs = SharedFileSystem::new(fs).unwrap();
+        let other = fs.clone();
+        assert!(fs.into_inner().is_err());
+        assert!(other.into_inner().is_ok());
+    }
+
+    #[test]
+    fn test_data_moves_outside_the_filesystem_lock() {
+        let (arrived, arrivals) = mpsc::channel();
+        let (open, opened) = mpsc::channel();
+        let fs = shared(GatedDisk {
+            disk: MemDisk::new(1024),
+            gate: AtomicUsize::new(0),
+            arrived: Mutex::new(arrived),
+            opened: Mutex::new(opened)
+        });
+        let slow = fs.open("/slow").unwrap();
+        fs.write(slow, &pattern(8 * Disk::BLOCK_SIZE, 1), 8 * Disk::BLOCK_SIZE, 0).unwrap();
+        let fast = fs.open("/fast").unwrap();
+        fs.write(fast, &pattern(Disk::BLOCK_SIZE, 2), Disk::BLOCK_SIZE, 0).unwrap();
+
+        // a write to /slow gets stuck in the device...
+        fs.with(|fs| {
+            let block_num = fs.load_inode(slow).unwrap().Direct[3] as usize;
+            fs.disk.gate.store(block_num, Ordering::SeqCst);
+        });
+        let writer = {
+            let fs = fs.clone();
+            thread::spawn(move || fs.write(slow, &pattern(Disk::BLOCK_SIZE, 3), Disk::BLOCK_SIZE, 3 * Disk::BLOCK_SIZE))
+        };
+        arrivals.recv().unwrap();
+
+        // ...while other files are read, overwritten, grown and created
+        let mut read = vec![0; Disk::BLOCK_SIZE];
+        assert_eq!(fs.read(fast, &mut read, Disk::BLOCK_SIZE, 0).unwrap(), Disk::BLOCK_SIZE);
+        assert_eq!(read, pattern(Disk::BLOCK_SIZE, 2));
+        let data = pattern(40 * Disk::BLOCK_SIZE, 4);
+        assert_eq!(fs.write(fast, &data, data.len(), 100).unwrap(), data.len());
+        let other = fs.open("/other").unwrap();
+        assert_eq!(fs.write(other, &data, data.len(), 0).unwrap(), data.len());
+
+        open.send(()).unwrap();
+        assert_eq!(writer.join().unwrap().unwrap(), Disk::BLOCK_SIZE);
+
+        let mut expected = pattern(8 * Disk::BLOCK_SIZE, 1);
+        expected[3 * Disk::BLOCK_SIZE..4 * Disk::BLOCK_SIZE].copy_from_slice(&pattern(Disk::BLOCK_SIZE, 3));
+        let mut read = vec![0; expected.len()];
+        assert_eq!(fs.read(slow, &mut read, expected.len(), 0).unwrap(), expected.len());
+        assert!(read == expected);
+        let mut read = vec![0; data.len() + 100];
+        assert_eq!(fs.read(fast, &mut read, data.len() + 100, 0).unwrap(), data.len() + 100);
+        assert!(read[..100] == pattern(100, 2)[..] && read[100..] == data[..]);
+        check(fs);
+    }
+
+    #[test]
+    fn test_short_write_when_full() {
+        // the blocks set aside for a write that does not fit go back
+        let fs = shared(MemDisk::new(100));
+        let inumber = fs.open("/big").unwrap();
+        let data = pattern(200 * Disk::BLOCK_SIZE, 5);
+        let written = fs.write(inumber, &data, data.len(), 10).unwrap();
+        assert!(written > 0 && written < data.len());
+        assert_eq!(fs.stat(inumber).unwrap(), 10 + written);
+        assert!(matches!(fs.write(inumber, &data, data.len(), 10 + written), Err(Error::NoSpace)));
+
+        let mut read = vec![0; 10 + written];
+        assert_eq!(fs.read(inumber, &mut read, 10 + written, 0).unwrap(), 10 + written);
+        assert!(read[..10] == [0; 10] && read[10..] == data[..written]);
+        fs.truncate(inumber, 0).unwrap();
+        assert_eq!(fs.write(inumber, &data, 20 * Disk::BLOCK_SIZE, 0).unwrap(), 20 * Disk::BLOCK_SIZE);
+        fs.with(|fs| assert!(fs.reservedBlocks.is_empty()));
+        check(fs);
+    }
+
+    #[test]
+    fn test_concurrent_writers_on_different_files() {
+        let fs = shared(MemDisk::new(2048));
+        let workers: Vec<_> = (0..8).map(|t| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let inumber = fs.open(&format!("/file{}", t)).unwrap();
+                let data = pattern(150_000, t);
+                for offset in (0..data.len()).step_by(7000) {
+                    let end = (offset + 7000).min(data.len());
+                    assert_eq!(fs.write(inumber, &data[offset..], end - offset, offset).unwrap(), end - offset);
+                }
+
+                let mut read = vec![0; data.len()];
+                assert_eq!(fs.read(inumber, &mut read, data.len(), 0).unwrap(), data.len());
+                assert_eq!(read, data);
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let disk = check(fs);
+        let mut fs = FileSystem::new(disk);
+        fs.mount().unwrap();
+        for t in 0..8 {
+            let inumber = fs.lookup(&format!("/file{}", t)).unwrap();
+            let mut read = vec![0; 150_000];
+            assert_eq!(fs.read(inumber, &mut read, 150_000, 0).unwrap(), 150_000);
+            assert_eq!(read, pattern(150_000, t));
+        }
+    }
+
+    #[test]
+    fn test_readers_alongside_writers_and_renames() {
+        let fs = shared(MemDisk::new(2048));
+        let shared_file = fs.open("/shared").unwrap();
+        let data = pattern(300_000, 99);
+        fs.write(shared_file, &data, data.len(), 0).unwrap();
+
+        let mut workers = Vec::new();
+        for _ in 0..4 {
+            let (fs, data) = (fs.clone(), data.clone());
+            workers.push(thread::spawn(move || {
+                for round in 0..10 {
+                    let offset = round * 20_000;
+                    let mut read = vec![0; 100_000];
+                    assert_eq!(fs.read(shared_file, &mut read, 100_000, offset).unwrap(), 100_000);
+                    assert_eq!(read[..], data[offset..offset + 100_000]);
+                }
+            }));
+        }
+        for t in 0..3 {
+            let fs = fs.clone();
+            workers.push(thread::spawn(move || {
+                let dir = format!("/dir{}", t);
+                fs.mkdir(&dir).unwrap();
+                for round in 0..20 {
+                    let path = format!("{}/file{}", dir, round);
+                    let inumber = fs.open(&path).unwrap();
+                    let data = pattern(10_000 + round * 1000, t);
+                    fs.write(inumber, &data, data.len(), 0).unwrap();
+                    fs.rename(&path, &format!("{}/renamed", dir)).unwrap();
+                    if round % 3 == 0 {
+                        fs.unlink(&format!("{}/renamed", dir)).unwrap();
+                    }
+                }
+            }));
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        for t in 0..3 {
+            let names: Vec<String> = fs.readdir(&format!("/dir{}", t)).unwrap().into_iter().map(|(name, _)| name).collect();
+            assert_eq!(names, vec!["renamed"]);
+        }
+        check(fs);
+    }
+
+    #[test]
+    fn test_writers_on_the_same_file() {
+        // each write is applied whole: the file ends up with one of the
+        // patterns, never a mix of two
+        let fs = shared(Mutex::new(BlockCache::new(MemDisk::new(1024))));
+        let inumber = fs.open("/contended").unwrap();
+        let workers: Vec<_> = (0..4).map(|t| {
+            let fs = fs.clone();
+            thread::spawn(move || {
+                let data = pattern(200_000, t);
+                for _ in 0..5 {
+                    fs.write(inumber, &data, data.len(), 0).unwrap();
+                    let mut read = vec![0; data.len()];
+                    fs.read(inumber, &mut read, data.len(), 0).unwrap();
+                    assert!((0..4).any(|seed| read == pattern(200_000, seed)));
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        check(fs);
+    }
+}
@@ -0,0 +1,548 @@
+use std::io::{self, Read, Write};
+
+use super::build::{Manifest, COPY_BLOCKS};
+use super::device::BlockDevice;
+use super::disk::Disk;
+use super::error::{Error, Result};
+use super::types::*;
+use super::FileSystem;
+
+// TAR ARCHIVES
+// POSIX ustar: every entry is a 512-byte header followed by its data,
+// padded to a multiple of 512 bytes, and two zeroed blocks end the archive.
+// The header fields used here (offset, length), numbers in octal text:
+//
+//     0  name      100   NUL-terminated unless it fills the field
+//   100  mode        8
+//   108  uid         8
+//   116  gid         8
+//   124  size       12
+//   136  mtime      12
+//   148  checksum    8   sum of the header bytes, taking this field as spaces
+//   156  typeflag    1   '0' (or NUL) regular file, '5' directory
+//   257  magic       6   "ustar\0", followed by the version "00"
+//   345  prefix    155   joined to the name with a '/' when set
+//
+// tfs keeps no owners, permissions or times, so archives get root, 0644
+// (0755 for directories) and the epoch, and importing ignores them.
+const TAR_BLOCK: usize = 512;
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+
+const REGULAR: u8 = b'0';
+const DIRECTORY: u8 = b'5';
+const GNU_LONG_NAME: u8 = b'L';
+const PAX_HEADER: u8 = b'x';
+
+// the most a long name or pax header may hold before the archive is
+// taken to be corrupt
+const TEXT_MAX: u64 = 64 * 1024;
+
+// where export puts files that are in no directory (as `create` leaves them)
+const LOST_AND_FOUND: &str = "lost+found";
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    path: String,
+    kind: u8,
+    size: u64
+}
+
+impl<D: BlockDevice> FileSystem<D> {
+    /// Writes the whole filesystem to `out` as a ustar archive: every
+    /// directory and file by path, each directory before what is in it,
+    /// then the files no directory links to as `lost+found/<inumber>`.
+    /// Returns the path and inode number of every entry written.
+    pub fn export_tar<W: Write>(&mut self, mut out: W) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        self.export_dir("", &mut out, &mut manifest)?;
+
+        let unlinked: Vec<usize> = match &self.inodeBitMap {
+            Some(inodeBitMap) => {
+                let mut linked = vec![false; inodeBitMap.len()];
+                for (_, inumber) in manifest.entries.iter() {
+                    linked[*inumber] = true;
+                }
+                (0..inodeBitMap.len())
+                    .filter(|inumber| inodeBitMap[*inumber] && *inumber != ROOT_INODE && !linked[*inumber])
+                    .collect()
+            }
+            None => return Err(Error::NotMounted)
+        };
+        let mut lost = Vec::new();
+        for inumber in unlinked {
+            if !self.is_dir(inumber)? {
+                lost.push(inumber);
+            }
+        }
+        if !lost.is_empty() {
+            out.write_all(&encode_header(&format!("{}/", LOST_AND_FOUND), DIRECTORY, 0)?)?;
+            for inumber in lost {
+                self.export_file(&format!("/{}/{}", LOST_AND_FOUND, inumber), inumber, &mut out, &mut manifest)?;
+            }
+        }
+
+        out.write_all(&[0; 2 * TAR_BLOCK])?;
+        out.flush()?;
+        Ok(manifest)
+    }
+
+    // writes what the directory at `path` holds, in name order
+    fn export_dir<W: Write>(&mut self, path: &str, out: &mut W, manifest: &mut Manifest) -> Result<()> {
+        let mut entries = self.readdir(if path.is_empty() { "/" } else { path })?;
+        entries.sort();
+        for (name, inumber) in entries {
+            let path = format!("{}/{}", path, name);
+            if self.is_dir(inumber)? {
+                out.write_all(&encode_header(&format!("{}/", &path[1..]), DIRECTORY, 0)?)?;
+                manifest.entries.push((path.clone(), inumber));
+                self.export_dir(&path, out, manifest)?;
+            } else {
+                self.export_file(&path, inumber, out, manifest)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn export_file<W: Write>(&mut self, path: &str, inumber: usize, out: &mut W, manifest: &mut Manifest) -> Result<()> {
+        let size = self.stat(inumber)?;
+        out.write_all(&encode_header(&path[1..], REGULAR, size as u64)?)?;
+
+        // the header promised `Inode.Size` bytes, so anything short of
+        // that would leave the archive unreadable
+        let mut buffer = vec![0; COPY_BLOCKS * Disk::BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let length = buffer.len().min(size - offset);
+            let read = self.read(inumber, &mut buffer, length, offset)?;
+            if read == 0 {
+                return Err(Error::CorruptMetadata(
+                    format!("inode {}: only {} of its {} bytes could be read", inumber, offset, size)
+                ));
+            }
+            out.write_all(&buffer[..read])?;
+            offset += read;
+        }
+        out.write_all(&[0; TAR_BLOCK][..padding(size as u64) as usize])?;
+
+        manifest.entries.push((path.to_string(), inumber));
+        Ok(())
+    }
+
+    /// Reads a tar archive from `input` into the root directory of the
+    /// mounted filesystem. Directories that entries need but the archive
+    /// lacks are created; entries other than files and directories (links,
+    /// devices, extended headers) are skipped. Returns the path and inode
+    /// number of every file and directory imported.
+    pub fn import_tar<R: Read>(&mut self, mut input: R) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        let mut block = [0; TAR_BLOCK];
+        let mut long_path = None;
+        loop {
+            // the end: a zeroed block, or (leniently) no more input at all
+            if !read_tar_block(&mut input, &mut block)? {
+                break;
+            }
+            let header = match decode_header(&block)? {
+                Some(header) => header,
+                None => break
+            };
+
+            // paths too long for the header come in an entry of their own
+            // just before it: GNU tar's `L` or a pax `x` extended header
+            match header.kind {
+                GNU_LONG_NAME => {
+                    let name = read_text(&mut input, header.size)?;
+                    long_path = Some(name.trim_end_matches('\0').to_string());
+                    continue;
+                }
+                PAX_HEADER => {
+                    let records = read_text(&mut input, header.size)?;
+                    if let Some(path) = pax_path(&records) {
+                        long_path = Some(path);
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let name = long_path.take().unwrap_or(header.path);
+
+            let path = match normalize(&name)? {
+                Some(path) => path,
+                None => {
+                    // `./` and the like: the root directory itself
+                    skip(&mut input, padded(header.size)?, &name)?;
+                    continue;
+                }
+            };
+            match header.kind {
+                REGULAR | 0 => {
+                    let inumber = self.import_file(&path, header.size, &mut input)?;
+                    manifest.entries.push((path, inumber));
+                }
+                DIRECTORY => {
+                    let inumber = self.make_dirs(&path)?;
+                    manifest.entries.push((path, inumber));
+                    skip(&mut input, padded(header.size)?, &name)?;
+                }
+                _ => skip(&mut input, padded(header.size)?, &name)?
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn import_file<R: Read>(&mut self, path: &str, size: u64, input: &mut R) -> Result<usize> {
+        let parent = &path[..path.rfind('/').unwrap_or(0)];
+        if !parent.is_empty() {
+            self.make_dirs(parent)?;
+        }
+        let inumber = self.open_new(path)?;
+
+        let size = size as usize;
+        let mut buffer = vec![0; COPY_BLOCKS * Disk::BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let length = buffer.len().min(size - offset);
+            read_exact(input, &mut buffer[..length], path)?;
+            if self.write(inumber, &buffer, length, offset)? < length {
+                return Err(Error::NoSpace);
+            }
+            offset += length;
+        }
+        skip(input, padding(size as u64), path)?;
+
+        let stored = self.stat(inumber)?;
+        if stored != size {
+            return Err(Error::CorruptMetadata(
+                format!("{} holds {} bytes after importing {}", path, stored, size)
+            ));
+        }
+        Ok(inumber)
+    }
+
+    // `mkdir -p`: the directory at `path`, created along with any missing
+    // parents if it does not exist
+    fn make_dirs(&mut self, path: &str) -> Result<usize> {
+        let mut inumber = ROOT_INODE;
+        let mut prefix = String::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            prefix.push('/');
+            prefix.push_str(name);
+            inumber = match self.lookup(&prefix) {
+                Ok(inumber) if self.is_dir(inumber)? => inumber,
+                Ok(_) => return Err(Error::NotADirectory(prefix)),
+                Err(Error::NotFound(_)) => self.mkdir(&prefix)?,
+                Err(e) => return Err(e)
+            };
+        }
+        Ok(inumber)
+    }
+}
+
+// bytes of zeros after `size` bytes of data, up to the next block
+fn padding(size: u64) -> u64 {
+    let block = TAR_BLOCK as u64;
+    (block - size % block) % block
+}
+
+// the length of an entry's data with its padding; base-256 sizes can be
+// too large to pad
+fn padded(size: u64) -> Result<u64> {
+    size.checked_add(padding(size))
+        .ok_or_else(|| Error::InvalidArchive(format!("{} byte entry", size)))
+}
+
+// fills `block`, or returns false at the end of the input
+fn read_tar_block<R: Read>(input: &mut R, block: &mut [u8; TAR_BLOCK]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < TAR_BLOCK {
+        match input.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(Error::InvalidArchive("archive ends in the middle of a header".to_string())),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::Io(e))
+        }
+    }
+    Ok(true)
+}
+
+fn read_exact<R: Read>(input: &mut R, data: &mut [u8], path: &str) -> Result<()> {
+    input.read_exact(data).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Error::InvalidArchive(format!("archive ends in the middle of {}", path)),
+        _ => Error::Io(e)
+    })
+}
+
+fn skip<R: Read>(input: &mut R, bytes: u64, path: &str) -> Result<()> {
+    let skipped = io::copy(&mut input.take(bytes), &mut io::sink())?;
+    if skipped < bytes {
+        return Err(Error::InvalidArchive(format!("archive ends in the middle of {}", path)));
+    }
+    Ok(())
+}
+
+// the data of a GNU long name or pax header, padding included
+fn read_text<R: Read>(input: &mut R, size: u64) -> Result<String> {
+    if size > TEXT_MAX {
+        return Err(Error::InvalidArchive(format!("{} byte extended header", size)));
+    }
+    let mut text = vec![0; size as usize];
+    read_exact(input, &mut text, "an extended header")?;
+    skip(input, padding(size), "an extended header")?;
+    String::from_utf8(text).map_err(|_| Error::InvalidArchive("extended header is not UTF-8".to_string()))
+}
+
+// the `path` among pax records, each "<length> <key>=<value>\n"
+fn pax_path(records: &str) -> Option<String> {
+    let mut rest = records;
+    while !rest.is_empty() {
+        let space = rest.find(' ')?;
+        let length: usize = rest[..space].parse().ok()?;
+        let record = rest.get(space + 1..length)?.strip_suffix('\n')?;
+        if let Some(path) = record.strip_prefix("path=") {
+            return Some(path.to_string());
+        }
+        rest = &rest[length..];
+    }
+    None
+}
+
+// a path from an archive as an absolute tfs path, or None for the root;
+// `..` could climb out of wherever the archive is imported, so it is refused
+fn normalize(path: &str) -> Result<Option<String>> {
+    let mut normal = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+        if name == ".." {
+            return Err(Error::InvalidPath(path.to_string()));
+        }
+        normal.push('/');
+        normal.push_str(name);
+    }
+    Ok(if normal.is_empty() { None } else { Some(normal) })
+}
+
+// writes `value` as zero-padded octal filling all but the last byte of
+// `field`, which stays NUL
+fn put_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(text.as_bytes());
+    field[width] = 0;
+}
+
+// reads a numeric field: octal text, or big-endian binary when the high
+// bit of the first byte is set (as GNU tar writes large sizes)
+fn get_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7f) as u64;
+        for byte in field[1..].iter() {
+            value = value.checked_mul(256)
+                .ok_or_else(|| Error::InvalidArchive("number too large".to_string()))?
+                | *byte as u64;
+        }
+        return Ok(value);
+    }
+
+    let text: Vec<u8> = field.iter().copied()
+        .skip_while(|b| *b == b' ')
+        .take_while(|b| *b != 0 && *b != b' ')
+        .collect();
+    let text = std::str::from_utf8(&text).unwrap_or("?");
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| Error::InvalidArchive(format!("{:?} is not an octal number", text)))
+}
+
+fn checksum(header: &[u8; TAR_BLOCK]) -> u64 {
+    header.iter().enumerate()
+        .map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 })
+        .sum()
+}
+
+// a NUL-terminated field, or the whole field when it is full
+fn get_text(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+fn encode_header(path: &str, kind: u8, size: u64) -> Result<[u8; TAR_BLOCK]> {
+    // long paths are split at a '/' into the prefix and the name
+    let (prefix, name) = if path.len() <= NAME_LEN {
+        ("", path)
+    } else {
+        let split = path.char_indices()
+            .filter(|(i, c)| *c == '/' && *i <= PREFIX_LEN && path.len() - i - 1 <= NAME_LEN && *i > 0)
+            .map(|(i, _)| i)
+            .next();
+        match split {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => return Err(Error::InvalidPath(format!("{} is too long for a tar header", path)))
+        }
+    };
+
+    let mut header = [0; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header[100..108], if kind == DIRECTORY { 0o755 } else { 0o644 });
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // six octal digits, a NUL and a space, as tar itself writes it
+    let sum = checksum(&header);
+    put_octal(&mut header[148..155], sum);
+    header[155] = b' ';
+    Ok(header)
+}
+
+// None for a zeroed block, the end of the archive
+fn decode_header(header: &[u8; TAR_BLOCK]) -> Result<Option<Header>> {
+    if header.iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    if get_number(&header[148..156])? != checksum(header) {
+        return Err(Error::InvalidArchive("header checksum does not match".to_string()));
+    }
+
+    let mut path = get_text(&header[..NAME_LEN]);
+    if header[257..262] == *b"ustar" {
+        let prefix = get_text(&header[345..345 + PREFIX_LEN]);
+        if !prefix.is_empty() {
+            path = format!("{}/{}", prefix, path);
+        }
+    }
+    Ok(Some(Header { path, kind: header[156], size: get_number(&header[124..136])? }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memdisk::MemDisk;
+    use super::super::testutil::{pattern, read_file, write_file};
+
+    #[test]
+    fn test_header() {
+        let header = encode_header("docs/readme", REGULAR, 1234).unwrap();
+        assert_eq!(&header[124..136], b"00000002322\0");
+        assert_eq!(&header[257..265], b"ustar\x0000");
+        assert_eq!(decode_header(&header).unwrap(),
+                   Some(Header { path: "docs/readme".to_string(), kind: REGULAR, size: 1234 }));
+
+        // long paths go through the prefix
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let header = encode_header(&long, REGULAR, 0).unwrap();
+        assert_eq!(decode_header(&header).unwrap().unwrap().path, long);
+        assert!(encode_header(&"f".repeat(101), REGULAR, 0).is_err());
+
+        let mut corrupt = header;
+        corrupt[0] ^= 1;
+        assert!(matches!(decode_header(&corrupt), Err(Error::InvalidArchive(_))));
+        assert_eq!(decode_header(&[0; TAR_BLOCK]).unwrap(), None);
+
+        // sizes GNU tar writes in binary
+        let mut field = [0; 12];
+        field[0] = 0x80;
+        field[11] = 0x10;
+        field[10] = 0x01;
+        assert_eq!(get_number(&field).unwrap(), 0x110);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(200)).unwrap();
+        fs.mkdir("/docs").unwrap();
+        fs.mkdir("/docs/empty").unwrap();
+        write_file(&mut fs, "/small", b"hello");
+        write_file(&mut fs, "/docs/large", &pattern(7 * Disk::BLOCK_SIZE + 3, 1));
+        write_file(&mut fs, "/docs/zero", b"");
+        let unlinked = fs.create().unwrap();
+        fs.write(unlinked, b"no name", 7, 0).unwrap();
+
+        let mut archive = Vec::new();
+        let exported = fs.export_tar(&mut archive).unwrap();
+        assert_eq!(exported.entries.len(), 6);
+        // seven headers, the padded contents of three files and the end
+        assert_eq!(archive.len(), TAR_BLOCK * 7 + (7 * Disk::BLOCK_SIZE + TAR_BLOCK) + TAR_BLOCK + TAR_BLOCK + TAR_BLOCK * 2);
+
+        let mut copy = FileSystem::from_disk(MemDisk::new(200)).unwrap();
+        let imported = copy.import_tar(&archive[..]).unwrap();
+        assert_eq!(imported.entries.len(), 7);
+        assert_eq!(read_file(&mut copy, "/small"), b"hello");
+        assert_eq!(read_file(&mut copy, "/docs/large"), pattern(7 * Disk::BLOCK_SIZE + 3, 1));
+        assert_eq!(read_file(&mut copy, "/docs/zero"), b"");
+        assert_eq!(read_file(&mut copy, &format!("/lost+found/{}", unlinked)), b"no name");
+        let empty = copy.lookup("/docs/empty").unwrap();
+        assert!(copy.is_dir(empty).unwrap());
+
+        // importing again would overwrite files
+        assert!(matches!(copy.import_tar(&archive[..]), Err(Error::AlreadyExists(_))));
+        let mut disk = copy.into_disk().unwrap();
+        assert!(FileSystem::check(&mut disk, false).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_import_creates_parents_and_skips_links() {
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&encode_header("./", DIRECTORY, 0).unwrap());
+        archive.extend_from_slice(&encode_header("a/b/file", REGULAR, 3).unwrap());
+        archive.extend_from_slice(&[b'x'; TAR_BLOCK]);
+        archive.extend_from_slice(&encode_header("a/link", b'2', 0).unwrap());
+        // no end marker: the end of the input will do
+
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        let manifest = fs.import_tar(&archive[..]).unwrap();
+        assert_eq!(manifest.entries, vec![("/a/b/file".to_string(), fs.lookup("/a/b/file").unwrap())]);
+        assert_eq!(read_file(&mut fs, "/a/b/file"), b"xxx");
+        assert!(fs.lookup("/a/link").is_err());
+    }
+
+    #[test]
+    fn test_import_long_names() {
+        fn entry(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+            archive.extend_from_slice(&encode_header(name, kind, data.len() as u64).unwrap());
+            archive.extend_from_slice(data);
+            archive.resize(archive.len() + padding(data.len() as u64) as usize, 0);
+        }
+        let gnu = format!("{}/{}", "g".repeat(56), "n".repeat(56));
+        let pax = format!("{}/{}", "p".repeat(56), "x".repeat(56));
+        let record = format!("path={}\n", pax);
+        let record = format!("{} {}", record.len() + 4, record);
+
+        let mut archive = Vec::new();
+        entry(&mut archive, "././@LongLink", GNU_LONG_NAME, format!("{}\0", gnu).as_bytes());
+        entry(&mut archive, &gnu[..NAME_LEN], REGULAR, b"gnu");
+        entry(&mut archive, "PaxHeaders/x", PAX_HEADER, format!("12 mtime=10\n{}", record).as_bytes());
+        entry(&mut archive, &pax[..NAME_LEN], REGULAR, b"pax");
+
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+        fs.import_tar(&archive[..]).unwrap();
+        assert_eq!(read_file(&mut fs, &format!("/{}", gnu)), b"gnu");
+        assert_eq!(read_file(&mut fs, &format!("/{}", pax)), b"pax");
+    }
+
+    #[test]
+    fn test_import_rejects_bad_archives() {
+        let mut fs = FileSystem::from_disk(MemDisk::new(50)).unwrap();
+
+        let escape = encode_header("../etc/passwd", REGULAR, 0).unwrap();
+        assert!(matches!(fs.import_tar(&escape[..]), Err(Error::InvalidPath(_))));
+
+        let mut truncated = encode_header("short", REGULAR, 1000).unwrap().to_vec();
+        truncated.extend_from_slice(&[1; 600]);
+        assert!(matches!(fs.import_tar(&truncated[..]), Err(Error::InvalidArchive(_))));
+        assert!(matches!(fs.import_tar(&[1; 100][..]), Err(Error::InvalidArchive(_))));
+
+        // a symlink whose base-256 size is u64::MAX cannot be skipped
+        let mut huge = encode_header("link", b'2', 0).unwrap();
+        huge[124..136].copy_from_slice(&[0x80, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        let sum = checksum(&huge);
+        put_octal(&mut huge[148..155], sum);
+        assert!(matches!(fs.import_tar(&huge[..]), Err(Error::InvalidArchive(_))));
+    }
+}
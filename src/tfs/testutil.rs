@@ -0,0 +1,35 @@
+//! Helpers shared by the unit tests of the other modules.
+
+use super::device::BlockDevice;
+use super::FileSystem;
+
+/// `len` bytes that differ from one `seed` to another and from one block
+/// to the next, so misplaced data shows up.
+pub fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Creates (or opens) the file at `path`, writes all of `data` to it and
+/// returns its inode number.
+pub fn write_file<D: BlockDevice>(fs: &mut FileSystem<D>, path: &str, data: &[u8]) -> usize {
+    let inumber = fs.open(path).unwrap();
+    assert_eq!(fs.write(inumber, data, data.len(), 0).unwrap(), data.len(), "{}", path);
+    inumber
+}
+
+/// The whole contents of the file at `path`.
+pub fn read_file<D: BlockDevice>(fs: &mut FileSystem<D>, path: &str) -> Vec<u8> {
+    let inumber = fs.lookup(path).unwrap();
+    let size = fs.stat(inumber).unwrap();
+    let mut data = vec![0; size];
+    assert_eq!(fs.read(inumber, &mut data, size, 0).unwrap(), size, "{}", path);
+    data
+}
+
+/// Checks that the file at `path` holds exactly `expected`, without
+/// printing both when they are large.
+pub fn assert_file<D: BlockDevice>(fs: &mut FileSystem<D>, path: &str, expected: &[u8]) {
+    let data = read_file(fs, path);
+    assert_eq!(data.len(), expected.len(), "{}", path);
+    assert!(data == expected, "{} changed", path);
+}
@@ -1,11 +1,89 @@
 // mod disk;
+use std::convert::TryInto;
+
 use super::disk::Disk;
-use super::utility;
+use super::utility::{as_u32_le, u32_to_le};
 
-pub const MAGIC_NUMBER: usize = 0xf0f03410;
-pub const INODES_PER_BLOCK: usize   = 128;
+// SimpleFS images use 0xf0f03410; ours have 64-byte inodes, so they differ
+pub const MAGIC_NUMBER: usize = 0xf0f03411;
+pub const INODES_PER_BLOCK: usize   = 64;
 pub const POINTERS_PER_INODE: usize = 5;
 pub const POINTERS_PER_BLOCK: usize = 1024;
+// number of data blocks reachable through the direct, indirect, double
+// indirect and triple indirect pointers of a single inode
+pub const MAX_FILE_BLOCKS: usize = POINTERS_PER_INODE
+    + POINTERS_PER_BLOCK
+    + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK
+    + POINTERS_PER_BLOCK * POINTERS_PER_BLOCK * POINTERS_PER_BLOCK;
+pub const ENTRIES_PER_BLOCK: usize  = 64;
+pub const DIR_ENTRY_SIZE: usize     = 64;
+pub const NAME_MAX: usize           = 56;
+pub const BITS_PER_BLOCK: usize     = Disk::BLOCK_SIZE * 8;
+
+// values of Inode.Valid: an allocated inode records what kind of file it is
+pub const FREE_INODE: u32      = 0;
+pub const FILE_INODE: u32      = 1;
+pub const DIRECTORY_INODE: u32 = 2;
+
+// the root directory is created by format, in the first inode
+pub const ROOT_INODE: usize = 0;
+
+// values of Superblock.State: the on-disk bitmaps are only trusted after a
+// clean unmount
+pub const STATE_DIRTY: u32 = 0;
+pub const STATE_CLEAN: u32 = 1;
+
+// disks of at least JOURNAL_MIN_BLOCKS blocks get a journal of JOURNAL_BLOCKS
+// blocks (a header and room for the blocks of one transaction) after the
+// bitmaps; smaller ones are written in place
+pub const JOURNAL_BLOCKS: usize     = 128;
+pub const JOURNAL_MIN_BLOCKS: usize = 1024;
+pub const JOURNAL_MAGIC: u32        = 0x4a524e4c;
+
+// longest volume label, in bytes
+pub const LABEL_MAX: usize = 32;
+
+// ON-DISK LAYOUT
+// Every integer is stored little-endian, whatever the host, and every
+// structure is encoded field by field at the offsets below:
+//
+//   Superblock (block 0, rest of the block zero)
+//      0  MagicNumber    u32
+//      4  Blocks         u32
+//      8  InodeBlocks    u32
+//     12  Inodes         u32
+//     16  BitmapBlocks   u32
+//     20  State          u32
+//     24  JournalBlocks  u32
+//     28  ReservedBlocks u32, at the end of the image, outside the data region
+//     32  Uuid           16 bytes (all zero: none)
+//     48  Label          LABEL_MAX bytes, NUL-padded
+//
+//   Inode (64 bytes, INODES_PER_BLOCK per inode block); the first 32 bytes
+//   are laid out like a SimpleFS inode
+//      0  Valid          u32
+//      4  Size           low 32 bits
+//      8  Direct         5 x u32
+//     28  Indirect       u32
+//     32  DoubleIndirect u32
+//     36  TripleIndirect u32
+//     40  Size           high 32 bits
+//     44  reserved, zero
+//
+//   Pointer block: POINTERS_PER_BLOCK x u32
+//
+//   DirEntry (64 bytes, ENTRIES_PER_BLOCK per directory block)
+//      0  Valid          u32
+//      4  Inumber        u32
+//      8  Name           NAME_MAX bytes, NUL-padded
+//
+//   Journal header (first journal block); the rest of the journal holds
+//   copies of the transaction's blocks, in order
+//      0  JOURNAL_MAGIC  u32
+//      4  Count          u32, blocks in the transaction (0: nothing to replay)
+//      8  Checksum       u32, over the block numbers and copies
+//     12  Block numbers  Count x u32, where each copy belongs
+pub const INODE_SIZE: usize = 64;
 
 #[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
@@ -13,24 +91,39 @@ pub struct Superblock {
     pub MagicNumber: u32,
     pub Blocks: u32,
     pub InodeBlocks: u32,
-    pub Inodes: u32
+    pub Inodes: u32,
+    pub BitmapBlocks: u32,  // blocks of inode and data bitmaps after the inode table
+    pub State: u32,         // STATE_CLEAN or STATE_DIRTY
+    pub JournalBlocks: u32,  // blocks of journal after the bitmaps (0: no journal)
+    pub ReservedBlocks: u32, // blocks after the data region, left alone
+    pub Uuid: [u8; 16],
+    pub Label: [u8; LABEL_MAX]  // NUL-padded volume label
 }
 
 #[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
 pub struct Inode {
-    pub Valid: u32, // whether or not inode is valid (or allocated)
-    pub Size: u32,  // size of file
+    pub Valid: u32, // FREE_INODE, or the kind of file (FILE_INODE, DIRECTORY_INODE)
+    pub Size: u64,  // size of file
     pub Direct: [u32; POINTERS_PER_INODE],
-    pub Indirect: u32
+    pub Indirect: u32,       // block of pointers to data blocks
+    pub DoubleIndirect: u32, // block of pointers to indirect blocks
+    pub TripleIndirect: u32  // block of pointers to double indirect blocks
+}
+
+/// One slot of a directory; a directory's data is an array of these.
+#[derive(Copy, Clone, Debug)]
+pub struct DirEntry {
+    pub Valid: u32,     // whether or not the slot is in use
+    pub Inumber: u32,   // inode the name refers to
+    pub Name: [u8; NAME_MAX]  // NUL-padded file name
 }
 
+/// The raw bytes of a block. The accessors decode (and the setters encode)
+/// the structures it holds, following the layout above.
 #[derive(Copy, Clone)]
 #[allow(dead_code)]
-pub union Block {
-    pub Super: Superblock,
-    pub Inodes: [Inode; INODES_PER_BLOCK],
-    pub Pointers: [u32; POINTERS_PER_BLOCK],
+pub struct Block {
     pub Data: [u8; Disk::BLOCK_SIZE]
 }
 
@@ -41,6 +134,16 @@ pub struct MetaData {
     pub inodeTable: Vec<[Inode; INODES_PER_BLOCK]>
 }
 
+pub(super) fn get_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut array = [0; 4];
+    array.copy_from_slice(&bytes[offset..offset + 4]);
+    as_u32_le(&array)
+}
+
+pub(super) fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&u32_to_le(value));
+}
+
 
 #[allow(dead_code)]
 impl Block {
@@ -52,63 +155,138 @@ impl Block {
 
     // ***************** get and set methods for Block fields *******************************
     // NOTE: the get methods ALWAYS returns a FRESH COPY of the field values because
-    //      the fields are NOT reference types; they are OWNED types. Hence,
+    //      they are decoded from the block's bytes. Hence,
     //      if you still want to refer to the original field value, use the corresponding
     //      set methods. For example, 
-    ///    let block = Block::new()
-    ///    let mut data = block.data()   // 'data' would be a fresh copy of block.Data;
-    ///    data[1] = 4;     // this won't change the corresponding block.Data
+    //    let block = Block::new()
+    //    let mut data = block.data()   // 'data' would be a fresh copy of block.Data;
+    //    data[1] = 4;     // this won't change the corresponding block.Data
     //     however, if you wish to have this change reflect on Block.Data, use the set method:
-    ///    block.set_data(data)
+    //    block.set_data(data)
+    //    The set methods other than set_data rewrite the whole block.
     // **************************************************************************************
 
     pub fn data(&self) -> [u8; Disk::BLOCK_SIZE] {
-        unsafe {
-            self.Data
-        }
+        self.Data
     }
 
     pub fn superblock(&self) -> Superblock {
-        unsafe {
-            self.Super
-        }
+        Superblock::decode(&self.Data)
     }
 
     pub fn inodes(&self) -> [Inode; INODES_PER_BLOCK] {
-        unsafe {
-            self.Inodes
+        let mut inodes = [Inode::blank(); INODES_PER_BLOCK];
+        for (inode, bytes) in inodes.iter_mut().zip(self.Data.chunks(INODE_SIZE)) {
+            *inode = Inode::decode(bytes);
         }
+        inodes
     }
 
     pub fn pointers(&self) -> [u32; POINTERS_PER_BLOCK]{
-        unsafe {
-            self.Pointers
+        let mut pointers = [0; POINTERS_PER_BLOCK];
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            *pointer = get_u32(&self.Data, 4 * i);
         }
+        pointers
     }
 
-    pub fn set_data(&mut self, data: [u8; Disk::BLOCK_SIZE]) {
-        unsafe {
-            self.Data = data;
+    pub fn entries(&self) -> [DirEntry; ENTRIES_PER_BLOCK] {
+        let mut entries = [DirEntry::blank(); ENTRIES_PER_BLOCK];
+        for (entry, bytes) in entries.iter_mut().zip(self.Data.chunks(DIR_ENTRY_SIZE)) {
+            *entry = DirEntry::decode(bytes);
         }
+        entries
+    }
+
+    pub fn set_data(&mut self, data: [u8; Disk::BLOCK_SIZE]) {
+        self.Data = data;
     }
 
     pub fn set_inodes(&mut self, inodes: [Inode; INODES_PER_BLOCK]) {
-        unsafe {
-            self.Inodes = inodes;
+        for (inode, bytes) in inodes.iter().zip(self.Data.chunks_mut(INODE_SIZE)) {
+            inode.encode(bytes);
         }
     }
 
     pub fn set_pointers(&mut self, pointers: [u32; POINTERS_PER_BLOCK]) {
-        unsafe {
-            self.Pointers = pointers;
+        for (i, pointer) in pointers.iter().enumerate() {
+            put_u32(&mut self.Data, 4 * i, *pointer);
+        }
+    }
+
+    pub fn set_entries(&mut self, entries: [DirEntry; ENTRIES_PER_BLOCK]) {
+        for (entry, bytes) in entries.iter().zip(self.Data.chunks_mut(DIR_ENTRY_SIZE)) {
+            entry.encode(bytes);
         }
     }
 
     pub fn set_superblock(&mut self, superblock: Superblock) {
-        unsafe {
-            self.Super = superblock;
+        self.Data = [0; Disk::BLOCK_SIZE];
+        superblock.encode(&mut self.Data);
+    }
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Superblock {
+    pub fn decode(bytes: &[u8]) -> Self {
+        Superblock {
+            MagicNumber: get_u32(bytes, 0),
+            Blocks: get_u32(bytes, 4),
+            InodeBlocks: get_u32(bytes, 8),
+            Inodes: get_u32(bytes, 12),
+            BitmapBlocks: get_u32(bytes, 16),
+            State: get_u32(bytes, 20),
+            JournalBlocks: get_u32(bytes, 24),
+            ReservedBlocks: get_u32(bytes, 28),
+            Uuid: bytes[32..48].try_into().unwrap(),
+            Label: bytes[48..48 + LABEL_MAX].try_into().unwrap()
         }
     }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        put_u32(bytes, 0, self.MagicNumber);
+        put_u32(bytes, 4, self.Blocks);
+        put_u32(bytes, 8, self.InodeBlocks);
+        put_u32(bytes, 12, self.Inodes);
+        put_u32(bytes, 16, self.BitmapBlocks);
+        put_u32(bytes, 20, self.State);
+        put_u32(bytes, 24, self.JournalBlocks);
+        put_u32(bytes, 28, self.ReservedBlocks);
+        bytes[32..48].copy_from_slice(&self.Uuid);
+        bytes[48..48 + LABEL_MAX].copy_from_slice(&self.Label);
+    }
+
+    /// The first block of the journal, right after the bitmaps.
+    pub fn journal_start(&self) -> usize {
+        1 + self.InodeBlocks as usize + self.BitmapBlocks as usize
+    }
+
+    /// The first block of the data region, after the superblock, the inode
+    /// table, the bitmaps and the journal.
+    pub fn data_start(&self) -> usize {
+        self.journal_start() + self.JournalBlocks as usize
+    }
+
+    /// The block right after the data region: the reserved blocks, if
+    /// any, come after it.
+    pub fn data_end(&self) -> usize {
+        (self.Blocks as usize).saturating_sub(self.ReservedBlocks as usize)
+    }
+
+    pub fn label(&self) -> String {
+        let len = self.Label.iter().position(|b| *b == 0).unwrap_or(LABEL_MAX);
+        String::from_utf8_lossy(&self.Label[..len]).into_owned()
+    }
+
+    /// Number of inodes the inode table has room for.
+    pub fn inode_count(&self) -> usize {
+        self.InodeBlocks as usize * INODES_PER_BLOCK
+    }
 }
 
 impl Inode {
@@ -117,7 +295,193 @@ impl Inode {
             Valid: 0,
             Size: 0,
             Direct: [0; POINTERS_PER_INODE],
-            Indirect: 0
+            Indirect: 0,
+            DoubleIndirect: 0,
+            TripleIndirect: 0
         }
     }
+
+    /// Decodes the `INODE_SIZE` bytes of an inode.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut direct = [0; POINTERS_PER_INODE];
+        for (i, pointer) in direct.iter_mut().enumerate() {
+            *pointer = get_u32(bytes, 8 + 4 * i);
+        }
+
+        Inode {
+            Valid: get_u32(bytes, 0),
+            Size: get_u32(bytes, 4) as u64 | (get_u32(bytes, 40) as u64) << 32,
+            Direct: direct,
+            Indirect: get_u32(bytes, 28),
+            DoubleIndirect: get_u32(bytes, 32),
+            TripleIndirect: get_u32(bytes, 36)
+        }
+    }
+
+    /// Encodes the inode into `INODE_SIZE` bytes.
+    pub fn encode(&self, bytes: &mut [u8]) {
+        bytes[..INODE_SIZE].copy_from_slice(&[0; INODE_SIZE]);
+        put_u32(bytes, 0, self.Valid);
+        put_u32(bytes, 4, self.Size as u32);
+        for (i, pointer) in self.Direct.iter().enumerate() {
+            put_u32(bytes, 8 + 4 * i, *pointer);
+        }
+        put_u32(bytes, 28, self.Indirect);
+        put_u32(bytes, 32, self.DoubleIndirect);
+        put_u32(bytes, 36, self.TripleIndirect);
+        put_u32(bytes, 40, (self.Size >> 32) as u32);
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.Valid == DIRECTORY_INODE
+    }
+
+    /// The root pointer of the indirect tree `level` blocks deep
+    /// (1 = `Indirect`, 2 = `DoubleIndirect`, 3 = `TripleIndirect`).
+    pub fn tree_root(&self, level: u32) -> u32 {
+        match level {
+            1 => self.Indirect,
+            2 => self.DoubleIndirect,
+            _ => self.TripleIndirect
+        }
+    }
+
+    pub fn tree_root_mut(&mut self, level: u32) -> &mut u32 {
+        match level {
+            1 => &mut self.Indirect,
+            2 => &mut self.DoubleIndirect,
+            _ => &mut self.TripleIndirect
+        }
+    }
+}
+
+impl DirEntry {
+    pub fn blank() -> Self {
+        DirEntry {
+            Valid: 0,
+            Inumber: 0,
+            Name: [0; NAME_MAX]
+        }
+    }
+
+    pub fn new(name: &str, inumber: usize) -> Self {
+        let mut entry = Self::blank();
+        entry.Valid = 1;
+        entry.Inumber = inumber as u32;
+        entry.Name[..name.len()].copy_from_slice(name.as_bytes());
+        entry
+    }
+
+    /// Decodes the `DIR_ENTRY_SIZE` bytes of a directory entry.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut name = [0; NAME_MAX];
+        name.copy_from_slice(&bytes[8..8 + NAME_MAX]);
+        DirEntry {
+            Valid: get_u32(bytes, 0),
+            Inumber: get_u32(bytes, 4),
+            Name: name
+        }
+    }
+
+    /// Encodes the entry into `DIR_ENTRY_SIZE` bytes.
+    pub fn encode(&self, bytes: &mut [u8]) {
+        put_u32(bytes, 0, self.Valid);
+        put_u32(bytes, 4, self.Inumber);
+        bytes[8..8 + NAME_MAX].copy_from_slice(&self.Name);
+    }
+
+    pub fn name(&self) -> String {
+        let len = self.Name.iter().position(|b| *b == 0).unwrap_or(NAME_MAX);
+        String::from_utf8_lossy(&self.Name[..len]).into_owned()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_superblock_layout() {
+        let mut label = [0; LABEL_MAX];
+        label[..7].copy_from_slice(b"scratch");
+        let superblock = Superblock {
+            MagicNumber: MAGIC_NUMBER as u32,
+            Blocks: 200,
+            InodeBlocks: 20,
+            Inodes: 3,
+            BitmapBlocks: 1,
+            State: STATE_CLEAN,
+            JournalBlocks: 16,
+            ReservedBlocks: 10,
+            Uuid: [7; 16],
+            Label: label
+        };
+        let mut block = Block::new();
+        block.set_superblock(superblock);
+
+        let data = block.data();
+        assert_eq!(&data[..8], &[0x11, 0x34, 0xf0, 0xf0, 200, 0, 0, 0]);
+        assert_eq!(&data[20..32], &[1, 0, 0, 0, 16, 0, 0, 0, 10, 0, 0, 0]);
+        assert_eq!(&data[32..48], &[7; 16]);
+        assert_eq!(&data[48..56], b"scratch\0");
+        assert!(data[48 + LABEL_MAX..].iter().all(|b| *b == 0));
+
+        let decoded = block.superblock();
+        assert_eq!(decoded.InodeBlocks, 20);
+        assert_eq!(decoded.Uuid, [7; 16]);
+        assert_eq!(decoded.label(), "scratch");
+        assert_eq!(superblock.journal_start(), 22);
+        assert_eq!(superblock.data_start(), 38);
+        assert_eq!(superblock.data_end(), 190);
+    }
+
+    #[test]
+    fn test_inode_layout() {
+        assert_eq!(INODE_SIZE * INODES_PER_BLOCK, Disk::BLOCK_SIZE);
+
+        let mut inode = Inode::blank();
+        inode.Valid = FILE_INODE;
+        inode.Size = 0x1_0000_0102;
+        inode.Direct = [1, 2, 3, 4, 0x01020304];
+        inode.Indirect = 6;
+        inode.DoubleIndirect = 7;
+        inode.TripleIndirect = 8;
+
+        let mut bytes = [0xff; INODE_SIZE];
+        inode.encode(&mut bytes);
+        assert_eq!(&bytes[..12], &[1, 0, 0, 0, 2, 1, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&bytes[24..44], &[4, 3, 2, 1, 6, 0, 0, 0, 7, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0]);
+        assert!(bytes[44..].iter().all(|b| *b == 0));
+
+        let decoded = Inode::decode(&bytes);
+        assert_eq!(decoded.Size, inode.Size);
+        assert_eq!(decoded.Direct, inode.Direct);
+        assert_eq!(decoded.TripleIndirect, 8);
+
+        // the second inode of a block starts INODE_SIZE bytes in
+        let mut inodes = [Inode::blank(); INODES_PER_BLOCK];
+        inodes[1] = inode;
+        let mut block = Block::new();
+        block.set_inodes(inodes);
+        assert_eq!(block.data()[INODE_SIZE], 1);
+        assert_eq!(block.inodes()[1].Indirect, 6);
+    }
+
+    #[test]
+    fn test_pointers_and_entries_layout() {
+        let mut pointers = [0; POINTERS_PER_BLOCK];
+        pointers[1] = 0x0a0b0c0d;
+        let mut block = Block::new();
+        block.set_pointers(pointers);
+        assert_eq!(&block.data()[4..8], &[0x0d, 0x0c, 0x0b, 0x0a]);
+        assert_eq!(block.pointers()[1], 0x0a0b0c0d);
+
+        let mut entries = [DirEntry::blank(); ENTRIES_PER_BLOCK];
+        entries[2] = DirEntry::new("notes", 9);
+        block.set_entries(entries);
+        let data = block.data();
+        assert_eq!(&data[2 * DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE + 13], b"\x01\0\0\0\x09\0\0\0notes");
+        assert_eq!(block.entries()[2].name(), "notes");
+    }
 }
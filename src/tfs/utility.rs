@@ -12,6 +12,15 @@ pub fn as_u32_le(array: &[u8; 4]) -> u32 {
     ((array[3] as u32) << 24)
 }
 
+pub fn u32_to_le(value: u32) -> [u8; 4] {
+    [
+        (value >>  0) as u8,
+        (value >>  8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8
+    ]
+}
+
 pub fn as_u32(array: &[u8]) -> u32 {
     let mut byte_array: [u8; 4] = [0; 4];
     byte_array[0] = array[0];